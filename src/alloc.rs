@@ -0,0 +1,535 @@
+//! trampoline region placement
+//!
+//! a strategy picks where a new region goes, the address space abstracts the queries and
+//! allocation so a strategy can be driven by a fake memory map
+
+use crate::backend::Protection;
+use crate::mem::DETOUR_REGION_SIZE;
+use std::ops::{Range, RangeInclusive};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionState {
+    Free,
+    Reserve,
+    Commit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionInfo {
+    pub base: usize,
+    pub allocation_base: usize,
+    pub size: usize,
+    pub state: RegionState,
+    pub protection: Protection,
+}
+
+pub trait AddressSpace {
+    fn query(&self, addr: usize) -> Option<RegionInfo>;
+
+    /// allocate a region of `DETOUR_REGION_SIZE` at `addr`, free or reserved by caller
+    fn alloc_at(&mut self, addr: usize) -> bool;
+
+    /// further allocation can not succeed, e.g. dynamic code is blocked
+    fn is_blocked(&self) -> bool {
+        false
+    }
+}
+
+pub trait AllocationStrategy: Send + Sync {
+    /// allocate one region which lies in `bound`, returns its base
+    ///
+    /// `target` is the region aligned address trampolines will jump from
+    fn allocate(
+        &mut self,
+        target: usize,
+        bound: &RangeInclusive<usize>,
+        space: &mut dyn AddressSpace,
+    ) -> Option<usize>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fit {
+    /// Detours search order, below the target first then above
+    #[default]
+    First,
+    /// free slot nearest to the target
+    Closest,
+}
+
+fn round_down_to_region(pb_try: usize) -> usize {
+    pb_try & !(DETOUR_REGION_SIZE - 1)
+}
+
+fn round_up_to_region(pb_try: usize) -> usize {
+    round_down_to_region(pb_try.saturating_add(DETOUR_REGION_SIZE - 1))
+}
+
+fn intersect(a: &Range<usize>, b: &Range<usize>) -> Option<Range<usize>> {
+    let range = a.start.max(b.start)..a.end.min(b.end);
+    if range.is_empty() { None } else { Some(range) }
+}
+
+/// scan queries until a usable slot, allocation is left to the caller
+struct Scanner<'a> {
+    forbidden: &'a [Range<usize>],
+    accept_reserved: bool,
+}
+
+impl Scanner<'_> {
+    fn forbidden(&self, pb_try: usize) -> Option<&Range<usize>> {
+        let slot = pb_try..pb_try.saturating_add(DETOUR_REGION_SIZE);
+        self.forbidden
+            .iter()
+            .find(|x| intersect(x, &slot).is_some())
+    }
+
+    fn usable(&self, info: &RegionInfo, pb_try: usize) -> bool {
+        let state = info.state == RegionState::Free
+            || (self.accept_reserved && info.state == RegionState::Reserve);
+        state && info.base + info.size >= pb_try + DETOUR_REGION_SIZE
+    }
+
+    /// highest usable slot at or below `pb_try` and above `lo`
+    fn next_down(&self, mut pb_try: usize, lo: usize, space: &dyn AddressSpace) -> Option<usize> {
+        pb_try = round_down_to_region(pb_try);
+        while pb_try > lo {
+            if let Some(range) = self.forbidden(pb_try) {
+                pb_try = round_down_to_region(range.start.checked_sub(DETOUR_REGION_SIZE)?);
+                continue;
+            }
+            let info = space.query(pb_try)?;
+            if self.usable(&info, pb_try) {
+                return Some(pb_try);
+            }
+            let next = if info.state == RegionState::Free {
+                pb_try
+            } else {
+                info.allocation_base.min(pb_try)
+            };
+            pb_try = round_down_to_region(next.checked_sub(DETOUR_REGION_SIZE)?);
+        }
+        None
+    }
+
+    /// lowest usable slot at or above `pb_try` and below `hi`
+    fn next_up(&self, mut pb_try: usize, hi: usize, space: &dyn AddressSpace) -> Option<usize> {
+        pb_try = round_up_to_region(pb_try);
+        while pb_try < hi {
+            if let Some(range) = self.forbidden(pb_try) {
+                pb_try = round_up_to_region(range.end);
+                continue;
+            }
+            let info = space.query(pb_try)?;
+            if self.usable(&info, pb_try) {
+                return Some(pb_try);
+            }
+            let next = info.base.saturating_add(info.size);
+            pb_try = round_up_to_region(next.max(pb_try + DETOUR_REGION_SIZE));
+        }
+        None
+    }
+
+    fn alloc_from_hi(&self, range: Range<usize>, space: &mut dyn AddressSpace) -> Option<usize> {
+        if space.is_blocked() {
+            return None;
+        }
+        let mut pb_try = range.end.checked_sub(DETOUR_REGION_SIZE)?;
+        loop {
+            let found = self.next_down(pb_try, range.start, space)?;
+            if space.alloc_at(found) {
+                return Some(found);
+            }
+            if space.is_blocked() {
+                return None;
+            }
+            pb_try = found.checked_sub(DETOUR_REGION_SIZE)?;
+        }
+    }
+
+    fn alloc_from_lo(&self, range: Range<usize>, space: &mut dyn AddressSpace) -> Option<usize> {
+        if space.is_blocked() {
+            return None;
+        }
+        let mut pb_try = range.start;
+        loop {
+            let found = self.next_up(pb_try, range.end, space)?;
+            if found + DETOUR_REGION_SIZE > range.end {
+                return None;
+            }
+            if space.alloc_at(found) {
+                return Some(found);
+            }
+            if space.is_blocked() {
+                return None;
+            }
+            pb_try = found + DETOUR_REGION_SIZE;
+        }
+    }
+
+    fn closest(
+        &self,
+        target: usize,
+        range: Range<usize>,
+        space: &mut dyn AddressSpace,
+    ) -> Option<usize> {
+        if space.is_blocked() {
+            return None;
+        }
+        let target = target.clamp(range.start, range.end);
+        let mut down = target
+            .checked_sub(DETOUR_REGION_SIZE)
+            .and_then(|x| self.next_down(x, range.start, space));
+        let mut up = self
+            .next_up(target, range.end, space)
+            .filter(|x| x + DETOUR_REGION_SIZE <= range.end);
+        loop {
+            let take_down = match (down, up) {
+                (None, None) => return None,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (Some(d), Some(u)) => target - d <= u - target,
+            };
+            let found = if take_down { down? } else { up? };
+            if space.alloc_at(found) {
+                return Some(found);
+            }
+            if space.is_blocked() {
+                return None;
+            }
+            if take_down {
+                down = found
+                    .checked_sub(DETOUR_REGION_SIZE)
+                    .and_then(|x| self.next_down(x, range.start, space));
+            } else {
+                up = self
+                    .next_up(found + DETOUR_REGION_SIZE, range.end, space)
+                    .filter(|x| x + DETOUR_REGION_SIZE <= range.end);
+            }
+        }
+    }
+}
+
+/// Windows keeps system dlls around this range on 32-bit, avoid it by default
+#[cfg(target_os = "windows")]
+pub const SYSTEM_REGION_BOUND: Range<usize> = 0x70000000..0x80000000;
+
+fn default_forbidden() -> Vec<Range<usize>> {
+    #[cfg(target_os = "windows")]
+    return vec![SYSTEM_REGION_BOUND];
+    #[cfg(not(target_os = "windows"))]
+    vec![]
+}
+
+/// scan the address space around the target
+///
+/// preferred ranges are tried in order before the whole bound,
+/// forbidden ranges are never used
+pub struct SearchStrategy {
+    pub fit: Fit,
+    pub preferred: Vec<Range<usize>>,
+    pub forbidden: Vec<Range<usize>>,
+}
+
+impl Default for SearchStrategy {
+    fn default() -> Self {
+        SearchStrategy {
+            fit: Fit::default(),
+            preferred: vec![],
+            forbidden: default_forbidden(),
+        }
+    }
+}
+
+impl SearchStrategy {
+    pub fn new(fit: Fit) -> SearchStrategy {
+        SearchStrategy {
+            fit,
+            ..Default::default()
+        }
+    }
+
+    pub fn prefer(mut self, range: Range<usize>) -> SearchStrategy {
+        self.preferred.push(range);
+        self
+    }
+
+    pub fn forbid(mut self, range: Range<usize>) -> SearchStrategy {
+        self.forbidden.push(range);
+        self
+    }
+
+    fn scanner(&self) -> Scanner<'_> {
+        Scanner {
+            forbidden: &self.forbidden,
+            accept_reserved: false,
+        }
+    }
+
+    fn first_fit(
+        &self,
+        target: usize,
+        range: Range<usize>,
+        space: &mut dyn AddressSpace,
+    ) -> Option<usize> {
+        let scanner = self.scanner();
+        let mut pb_try = None;
+
+        #[cfg(target_pointer_width = "64")]
+        {
+            const GB: usize = 0x40000000;
+            // Try looking 1GB below or lower.
+            if pb_try.is_none() && target > range.start.saturating_add(GB) {
+                pb_try = scanner.alloc_from_hi(range.start..target - GB, space);
+            }
+            // Try looking 1GB above or higher.
+            if pb_try.is_none() && target < range.end.saturating_sub(GB) {
+                pb_try = scanner.alloc_from_lo(target + GB..range.end, space);
+            }
+            // Try looking 1GB below or higher.
+            if pb_try.is_none() && target > GB {
+                pb_try = scanner.alloc_from_lo((target - GB).max(range.start)..target, space);
+            }
+            // Try looking 1GB above or lower.
+            if pb_try.is_none() && target < usize::MAX - GB {
+                pb_try = scanner.alloc_from_hi(target..(target + GB).min(range.end), space);
+            }
+        }
+
+        // Try anything below.
+        if pb_try.is_none() && target > range.start {
+            pb_try = scanner.alloc_from_hi(range.start..target.min(range.end), space);
+        }
+        // try anything above.
+        if pb_try.is_none() && target < range.end {
+            pb_try = scanner.alloc_from_lo(target.max(range.start)..range.end, space);
+        }
+
+        pb_try
+    }
+
+    fn search(
+        &self,
+        target: usize,
+        range: Range<usize>,
+        space: &mut dyn AddressSpace,
+    ) -> Option<usize> {
+        match self.fit {
+            Fit::First => self.first_fit(target, range, space),
+            Fit::Closest => self.scanner().closest(target, range, space),
+        }
+    }
+}
+
+impl AllocationStrategy for SearchStrategy {
+    fn allocate(
+        &mut self,
+        target: usize,
+        bound: &RangeInclusive<usize>,
+        space: &mut dyn AddressSpace,
+    ) -> Option<usize> {
+        let bound = *bound.start()..bound.end().saturating_add(1);
+        self.preferred
+            .iter()
+            .filter_map(|x| intersect(x, &bound))
+            .find_map(|x| self.search(target, x, space))
+            .or_else(|| self.search(target, bound, space))
+    }
+}
+
+/// only place regions inside memory the caller reserved up front
+///
+/// reserved but uncommitted slots are committed on use, free slots inside arena are used too
+pub struct ArenaStrategy {
+    pub fit: Fit,
+    pub arenas: Vec<Range<usize>>,
+}
+
+impl ArenaStrategy {
+    pub fn new(arenas: Vec<Range<usize>>) -> ArenaStrategy {
+        ArenaStrategy {
+            fit: Fit::Closest,
+            arenas,
+        }
+    }
+}
+
+impl AllocationStrategy for ArenaStrategy {
+    fn allocate(
+        &mut self,
+        target: usize,
+        bound: &RangeInclusive<usize>,
+        space: &mut dyn AddressSpace,
+    ) -> Option<usize> {
+        let bound = *bound.start()..bound.end().saturating_add(1);
+        let scanner = Scanner {
+            forbidden: &[],
+            accept_reserved: true,
+        };
+        self.arenas
+            .iter()
+            .filter_map(|x| intersect(x, &bound))
+            .find_map(|range| match self.fit {
+                Fit::First => scanner.alloc_from_lo(range, space),
+                Fit::Closest => scanner.closest(target, range, space),
+            })
+    }
+}
+
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    const R: usize = DETOUR_REGION_SIZE;
+    const GB: usize = 0x4000_0000;
+    const TARGET: usize = 0x7f00_0000_0000;
+
+    /// address space where every region is free unless listed
+    #[derive(Default)]
+    struct FakeSpace {
+        used: BTreeMap<usize, RegionState>,
+        failing: Vec<usize>,
+        policy: bool,
+        blocked: bool,
+        attempts: Vec<usize>,
+    }
+
+    impl FakeSpace {
+        fn with(mut self, addr: usize, state: RegionState) -> FakeSpace {
+            self.used.insert(addr, state);
+            self
+        }
+    }
+
+    impl AddressSpace for FakeSpace {
+        fn query(&self, addr: usize) -> Option<RegionInfo> {
+            let base = round_down_to_region(addr);
+            Some(RegionInfo {
+                base,
+                allocation_base: base,
+                size: R,
+                state: self.used.get(&base).copied().unwrap_or(RegionState::Free),
+                protection: Protection::NoAccess,
+            })
+        }
+
+        fn alloc_at(&mut self, addr: usize) -> bool {
+            self.attempts.push(addr);
+            // a policy like arbitrary code guard fails the first allocation and every later one
+            self.blocked |= self.policy;
+            if self.blocked
+                || self.failing.contains(&addr)
+                || self.used.get(&addr) == Some(&RegionState::Commit)
+            {
+                return false;
+            }
+            self.used.insert(addr, RegionState::Commit);
+            true
+        }
+
+        fn is_blocked(&self) -> bool {
+            self.blocked
+        }
+    }
+
+    fn reach(target: usize) -> RangeInclusive<usize> {
+        target - 0x7ff8_0000..=target + 0x7ff8_0000
+    }
+
+    #[test]
+    fn first_fit_looks_1gb_below() {
+        let mut space = FakeSpace::default();
+        let found = SearchStrategy::new(Fit::First).allocate(TARGET, &reach(TARGET), &mut space);
+        assert_eq!(found, Some(TARGET - GB - R));
+        assert_eq!(space.attempts, [TARGET - GB - R]);
+    }
+
+    #[test]
+    fn closest_skips_used_regions() {
+        let mut space = FakeSpace::default()
+            .with(TARGET - R, RegionState::Commit)
+            .with(TARGET, RegionState::Reserve);
+        let mut strategy = SearchStrategy::new(Fit::Closest);
+        assert_eq!(
+            strategy.allocate(TARGET, &reach(TARGET), &mut space),
+            Some(TARGET + R)
+        );
+
+        // a failed allocation moves on, ties go below
+        let mut space = FakeSpace {
+            failing: vec![TARGET + R],
+            ..FakeSpace::default()
+        }
+        .with(TARGET - R, RegionState::Commit)
+        .with(TARGET, RegionState::Commit);
+        assert_eq!(
+            strategy.allocate(TARGET, &reach(TARGET), &mut space),
+            Some(TARGET - 2 * R)
+        );
+        assert_eq!(space.attempts, [TARGET + R, TARGET - 2 * R]);
+    }
+
+    #[test]
+    fn preferred_and_forbidden_ranges() {
+        let preferred = TARGET - 0x1000_0000..TARGET - 0x1000_0000 + 4 * R;
+        let mut strategy = SearchStrategy::new(Fit::Closest).prefer(preferred.clone());
+        let found = strategy.allocate(TARGET, &reach(TARGET), &mut FakeSpace::default());
+        assert_eq!(found, Some(preferred.end - R));
+
+        // a preferred range out of reach is ignored
+        let mut strategy = SearchStrategy::new(Fit::Closest).prefer(0x10000..0x20000);
+        let found = strategy.allocate(TARGET, &reach(TARGET), &mut FakeSpace::default());
+        assert_eq!(found, Some(TARGET));
+
+        let mut strategy = SearchStrategy::new(Fit::Closest).forbid(TARGET - 4 * R..TARGET + 4 * R);
+        let found = strategy.allocate(TARGET, &reach(TARGET), &mut FakeSpace::default());
+        assert_eq!(found, Some(TARGET + 4 * R));
+    }
+
+    #[test]
+    fn blocked_space_stops_searching() {
+        for fit in [Fit::First, Fit::Closest] {
+            let mut space = FakeSpace {
+                policy: true,
+                ..FakeSpace::default()
+            };
+            let mut strategy = SearchStrategy::new(fit).prefer(TARGET - 0x1000_0000..TARGET);
+            let found = strategy.allocate(TARGET, &reach(TARGET), &mut space);
+            assert_eq!((found, space.attempts.len()), (None, 1));
+        }
+    }
+
+    #[test]
+    fn arena_uses_reserved_slots_inside() {
+        let arena = TARGET + 0x100_0000..TARGET + 0x100_0000 + 4 * R;
+        let space = || {
+            FakeSpace::default()
+                .with(arena.start, RegionState::Reserve)
+                .with(arena.start + R, RegionState::Commit)
+                .with(arena.start + 2 * R, RegionState::Reserve)
+                .with(arena.start + 3 * R, RegionState::Commit)
+        };
+
+        let mut strategy = ArenaStrategy::new(vec![0x10000..0x20000, arena.clone()]);
+        assert_eq!(
+            strategy.allocate(TARGET, &reach(TARGET), &mut space()),
+            Some(arena.start)
+        );
+
+        let mut space = space().with(arena.start, RegionState::Commit);
+        let found = strategy.allocate(TARGET, &reach(TARGET), &mut space);
+        assert_eq!(found, Some(arena.start + 2 * R));
+        let found = strategy.allocate(TARGET, &reach(TARGET), &mut space);
+        assert_eq!(found, None);
+
+        strategy.fit = Fit::First;
+        let found = strategy.allocate(TARGET, &reach(TARGET), &mut FakeSpace::default());
+        assert_eq!(found, Some(arena.start));
+        // out of reach of the target
+        let far = TARGET + 0x1_0000_0000;
+        assert_eq!(
+            strategy.allocate(far, &reach(far), &mut FakeSpace::default()),
+            None
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! memory operations behind regions and patching
+//!
+//! [`ProcessMemory`] talks to the current process, [`SimulatedMemory`] models a fake
+//! address space so allocation and patching can be checked deterministically,
+//! `RemoteProcess` reaches into another process on x86_64 Linux
+
+mod process;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod remote;
+mod sim;
+
+pub use process::ProcessMemory;
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+pub(crate) use remote::tests::spawn;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub use remote::{CALL_STUB, RemoteProcess};
+pub use sim::SimulatedMemory;
+
+use crate::alloc::RegionInfo;
+use crate::{Error, platform};
+use std::ptr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protection {
+    NoAccess,
+    Read,
+    ReadWrite,
+    ReadExecute,
+    ReadWriteExecute,
+    /// backend specific value, only handed back to restore it
+    Other(u32),
+}
+
+impl Protection {
+    pub fn is_writable(&self) -> bool {
+        matches!(self, Protection::ReadWrite | Protection::ReadWriteExecute)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        matches!(self, Protection::ReadExecute | Protection::ReadWriteExecute)
+    }
+
+    pub fn is_readable(&self) -> bool {
+        match self {
+            Protection::NoAccess => false,
+            Protection::Other(flag) => platform::is_readable(*flag),
+            _ => true,
+        }
+    }
+}
+
+/// two views of the same memory, see [`crate::RegionMode::DualMapped`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DualMapping {
+    pub exec: usize,
+    pub write: usize,
+    pub section: usize,
+}
+
+pub trait MemoryBackend: Send + Sync {
+    fn query(&self, addr: usize) -> Option<RegionInfo>;
+
+    /// addresses belong to the current process and can be dereferenced directly
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    /// default reads memory of the current process directly
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        if addr == 0 {
+            return Err(Error::InvalidAddress);
+        }
+        unsafe { ptr::copy(addr as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// default writes memory of the current process directly, caller handles protection
+    fn write(&self, addr: usize, data: &[u8]) -> Result<(), Error> {
+        if addr == 0 {
+            return Err(Error::InvalidAddress);
+        }
+        unsafe { ptr::copy(data.as_ptr(), addr as *mut u8, data.len()) };
+        Ok(())
+    }
+
+    /// returns previous protection of the first page
+    fn protect(
+        &self,
+        addr: usize,
+        size: usize,
+        protection: Protection,
+    ) -> Result<Protection, Error>;
+
+    /// reserve and commit `size` bytes at `addr` as read write execute
+    fn alloc(&self, addr: usize, size: usize) -> Option<usize>;
+
+    /// commit `size` bytes inside an existing reservation
+    fn commit(&self, addr: usize, size: usize) -> Option<usize>;
+
+    /// release whole allocation starting at `addr`
+    fn free(&self, addr: usize) -> Result<(), Error>;
+
+    fn decommit(&self, addr: usize, size: usize) -> Result<(), Error>;
+
+    fn alloc_dual(&self, _addr: usize, _size: usize) -> Option<DualMapping> {
+        None
+    }
+
+    fn free_dual(&self, _mapping: &DualMapping) -> Result<(), Error> {
+        Err(Error::InvalidAddress)
+    }
+
+    fn flush_instruction_cache(&self, _addr: usize, _size: usize) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn is_dynamic_code_blocked(&self) -> bool {
+        false
+    }
+}
+
+pub struct MemoryProtector<'a> {
+    backend: &'a dyn MemoryBackend,
+    addr: usize,
+    size: usize,
+    old_flag: Protection,
+}
+
+impl MemoryProtector<'static> {
+    pub fn new(addr: usize, size: usize) -> Result<MemoryProtector<'static>, Error> {
+        Self::with_backend(&ProcessMemory, addr, size)
+    }
+
+    pub fn new_with<T: Sized>(addr: usize) -> Result<MemoryProtector<'static>, Error> {
+        Self::new(addr, size_of::<T>())
+    }
+}
+
+impl<'a> MemoryProtector<'a> {
+    pub fn with_backend(
+        backend: &'a dyn MemoryBackend,
+        addr: usize,
+        size: usize,
+    ) -> Result<MemoryProtector<'a>, Error> {
+        backend
+            .protect(addr, size, Protection::ReadWriteExecute)
+            .map(|old_flag| MemoryProtector {
+                backend,
+                addr,
+                size,
+                old_flag,
+            })
+    }
+
+    pub fn write_override<T>(&mut self, value: T) -> usize {
+        let t_size = size_of_val(&value);
+        if t_size > self.size {
+            return 0;
+        }
+        unsafe {
+            ptr::write_unaligned(self.addr as *mut T, value);
+        }
+        t_size
+    }
+
+    /// # Safety
+    ///
+    /// `from` must be readable for `size` bytes
+    pub unsafe fn write_from_with_size<T>(&mut self, from: *const T, size: usize) -> usize {
+        unsafe { ptr::copy(from.cast::<u8>(), self.addr as *mut u8, size) };
+        size
+    }
+}
+
+impl Drop for MemoryProtector<'_> {
+    fn drop(&mut self) {
+        let _ = self.backend.protect(self.addr, self.size, self.old_flag);
+    }
+}
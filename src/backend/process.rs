@@ -0,0 +1,112 @@
+use crate::Error;
+use crate::alloc::{RegionInfo, RegionState};
+use crate::backend::{DualMapping, MemoryBackend, Protection};
+use crate::platform::{
+    MEM_TYPE_COMMIT, MEM_TYPE_FREE, MEM_TYPE_RESERVE, PAGE_FLAG_EXECUTE_READ,
+    PAGE_FLAG_EXECUTE_READWRITE, PAGE_FLAG_NOACCESS, PAGE_FLAG_READONLY, PAGE_FLAG_READWRITE,
+    PageProtectionFlag, valloc, valloc_dual, vdecommit, vfree, vfree_dual, vprotect, vquery,
+};
+use std::ffi::c_void;
+
+/// memory of the current process
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessMemory;
+
+fn to_flag(protection: Protection) -> PageProtectionFlag {
+    match protection {
+        Protection::NoAccess => PAGE_FLAG_NOACCESS,
+        Protection::Read => PAGE_FLAG_READONLY,
+        Protection::ReadWrite => PAGE_FLAG_READWRITE,
+        Protection::ReadExecute => PAGE_FLAG_EXECUTE_READ,
+        Protection::ReadWriteExecute => PAGE_FLAG_EXECUTE_READWRITE,
+        Protection::Other(flag) => flag,
+    }
+}
+
+fn from_flag(flag: PageProtectionFlag) -> Protection {
+    [
+        Protection::NoAccess,
+        Protection::Read,
+        Protection::ReadWrite,
+        Protection::ReadExecute,
+        Protection::ReadWriteExecute,
+    ]
+    .into_iter()
+    .find(|x| to_flag(*x) == flag)
+    .unwrap_or(Protection::Other(flag))
+}
+
+impl MemoryBackend for ProcessMemory {
+    fn query(&self, addr: usize) -> Option<RegionInfo> {
+        let mbi = vquery(addr as *const _)?;
+        let state = if mbi.state == MEM_TYPE_FREE {
+            RegionState::Free
+        } else if mbi.state == MEM_TYPE_RESERVE {
+            RegionState::Reserve
+        } else {
+            RegionState::Commit
+        };
+        Some(RegionInfo {
+            base: mbi.base_address as usize,
+            allocation_base: mbi.allocation_base as usize,
+            size: mbi.region_size,
+            state,
+            protection: from_flag(mbi.protect),
+        })
+    }
+
+    fn protect(
+        &self,
+        addr: usize,
+        size: usize,
+        protection: Protection,
+    ) -> Result<Protection, Error> {
+        vprotect(addr as *const c_void, size, to_flag(protection)).map(from_flag)
+    }
+
+    fn alloc(&self, addr: usize, size: usize) -> Option<usize> {
+        valloc(addr as *const _, size, MEM_TYPE_COMMIT | MEM_TYPE_RESERVE).map(|x| x as usize)
+    }
+
+    fn commit(&self, addr: usize, size: usize) -> Option<usize> {
+        valloc(addr as *const _, size, MEM_TYPE_COMMIT).map(|x| x as usize)
+    }
+
+    fn free(&self, addr: usize) -> Result<(), Error> {
+        vfree(addr as *mut c_void)
+    }
+
+    fn decommit(&self, addr: usize, size: usize) -> Result<(), Error> {
+        vdecommit(addr as *mut c_void, size)
+    }
+
+    fn alloc_dual(&self, addr: usize, size: usize) -> Option<DualMapping> {
+        valloc_dual(addr as *const _, size).map(|(exec, write, section)| DualMapping {
+            exec: exec as usize,
+            write: write as usize,
+            section,
+        })
+    }
+
+    fn free_dual(&self, mapping: &DualMapping) -> Result<(), Error> {
+        vfree_dual(
+            mapping.exec as *mut c_void,
+            mapping.write as *mut c_void,
+            mapping.section,
+        )
+    }
+
+    #[cfg(target_os = "windows")]
+    fn flush_instruction_cache(&self, addr: usize, size: usize) -> Result<(), Error> {
+        crate::platform::flush_instruction_cache(
+            crate::platform::get_current_process(),
+            addr as *const c_void,
+            size,
+        )
+    }
+
+    #[cfg(target_os = "windows")]
+    fn is_dynamic_code_blocked(&self) -> bool {
+        crate::platform::check_dynamic_code_blocked()
+    }
+}
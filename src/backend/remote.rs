@@ -0,0 +1,587 @@
+use crate::Error;
+use crate::alloc::{RegionInfo, RegionState};
+use crate::backend::{MemoryBackend, Protection};
+use fnv::FnvHashMap;
+use std::ffi::c_void;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+const PAGE_SIZE: usize = 0x1000;
+const SYSCALL: u64 = 0x050f;
+
+/// `call rax; int3`, place in remote executable memory for [`RemoteProcess::call`]
+pub const CALL_STUB: [u8; 3] = [0xff, 0xd0, 0xcc];
+
+fn last_error() -> Error {
+    Error::ErrorCode(std::io::Error::last_os_error().raw_os_error().unwrap_or(0) as usize)
+}
+
+fn to_prot(protection: Protection) -> i32 {
+    match protection {
+        Protection::NoAccess => libc::PROT_NONE,
+        Protection::Read => libc::PROT_READ,
+        Protection::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+        Protection::ReadExecute => libc::PROT_READ | libc::PROT_EXEC,
+        Protection::ReadWriteExecute => libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+        Protection::Other(prot) => prot as i32,
+    }
+}
+
+fn from_perms(perms: &[u8]) -> Protection {
+    match (perms[0], perms[1], perms[2]) {
+        (b'-', b'-', b'-') => Protection::NoAccess,
+        (b'r', b'-', b'-') => Protection::Read,
+        (b'r', b'w', b'-') => Protection::ReadWrite,
+        (b'r', b'-', b'x') => Protection::ReadExecute,
+        (b'r', b'w', b'x') => Protection::ReadWriteExecute,
+        (r, w, x) => {
+            let flag = |set: bool, prot: i32| if set { prot as u32 } else { 0 };
+            Protection::Other(
+                flag(r == b'r', libc::PROT_READ)
+                    | flag(w == b'w', libc::PROT_WRITE)
+                    | flag(x == b'x', libc::PROT_EXEC),
+            )
+        }
+    }
+}
+
+struct State {
+    // signals which arrived while stepping a remote syscall, delivered on detach
+    pending: Vec<i32>,
+    // remote mappings made by `alloc`, munmap needs their length
+    allocations: FnvHashMap<usize, usize>,
+}
+
+/// memory of another process, attached with ptrace
+///
+/// memory is accessed with `process_vm_readv`/`process_vm_writev`, allocation and
+/// protection run as syscalls injected into the stopped main thread. ptrace requests are
+/// bound to the attaching thread, use the backend from that thread only. Other threads of
+/// the target keep running.
+pub struct RemoteProcess {
+    pid: libc::pid_t,
+    state: Mutex<State>,
+}
+
+impl RemoteProcess {
+    /// attach to `pid` and wait until it stops, detached again on drop
+    pub fn attach(pid: u32) -> Result<RemoteProcess, Error> {
+        let pid = pid as libc::pid_t;
+        if unsafe { libc::ptrace(libc::PTRACE_ATTACH, pid, 0, 0) } < 0 {
+            return Err(last_error());
+        }
+        let process = RemoteProcess {
+            pid,
+            state: Mutex::new(State {
+                pending: vec![],
+                allocations: FnvHashMap::default(),
+            }),
+        };
+        process.wait_stop(libc::SIGSTOP, false)?;
+        Ok(process)
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid as u32
+    }
+
+    /// wait for a stop by `signal`, other signals are kept for later delivery
+    fn wait_stop(&self, signal: i32, step: bool) -> Result<(), Error> {
+        loop {
+            let mut status = 0;
+            if unsafe { libc::waitpid(self.pid, &mut status, libc::__WALL) } < 0 {
+                return Err(last_error());
+            }
+            if !libc::WIFSTOPPED(status) {
+                return Err(Error::InvalidAddress);
+            }
+            let stop = libc::WSTOPSIG(status);
+            if stop == signal {
+                return Ok(());
+            }
+            self.state.lock().unwrap().pending.push(stop);
+            let request = if step {
+                libc::PTRACE_SINGLESTEP
+            } else {
+                libc::PTRACE_CONT
+            };
+            if unsafe { libc::ptrace(request, self.pid, 0, 0) } < 0 {
+                return Err(last_error());
+            }
+        }
+    }
+
+    fn regs(&self) -> Result<libc::user_regs_struct, Error> {
+        let mut regs = unsafe { std::mem::zeroed::<libc::user_regs_struct>() };
+        if unsafe { libc::ptrace(libc::PTRACE_GETREGS, self.pid, 0, &mut regs) } < 0 {
+            return Err(last_error());
+        }
+        Ok(regs)
+    }
+
+    fn set_regs(&self, regs: &libc::user_regs_struct) -> Result<(), Error> {
+        if unsafe { libc::ptrace(libc::PTRACE_SETREGS, self.pid, 0, regs) } < 0 {
+            return Err(last_error());
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u64) -> Result<u64, Error> {
+        // -1 is a valid word, errno tells failure apart
+        unsafe { *libc::__errno_location() = 0 };
+        let word = unsafe { libc::ptrace(libc::PTRACE_PEEKTEXT, self.pid, addr, 0) };
+        if word == -1 && std::io::Error::last_os_error().raw_os_error() != Some(0) {
+            return Err(last_error());
+        }
+        Ok(word as u64)
+    }
+
+    fn poke(&self, addr: u64, word: u64) -> Result<(), Error> {
+        if unsafe { libc::ptrace(libc::PTRACE_POKETEXT, self.pid, addr, word) } < 0 {
+            return Err(last_error());
+        }
+        Ok(())
+    }
+
+    /// run one syscall in the target, registers and code are restored afterwards
+    pub fn syscall(&self, nr: i64, args: [u64; 6]) -> Result<u64, Error> {
+        let saved = self.regs()?;
+        let word = self.peek(saved.rip)?;
+        self.poke(saved.rip, (word & !0xffff) | SYSCALL)?;
+
+        let mut regs = saved;
+        regs.rax = nr as u64;
+        // keep the kernel from restarting an interrupted syscall over ours
+        regs.orig_rax = u64::MAX;
+        regs.rdi = args[0];
+        regs.rsi = args[1];
+        regs.rdx = args[2];
+        regs.r10 = args[3];
+        regs.r8 = args[4];
+        regs.r9 = args[5];
+
+        let result = self.set_regs(&regs).and_then(|_| {
+            if unsafe { libc::ptrace(libc::PTRACE_SINGLESTEP, self.pid, 0, 0) } < 0 {
+                return Err(last_error());
+            }
+            self.wait_stop(libc::SIGTRAP, true)?;
+            Ok(self.regs()?.rax)
+        });
+
+        self.poke(saved.rip, word)?;
+        self.set_regs(&saved)?;
+
+        let rax = result?;
+        if rax > -4096i64 as u64 {
+            return Err(Error::ErrorCode(rax.wrapping_neg() as usize));
+        }
+        Ok(rax)
+    }
+
+    /// call `func` in the target through a [`CALL_STUB`] at `stub`
+    ///
+    /// runs on the stopped main thread until the stub traps, registers are restored
+    /// afterwards. The thread may have been stopped holding locks, functions taking
+    /// the same locks will never return.
+    pub fn call(&self, stub: usize, func: usize, args: [u64; 6]) -> Result<u64, Error> {
+        let saved = self.regs()?;
+
+        let mut regs = saved;
+        regs.rip = stub as u64;
+        regs.rax = func as u64;
+        regs.orig_rax = u64::MAX;
+        regs.rdi = args[0];
+        regs.rsi = args[1];
+        regs.rdx = args[2];
+        regs.rcx = args[3];
+        regs.r8 = args[4];
+        regs.r9 = args[5];
+        // step over the red zone, stack is 16 byte aligned at the call
+        regs.rsp = (saved.rsp - 0x100) & !0xf;
+
+        let result = self.set_regs(&regs).and_then(|_| {
+            if unsafe { libc::ptrace(libc::PTRACE_CONT, self.pid, 0, 0) } < 0 {
+                return Err(last_error());
+            }
+            self.wait_stop(libc::SIGTRAP, false)?;
+            let regs = self.regs()?;
+            if regs.rip != (stub + CALL_STUB.len()) as u64 {
+                return Err(Error::InvalidAddress);
+            }
+            Ok(regs.rax)
+        });
+
+        self.set_regs(&saved)?;
+        result
+    }
+
+    /// load address of a file mapped in the target, matched by inode
+    pub fn module_base(&self, path: &Path) -> Option<usize> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", self.pid)).ok()?;
+        maps.lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_ascii_whitespace().collect();
+                let (start, _) = fields.first()?.split_once('-')?;
+                let (major, minor) = fields.get(3)?.split_once(':')?;
+                let dev = libc::makedev(
+                    u32::from_str_radix(major, 16).ok()?,
+                    u32::from_str_radix(minor, 16).ok()?,
+                );
+                let same = fields.get(4)?.parse::<u64>().ok()? == metadata.ino()
+                    && dev == metadata.dev()
+                    && u64::from_str_radix(fields.get(2)?, 16).ok()? == 0;
+                same.then(|| usize::from_str_radix(start, 16).ok())?
+            })
+            .min()
+    }
+
+    fn maps(&self) -> Option<Vec<(usize, usize, Protection)>> {
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", self.pid)).ok()?;
+        maps.lines()
+            .map(|line| {
+                let mut fields = line.split_ascii_whitespace();
+                let (start, end) = fields.next()?.split_once('-')?;
+                let perms = fields.next()?.as_bytes();
+                Some((
+                    usize::from_str_radix(start, 16).ok()?,
+                    usize::from_str_radix(end, 16).ok()?,
+                    from_perms(perms.get(..3)?),
+                ))
+            })
+            .collect()
+    }
+}
+
+impl Drop for RemoteProcess {
+    fn drop(&mut self) {
+        let pending = match self.state.get_mut() {
+            Ok(state) => std::mem::take(&mut state.pending),
+            Err(err) => std::mem::take(&mut err.into_inner().pending),
+        };
+        // detach delivers one signal, the others are queued again once the target runs
+        let (first, rest) = pending.split_first().unwrap_or((&0, &[]));
+        unsafe { libc::ptrace(libc::PTRACE_DETACH, self.pid, 0, *first) };
+        for signal in rest {
+            unsafe { libc::kill(self.pid, *signal) };
+        }
+    }
+}
+
+impl MemoryBackend for RemoteProcess {
+    fn query(&self, addr: usize) -> Option<RegionInfo> {
+        let maps = self.maps()?;
+        let mut free_base = 0;
+        for (start, end, protection) in maps {
+            if addr < start {
+                return Some(RegionInfo {
+                    base: free_base,
+                    allocation_base: 0,
+                    size: start - free_base,
+                    state: RegionState::Free,
+                    protection: Protection::NoAccess,
+                });
+            }
+            if addr < end {
+                return Some(RegionInfo {
+                    base: start,
+                    allocation_base: start,
+                    size: end - start,
+                    state: RegionState::Commit,
+                    protection,
+                });
+            }
+            free_base = end;
+        }
+        Some(RegionInfo {
+            base: free_base,
+            allocation_base: 0,
+            size: usize::MAX - free_base,
+            state: RegionState::Free,
+            protection: Protection::NoAccess,
+        })
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let local = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: addr as *mut c_void,
+            iov_len: buf.len(),
+        };
+        let read = unsafe { libc::process_vm_readv(self.pid, &local, 1, &remote, 1, 0) };
+        match read {
+            -1 => Err(last_error()),
+            n if n as usize != buf.len() => Err(Error::InvalidAddress),
+            _ => Ok(()),
+        }
+    }
+
+    /// respects page protection like the target itself would
+    fn write(&self, addr: usize, data: &[u8]) -> Result<(), Error> {
+        let local = libc::iovec {
+            iov_base: data.as_ptr() as *mut c_void,
+            iov_len: data.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: addr as *mut c_void,
+            iov_len: data.len(),
+        };
+        let written = unsafe { libc::process_vm_writev(self.pid, &local, 1, &remote, 1, 0) };
+        match written {
+            -1 => Err(last_error()),
+            n if n as usize != data.len() => Err(Error::InvalidAddress),
+            _ => Ok(()),
+        }
+    }
+
+    fn protect(
+        &self,
+        addr: usize,
+        size: usize,
+        protection: Protection,
+    ) -> Result<Protection, Error> {
+        let old = self
+            .query(addr)
+            .filter(|x| x.state == RegionState::Commit)
+            .ok_or(Error::InvalidAddress)?
+            .protection;
+        let start = addr & !(PAGE_SIZE - 1);
+        let end = (addr + size.max(1)).next_multiple_of(PAGE_SIZE);
+        self.syscall(
+            libc::SYS_mprotect,
+            [
+                start as u64,
+                (end - start) as u64,
+                to_prot(protection) as u64,
+                0,
+                0,
+                0,
+            ],
+        )?;
+        Ok(old)
+    }
+
+    fn alloc(&self, addr: usize, size: usize) -> Option<usize> {
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE;
+        let mapped = self
+            .syscall(
+                libc::SYS_mmap,
+                [
+                    addr as u64,
+                    size as u64,
+                    to_prot(Protection::ReadWriteExecute) as u64,
+                    flags as u64,
+                    u64::MAX,
+                    0,
+                ],
+            )
+            .ok()? as usize;
+        // kernels before 4.17 treat the address as hint only
+        if mapped != addr {
+            let _ = self.syscall(libc::SYS_munmap, [mapped as u64, size as u64, 0, 0, 0, 0]);
+            return None;
+        }
+        self.state.lock().unwrap().allocations.insert(addr, size);
+        Some(addr)
+    }
+
+    fn commit(&self, addr: usize, size: usize) -> Option<usize> {
+        self.protect(addr, size, Protection::ReadWriteExecute)
+            .ok()
+            .map(|_| addr)
+    }
+
+    fn free(&self, addr: usize) -> Result<(), Error> {
+        let size = self
+            .state
+            .lock()
+            .unwrap()
+            .allocations
+            .remove(&addr)
+            .ok_or(Error::InvalidAddress)?;
+        self.syscall(libc::SYS_munmap, [addr as u64, size as u64, 0, 0, 0, 0])
+            .map(|_| ())
+    }
+
+    fn decommit(&self, addr: usize, size: usize) -> Result<(), Error> {
+        self.syscall(
+            libc::SYS_madvise,
+            [
+                addr as u64,
+                size as u64,
+                libc::MADV_DONTNEED as u64,
+                0,
+                0,
+                0,
+            ],
+        )?;
+        self.protect(addr, size, Protection::NoAccess).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{Detours, RegionMode};
+    use std::process::{Child, Command};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // push rbp; mov rbp, rsp; lea eax, [rdi + rdi * 2]; pop rbp; ret
+    const TRIPLE: [u8; 10] = [0x55, 0x48, 0x89, 0xe5, 0x8d, 0x04, 0x7f, 0x5d, 0xc3, 0xcc];
+
+    /// child process killed on drop, drop the attached backend first
+    pub(crate) struct Target(pub(crate) Child);
+
+    impl Drop for Target {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// run `command`, returned once it replaced the forked test binary, finished loading
+    /// and went to sleep
+    pub(crate) fn spawn(command: &mut Command) -> Target {
+        let child = Target(command.spawn().unwrap());
+        let own = std::fs::read_link("/proc/self/exe").unwrap();
+        let exe = format!("/proc/{}/exe", child.0.id());
+        let stat = format!("/proc/{}/stat", child.0.id());
+        let sleeping = || {
+            let stat = std::fs::read_to_string(&stat).unwrap_or_default();
+            // state follows the parenthesized command name
+            stat.rsplit_once(") ").is_some_and(|x| x.1.starts_with('S'))
+        };
+        while std::fs::read_link(&exe).is_ok_and(|x| x == own) || !sleeping() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        child
+    }
+
+    /// free page of the target above `addr`
+    fn free_above(remote: &RemoteProcess, addr: usize) -> usize {
+        let mut cursor = addr;
+        loop {
+            let info = remote.query(cursor).unwrap();
+            if info.state == RegionState::Free && info.size >= 0x10000 {
+                return info.base.max(addr).next_multiple_of(0x10000);
+            }
+            cursor = info.base + info.size;
+        }
+    }
+
+    #[test]
+    fn read_write_and_alloc_near() {
+        let target = spawn(Command::new("sleep").arg("60"));
+        let remote = RemoteProcess::attach(target.0.id()).unwrap();
+        let exe = std::fs::read_link(format!("/proc/{}/exe", remote.pid())).unwrap();
+        let base = remote.module_base(&exe).unwrap();
+        let mut magic = [0u8; 4];
+        remote.read(base, &mut magic).unwrap();
+        assert_eq!(magic, *b"\x7fELF");
+
+        let page = free_above(&remote, base);
+        assert!(page.abs_diff(base) < 0x8000_0000);
+        assert_eq!(remote.alloc(page, 0x1000), Some(page));
+        assert_eq!(remote.alloc(page, 0x1000), None);
+        let info = remote.query(page).unwrap();
+        assert_eq!((info.base, info.state), (page, RegionState::Commit));
+        assert_eq!(info.protection, Protection::ReadWriteExecute);
+
+        remote.write(page + 0x10, b"remote").unwrap();
+        let mut back = [0u8; 6];
+        remote.read(page + 0x10, &mut back).unwrap();
+        assert_eq!(&back, b"remote");
+
+        let old = remote.protect(page, 0x1000, Protection::Read).unwrap();
+        assert_eq!(old, Protection::ReadWriteExecute);
+        assert!(remote.write(page, b"x").is_err());
+        remote.free(page).unwrap();
+        assert_eq!(remote.query(page).unwrap().state, RegionState::Free);
+        assert!(remote.read(page, &mut back).is_err());
+    }
+
+    #[test]
+    fn attach_detour_in_remote_process() {
+        let target = spawn(Command::new("sleep").arg("60"));
+        let remote = RemoteProcess::attach(target.0.id()).unwrap();
+        let exe = std::fs::read_link(format!("/proc/{}/exe", remote.pid())).unwrap();
+        let code = free_above(&remote, remote.module_base(&exe).unwrap());
+        remote.alloc(code, 0x1000).unwrap();
+        remote.write(code, &TRIPLE).unwrap();
+        remote
+            .protect(code, 0x1000, Protection::ReadExecute)
+            .unwrap();
+        let detour = code + 0x800;
+
+        let remote = Arc::new(remote);
+        let mut detours = Detours::with_backend(RegionMode::default(), remote.clone());
+        let attached = detours
+            .lock()
+            .unwrap()
+            .attach(code as *const c_void, detour as *const c_void);
+        attached.unwrap();
+
+        let mut patched = [0u8; 5];
+        remote.read(code, &mut patched).unwrap();
+        let rel = i32::from_le_bytes(patched[1..5].try_into().unwrap());
+        assert_eq!(patched[0], 0xe9);
+        assert_eq!((code + 5).wrapping_add_signed(rel as isize), detour);
+        assert_eq!(
+            remote.query(code).unwrap().protection,
+            Protection::ReadExecute
+        );
+
+        let trampoline = *detours.get(&code).unwrap().trampoline::<usize>();
+        assert_ne!(remote.query(trampoline).unwrap().allocation_base, 0);
+        detours.get(&code).unwrap().verify().unwrap();
+
+        detours.lock().unwrap().detach(&code);
+        let mut restored = [0u8; TRIPLE.len()];
+        remote.read(code, &mut restored).unwrap();
+        assert_eq!(restored, TRIPLE);
+    }
+
+    #[test]
+    fn signals_arriving_while_stepping_are_delivered() {
+        let marks = std::env::temp_dir().join(format!("detours-signals-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marks);
+        let script = format!(
+            "trap 'echo usr1 >> {0}' USR1; trap 'echo usr2 >> {0}' USR2; \
+             while :; do sleep 0.01; done",
+            marks.display()
+        );
+        // the shell sets its traps before it sleeps
+        let target = spawn(Command::new("sh").args(["-c", &script]));
+
+        let remote = RemoteProcess::attach(target.0.id()).unwrap();
+        unsafe { libc::kill(remote.pid as libc::pid_t, libc::SIGUSR1) };
+        unsafe { libc::kill(remote.pid as libc::pid_t, libc::SIGUSR2) };
+        let pid = remote.syscall(libc::SYS_getpid, [0; 6]).unwrap();
+        assert_eq!(pid, target.0.id() as u64);
+        let pending = remote.state.lock().unwrap().pending.clone();
+        assert!(pending.contains(&libc::SIGUSR1) && pending.contains(&libc::SIGUSR2));
+        drop(remote);
+
+        let mut seen = String::new();
+        for _ in 0..200 {
+            seen = std::fs::read_to_string(&marks).unwrap_or_default();
+            if seen.lines().count() == 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let _ = std::fs::remove_file(&marks);
+        let mut lines: Vec<&str> = seen.lines().collect();
+        lines.sort_unstable();
+        assert_eq!(lines, ["usr1", "usr2"]);
+    }
+}
@@ -0,0 +1,314 @@
+use crate::Error;
+use crate::alloc::{RegionInfo, RegionState};
+use crate::backend::{MemoryBackend, Protection};
+use std::alloc::Layout;
+use std::ops::Range;
+use std::sync::Mutex;
+
+const PAGE_SIZE: usize = 0x1000;
+const ALLOCATION_GRANULARITY: usize = 0x10000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Page {
+    state: RegionState,
+    protection: Protection,
+    allocation_base: usize,
+}
+
+const FREE_PAGE: Page = Page {
+    state: RegionState::Free,
+    protection: Protection::NoAccess,
+    allocation_base: 0,
+};
+
+struct State {
+    pages: Vec<Page>,
+    dynamic_code_blocked: bool,
+}
+
+/// fake address space backed by one host allocation
+///
+/// addresses are real so code can be copied and decoded in place, while page state and
+/// protection are only bookkept and never enforced. Memory outside the arena reads as
+/// committed by somebody else.
+pub struct SimulatedMemory {
+    base: usize,
+    size: usize,
+    state: Mutex<State>,
+}
+
+impl SimulatedMemory {
+    pub fn new(size: usize) -> SimulatedMemory {
+        let size = size
+            .max(ALLOCATION_GRANULARITY)
+            .next_multiple_of(ALLOCATION_GRANULARITY);
+        let layout = Layout::from_size_align(size, ALLOCATION_GRANULARITY).unwrap();
+        let base = unsafe { std::alloc::alloc_zeroed(layout) };
+        if base.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        SimulatedMemory {
+            base: base as usize,
+            size,
+            state: Mutex::new(State {
+                pages: vec![FREE_PAGE; size / PAGE_SIZE],
+                dynamic_code_blocked: false,
+            }),
+        }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.base..self.base + self.size
+    }
+
+    fn pages(&self, addr: usize, size: usize) -> Option<Range<usize>> {
+        let end = addr.checked_add(size.max(1))?;
+        if addr < self.base || end > self.base + self.size {
+            return None;
+        }
+        Some((addr - self.base) / PAGE_SIZE..(end - self.base).div_ceil(PAGE_SIZE))
+    }
+
+    fn update(
+        &self,
+        addr: usize,
+        size: usize,
+        check: impl Fn(&Page) -> bool,
+        page: impl Fn(&Page) -> Page,
+    ) -> Option<usize> {
+        let pages = self.pages(addr, size)?;
+        let mut state = self.state.lock().unwrap();
+        if !state.pages[pages.clone()].iter().all(check) {
+            return None;
+        }
+        state.pages[pages].iter_mut().for_each(|x| *x = page(x));
+        Some(addr)
+    }
+
+    /// commit pages as a loader would, e.g. to place code for a target
+    pub fn map(&self, addr: usize, size: usize, protection: Protection) -> Result<(), Error> {
+        let allocation_base = addr - addr.wrapping_sub(self.base) % ALLOCATION_GRANULARITY;
+        self.update(
+            addr,
+            size,
+            |x| x.state == RegionState::Free,
+            |_| Page {
+                state: RegionState::Commit,
+                protection,
+                allocation_base,
+            },
+        )
+        .map(|_| ())
+        .ok_or(Error::InvalidAddress)
+    }
+
+    /// reserve pages without committing, for arena style allocation
+    pub fn reserve(&self, addr: usize, size: usize) -> Result<(), Error> {
+        if !addr
+            .wrapping_sub(self.base)
+            .is_multiple_of(ALLOCATION_GRANULARITY)
+        {
+            return Err(Error::InvalidAddress);
+        }
+        self.update(
+            addr,
+            size,
+            |x| x.state == RegionState::Free,
+            |_| Page {
+                state: RegionState::Reserve,
+                protection: Protection::NoAccess,
+                allocation_base: addr,
+            },
+        )
+        .map(|_| ())
+        .ok_or(Error::InvalidAddress)
+    }
+
+    /// copy bytes in regardless of protection
+    pub fn write(&self, addr: usize, data: &[u8]) -> Result<(), Error> {
+        self.pages(addr, data.len()).ok_or(Error::InvalidAddress)?;
+        unsafe { std::ptr::copy(data.as_ptr(), addr as *mut u8, data.len()) };
+        Ok(())
+    }
+
+    pub fn read(&self, addr: usize, size: usize) -> Result<Vec<u8>, Error> {
+        self.pages(addr, size).ok_or(Error::InvalidAddress)?;
+        Ok(unsafe { std::slice::from_raw_parts(addr as *const u8, size) }.to_vec())
+    }
+
+    pub fn protection(&self, addr: usize) -> Option<Protection> {
+        let pages = self.pages(addr, 1)?;
+        let page = self.state.lock().unwrap().pages[pages.start];
+        (page.state == RegionState::Commit).then_some(page.protection)
+    }
+
+    /// committed ranges which are writable and executable at the same time
+    pub fn writable_executable(&self) -> Vec<Range<usize>> {
+        let state = self.state.lock().unwrap();
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for (index, page) in state.pages.iter().enumerate() {
+            if page.state != RegionState::Commit || page.protection != Protection::ReadWriteExecute
+            {
+                continue;
+            }
+            let addr = self.base + index * PAGE_SIZE;
+            match ranges.last_mut() {
+                Some(last) if last.end == addr => last.end += PAGE_SIZE,
+                _ => ranges.push(addr..addr + PAGE_SIZE),
+            }
+        }
+        ranges
+    }
+
+    /// make every later allocation fail like an arbitrary code guard policy would
+    pub fn set_dynamic_code_blocked(&self, blocked: bool) {
+        self.state.lock().unwrap().dynamic_code_blocked = blocked;
+    }
+}
+
+impl Drop for SimulatedMemory {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.size, ALLOCATION_GRANULARITY).unwrap();
+        unsafe { std::alloc::dealloc(self.base as *mut u8, layout) };
+    }
+}
+
+impl MemoryBackend for SimulatedMemory {
+    fn query(&self, addr: usize) -> Option<RegionInfo> {
+        let end = self.base + self.size;
+        if addr < self.base {
+            return Some(RegionInfo {
+                base: 0,
+                allocation_base: 0,
+                size: self.base,
+                state: RegionState::Commit,
+                protection: Protection::NoAccess,
+            });
+        }
+        if addr >= end {
+            return Some(RegionInfo {
+                base: end,
+                allocation_base: end,
+                size: usize::MAX - end,
+                state: RegionState::Commit,
+                protection: Protection::NoAccess,
+            });
+        }
+
+        let state = self.state.lock().unwrap();
+        let index = (addr - self.base) / PAGE_SIZE;
+        let page = state.pages[index];
+        let count = state.pages[index..]
+            .iter()
+            .take_while(|x| **x == page)
+            .count();
+        Some(RegionInfo {
+            base: self.base + index * PAGE_SIZE,
+            allocation_base: page.allocation_base,
+            size: count * PAGE_SIZE,
+            state: page.state,
+            protection: page.protection,
+        })
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.pages(addr, buf.len()).ok_or(Error::InvalidAddress)?;
+        unsafe { std::ptr::copy(addr as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn write(&self, addr: usize, data: &[u8]) -> Result<(), Error> {
+        SimulatedMemory::write(self, addr, data)
+    }
+
+    fn protect(
+        &self,
+        addr: usize,
+        size: usize,
+        protection: Protection,
+    ) -> Result<Protection, Error> {
+        let pages = self.pages(addr, size).ok_or(Error::InvalidAddress)?;
+        let mut state = self.state.lock().unwrap();
+        let pages = &mut state.pages[pages];
+        if pages.iter().any(|x| x.state != RegionState::Commit) {
+            return Err(Error::InvalidAddress);
+        }
+        let old = pages[0].protection;
+        pages.iter_mut().for_each(|x| x.protection = protection);
+        Ok(old)
+    }
+
+    fn alloc(&self, addr: usize, size: usize) -> Option<usize> {
+        if self.is_dynamic_code_blocked()
+            || !addr
+                .wrapping_sub(self.base)
+                .is_multiple_of(ALLOCATION_GRANULARITY)
+        {
+            return None;
+        }
+        self.update(
+            addr,
+            size,
+            |x| x.state == RegionState::Free,
+            |_| Page {
+                state: RegionState::Commit,
+                protection: Protection::ReadWriteExecute,
+                allocation_base: addr,
+            },
+        )
+    }
+
+    fn commit(&self, addr: usize, size: usize) -> Option<usize> {
+        if self.is_dynamic_code_blocked() {
+            return None;
+        }
+        self.update(
+            addr,
+            size,
+            |x| x.state != RegionState::Free,
+            |x| Page {
+                state: RegionState::Commit,
+                protection: Protection::ReadWriteExecute,
+                allocation_base: x.allocation_base,
+            },
+        )
+    }
+
+    fn free(&self, addr: usize) -> Result<(), Error> {
+        self.pages(addr, 1).ok_or(Error::InvalidAddress)?;
+        let mut state = self.state.lock().unwrap();
+        let mut released = false;
+        for page in state
+            .pages
+            .iter_mut()
+            .filter(|x| x.state != RegionState::Free && x.allocation_base == addr)
+        {
+            *page = FREE_PAGE;
+            released = true;
+        }
+        if released {
+            Ok(())
+        } else {
+            Err(Error::InvalidAddress)
+        }
+    }
+
+    fn decommit(&self, addr: usize, size: usize) -> Result<(), Error> {
+        self.update(
+            addr,
+            size,
+            |x| x.state != RegionState::Free,
+            |x| Page {
+                state: RegionState::Reserve,
+                protection: Protection::NoAccess,
+                allocation_base: x.allocation_base,
+            },
+        )
+        .map(|_| ())
+        .ok_or(Error::InvalidAddress)
+    }
+
+    fn is_dynamic_code_blocked(&self) -> bool {
+        self.state.lock().unwrap().dynamic_code_blocked
+    }
+}
@@ -0,0 +1,26 @@
+//! detours-inject <pid> <library>
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (Some(pid), Some(library)) = (args.get(1).and_then(|x| x.parse().ok()), args.get(2)) else {
+        eprintln!("usage: detours-inject <pid> <library>");
+        std::process::exit(2);
+    };
+    match detours_rs::inject::inject(pid, std::path::Path::new(library)) {
+        Ok(injection) => println!(
+            "loaded {library} into {pid}, handle {:#x}",
+            injection.handle
+        ),
+        Err(err) => {
+            eprintln!("inject {library} into {pid}: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+fn main() {
+    eprintln!("detours-inject is only supported on x86_64 Linux");
+    std::process::exit(1);
+}
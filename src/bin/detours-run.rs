@@ -0,0 +1,47 @@
+//! detours-run -l <library> [-l <library>...] -- <program> [args...]
+
+#[cfg(target_os = "linux")]
+fn main() {
+    let usage = || -> ! {
+        eprintln!("usage: detours-run -l <library> [-l <library>...] -- <program> [args...]");
+        std::process::exit(2);
+    };
+
+    let mut args = std::env::args_os().skip(1);
+    let mut libraries = vec![];
+    loop {
+        match args.next() {
+            Some(arg) if arg == "-l" => libraries.push(args.next().unwrap_or_else(|| usage())),
+            Some(arg) if arg == "--" => break,
+            _ => usage(),
+        }
+    }
+    let Some(program) = args.next() else { usage() };
+    if libraries.is_empty() {
+        usage();
+    }
+
+    let mut launcher = detours_rs::launch::Launcher::new(&program);
+    launcher.args(args);
+    for library in &libraries {
+        launcher.library(library);
+    }
+    let status = launcher.spawn().and_then(|mut child| {
+        child
+            .wait()
+            .map_err(|err| detours_rs::Error::ErrorCode(err.raw_os_error().unwrap_or(0) as usize))
+    });
+    match status {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(err) => {
+            eprintln!("detours-run {}: {err}", program.to_string_lossy());
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("detours-run is only supported on Linux");
+    std::process::exit(1);
+}
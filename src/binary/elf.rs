@@ -0,0 +1,1141 @@
+use crate::alloc::RegionInfo;
+use crate::backend::{MemoryBackend, Protection};
+use crate::binary::{read_c_str, read_uint, write_uint};
+use crate::{Error, copy, mem};
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Code, Decoder, DecoderOptions, Instruction, InstructionBlock,
+};
+use std::path::Path;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_386: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNAMIC: u32 = 6;
+const SHT_DYNSYM: u32 = 11;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_STRSZ: u64 = 10;
+const DT_VERNEED: u64 = 0x6ffffffe;
+const DT_VERNEEDNUM: u64 = 0x6fffffff;
+
+const PAGE_SIZE: u64 = 0x1000;
+const JMP_REL32_SIZE: usize = 5;
+const PREFETCH_SIZE: usize = 0x20;
+const ENDBR64: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+const ENDBR32: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfb];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ElfSegment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ElfSegment {
+    fn contains(&self, vaddr: u64, size: u64) -> bool {
+        vaddr >= self.vaddr
+            && vaddr
+                .checked_add(size)
+                .is_some_and(|end| end <= self.vaddr + self.filesz)
+    }
+}
+
+struct Section {
+    /// file offset of the header itself
+    header: usize,
+    kind: u32,
+    offset: u64,
+    size: u64,
+    link: u32,
+    entsize: u64,
+}
+
+/// ELF file held in memory, little endian x86 and x86_64 layouts
+pub struct ElfFile {
+    data: Vec<u8>,
+    is_64: bool,
+    machine: u16,
+    segments: Vec<ElfSegment>,
+}
+
+impl ElfFile {
+    pub fn parse(data: Vec<u8>) -> Result<ElfFile, Error> {
+        let mut elf = ElfFile::header(data)?;
+        elf.load_segments()?;
+        Ok(elf)
+    }
+
+    /// headers of an image mapped at `base` in the current process
+    ///
+    /// only the ELF header and the program header table are copied, so
+    /// reads of file contents past them fail
+    pub fn from_base(base: usize) -> Result<ElfFile, Error> {
+        let copy = |size| mem::checked_copy(base, size).ok_or(Error::InvalidAddress);
+        // a 64 bit header is the larger one, it tells how far the table reaches
+        let mut elf = ElfFile::header(copy(0x40)?)?;
+        let (phoff, phentsize, phnum) = elf.phdr_table()?;
+        let end = phoff
+            .checked_add(phentsize * phnum)
+            .ok_or(Error::InvalidImage)?;
+        elf.data = copy(end.max(0x40))?;
+        elf.load_segments()?;
+        Ok(elf)
+    }
+
+    /// identification and machine, segments are left empty
+    fn header(data: Vec<u8>) -> Result<ElfFile, Error> {
+        if data.get(..4) != Some(&ELF_MAGIC[..]) || data.get(5) != Some(&ELFDATA2LSB) {
+            return Err(Error::InvalidImage);
+        }
+        let is_64 = match data[4] {
+            ELFCLASS32 => false,
+            ELFCLASS64 => true,
+            _ => return Err(Error::InvalidImage),
+        };
+        let mut elf = ElfFile {
+            data,
+            is_64,
+            machine: 0,
+            segments: vec![],
+        };
+        elf.machine = elf.uint(18, 2)? as u16;
+        Ok(elf)
+    }
+
+    fn load_segments(&mut self) -> Result<(), Error> {
+        let (phoff, phentsize, phnum) = self.phdr_table()?;
+        self.segments = (0..phnum)
+            .map(|i| self.segment(phoff + i * phentsize))
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<ElfFile, Error> {
+        let data = std::fs::read(path)
+            .map_err(|err| Error::ErrorCode(err.raw_os_error().unwrap_or(0) as usize))?;
+        ElfFile::parse(data)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn is_64(&self) -> bool {
+        self.is_64
+    }
+
+    pub fn machine(&self) -> u16 {
+        self.machine
+    }
+
+    pub fn segments(&self) -> &[ElfSegment] {
+        &self.segments
+    }
+
+    fn uint(&self, offset: usize, size: usize) -> Result<u64, Error> {
+        read_uint(&self.data, offset, size).ok_or(Error::InvalidImage)
+    }
+
+    fn set_uint(&mut self, offset: usize, size: usize, value: u64) -> Result<(), Error> {
+        write_uint(&mut self.data, offset, size, value).ok_or(Error::InvalidImage)
+    }
+
+    /// offset, entry size and count of the program header table
+    fn phdr_table(&self) -> Result<(usize, usize, usize), Error> {
+        let (phoff, phentsize, phnum) = if self.is_64 {
+            (
+                self.uint(0x20, 8)?,
+                self.uint(0x36, 2)?,
+                self.uint(0x38, 2)?,
+            )
+        } else {
+            (
+                self.uint(0x1c, 4)?,
+                self.uint(0x2a, 2)?,
+                self.uint(0x2c, 2)?,
+            )
+        };
+        Ok((phoff as usize, phentsize as usize, phnum as usize))
+    }
+
+    fn segment(&self, at: usize) -> Result<ElfSegment, Error> {
+        Ok(if self.is_64 {
+            ElfSegment {
+                kind: self.uint(at, 4)? as u32,
+                flags: self.uint(at + 4, 4)? as u32,
+                offset: self.uint(at + 8, 8)?,
+                vaddr: self.uint(at + 0x10, 8)?,
+                paddr: self.uint(at + 0x18, 8)?,
+                filesz: self.uint(at + 0x20, 8)?,
+                memsz: self.uint(at + 0x28, 8)?,
+                align: self.uint(at + 0x30, 8)?,
+            }
+        } else {
+            ElfSegment {
+                kind: self.uint(at, 4)? as u32,
+                offset: self.uint(at + 4, 4)?,
+                vaddr: self.uint(at + 8, 4)?,
+                paddr: self.uint(at + 0xc, 4)?,
+                filesz: self.uint(at + 0x10, 4)?,
+                memsz: self.uint(at + 0x14, 4)?,
+                flags: self.uint(at + 0x18, 4)? as u32,
+                align: self.uint(at + 0x1c, 4)?,
+            }
+        })
+    }
+
+    fn segment_bytes(&self, segment: &ElfSegment) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.phentsize()];
+        let fields: [(usize, usize, u64); 8] = if self.is_64 {
+            [
+                (0, 4, segment.kind as u64),
+                (4, 4, segment.flags as u64),
+                (8, 8, segment.offset),
+                (0x10, 8, segment.vaddr),
+                (0x18, 8, segment.paddr),
+                (0x20, 8, segment.filesz),
+                (0x28, 8, segment.memsz),
+                (0x30, 8, segment.align),
+            ]
+        } else {
+            [
+                (0, 4, segment.kind as u64),
+                (4, 4, segment.offset),
+                (8, 4, segment.vaddr),
+                (0xc, 4, segment.paddr),
+                (0x10, 4, segment.filesz),
+                (0x14, 4, segment.memsz),
+                (0x18, 4, segment.flags as u64),
+                (0x1c, 4, segment.align),
+            ]
+        };
+        for (at, size, value) in fields {
+            write_uint(&mut bytes, at, size, value);
+        }
+        bytes
+    }
+
+    fn phentsize(&self) -> usize {
+        if self.is_64 { 0x38 } else { 0x20 }
+    }
+
+    /// file offset of `size` bytes at `vaddr`, they must be backed by the file
+    pub fn offset_of(&self, vaddr: u64, size: u64) -> Option<usize> {
+        self.segments
+            .iter()
+            .find(|x| x.kind == PT_LOAD && x.contains(vaddr, size))
+            .map(|x| (x.offset + (vaddr - x.vaddr)) as usize)
+    }
+
+    pub fn read_at(&self, vaddr: u64, size: usize) -> Option<&[u8]> {
+        let offset = self.offset_of(vaddr, size as u64)?;
+        self.data.get(offset..offset + size)
+    }
+
+    pub fn write_at(&mut self, vaddr: u64, bytes: &[u8]) -> Result<(), Error> {
+        let offset = self
+            .offset_of(vaddr, bytes.len() as u64)
+            .ok_or(Error::InvalidAddress)?;
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn sections(&self) -> Result<Vec<Section>, Error> {
+        let (shoff, shentsize, shnum) = if self.is_64 {
+            (
+                self.uint(0x28, 8)?,
+                self.uint(0x3a, 2)?,
+                self.uint(0x3c, 2)?,
+            )
+        } else {
+            (
+                self.uint(0x20, 4)?,
+                self.uint(0x2e, 2)?,
+                self.uint(0x30, 2)?,
+            )
+        };
+        (0..shnum as usize)
+            .map(|i| {
+                let at = shoff as usize + i * shentsize as usize;
+                Ok(if self.is_64 {
+                    Section {
+                        header: at,
+                        kind: self.uint(at + 4, 4)? as u32,
+                        offset: self.uint(at + 0x18, 8)?,
+                        size: self.uint(at + 0x20, 8)?,
+                        link: self.uint(at + 0x28, 4)? as u32,
+                        entsize: self.uint(at + 0x38, 8)?,
+                    }
+                } else {
+                    Section {
+                        header: at,
+                        kind: self.uint(at + 4, 4)? as u32,
+                        offset: self.uint(at + 0x10, 4)?,
+                        size: self.uint(at + 0x14, 4)?,
+                        link: self.uint(at + 0x18, 4)? as u32,
+                        entsize: self.uint(at + 0x24, 4)?,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// value of a defined symbol from `.symtab` or `.dynsym`
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        let sections = self.sections().ok()?;
+        sections
+            .iter()
+            .filter(|x| (x.kind == SHT_SYMTAB || x.kind == SHT_DYNSYM) && x.entsize != 0)
+            .find_map(|table| {
+                let strtab = sections.get(table.link as usize)?;
+                (0..table.size / table.entsize).find_map(|i| {
+                    let at = (table.offset + i * table.entsize) as usize;
+                    let (name_at, value, shndx) = if self.is_64 {
+                        (
+                            read_uint(&self.data, at, 4)?,
+                            read_uint(&self.data, at + 8, 8)?,
+                            read_uint(&self.data, at + 6, 2)?,
+                        )
+                    } else {
+                        (
+                            read_uint(&self.data, at, 4)?,
+                            read_uint(&self.data, at + 4, 4)?,
+                            read_uint(&self.data, at + 0xe, 2)?,
+                        )
+                    };
+                    let found = read_c_str(&self.data, (strtab.offset + name_at) as usize)?;
+                    (shndx != 0 && found == name.as_bytes()).then_some(value)
+                })
+            })
+    }
+}
+
+/// file contents at their linked addresses, lets the relocating copier read code such as
+/// PC thunks the prologue calls
+struct FileImage<'a>(&'a ElfFile);
+
+impl MemoryBackend for FileImage<'_> {
+    fn query(&self, _addr: usize) -> Option<RegionInfo> {
+        None
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let bytes = self
+            .0
+            .read_at(addr as u64, buf.len())
+            .ok_or(Error::InvalidAddress)?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write(&self, _addr: usize, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::InvalidAddress)
+    }
+
+    fn protect(&self, _addr: usize, _size: usize, _: Protection) -> Result<Protection, Error> {
+        Err(Error::InvalidAddress)
+    }
+
+    fn alloc(&self, _addr: usize, _size: usize) -> Option<usize> {
+        None
+    }
+
+    fn commit(&self, _addr: usize, _size: usize) -> Option<usize> {
+        None
+    }
+
+    fn free(&self, _addr: usize) -> Result<(), Error> {
+        Err(Error::InvalidAddress)
+    }
+
+    fn decommit(&self, _addr: usize, _size: usize) -> Result<(), Error> {
+        Err(Error::InvalidAddress)
+    }
+}
+
+/// offset of `name` in a string table, appended when missing
+fn intern(strings: &mut Vec<u8>, name: &str) -> u64 {
+    let mut wanted = name.as_bytes().to_vec();
+    wanted.push(0);
+    if let Some(at) = strings.windows(wanted.len()).position(|x| x == wanted) {
+        return at as u64;
+    }
+    strings.extend(wanted);
+    (strings.len() - name.len() - 1) as u64
+}
+
+/// SysV hash stored with version requirements
+fn elf_hash(name: &[u8]) -> u32 {
+    let mut hash = 0u32;
+    for c in name {
+        hash = (hash << 4).wrapping_add(*c as u32);
+        let high = hash & 0xf000_0000;
+        if high != 0 {
+            hash ^= high >> 24;
+        }
+        hash &= !high;
+    }
+    hash
+}
+
+impl ElfFile {
+    /// tag and value pairs of the dynamic section up to `DT_NULL`
+    fn dynamic(&self) -> Result<Vec<(u64, u64)>, Error> {
+        let segment = self
+            .segments
+            .iter()
+            .find(|x| x.kind == PT_DYNAMIC)
+            .ok_or(Error::InvalidImage)?;
+        let word = if self.is_64 { 8 } else { 4 };
+        let mut entries = vec![];
+        for at in (0..segment.filesz as usize).step_by(word * 2) {
+            let at = segment.offset as usize + at;
+            let tag = self.uint(at, word)?;
+            if tag == DT_NULL {
+                break;
+            }
+            entries.push((tag, self.uint(at + word, word)?));
+        }
+        Ok(entries)
+    }
+
+    /// version requirements as (`vn_file` offset, [(`vna_name` offset, file offset of aux)])
+    fn version_needs(&self, dynamic: &[(u64, u64)]) -> Result<Vec<(usize, Vec<usize>)>, Error> {
+        let value = |tag| dynamic.iter().find(|x| x.0 == tag).map(|x| x.1);
+        let (Some(verneed), Some(count)) = (value(DT_VERNEED), value(DT_VERNEEDNUM)) else {
+            return Ok(vec![]);
+        };
+        let mut at = self.offset_of(verneed, 16).ok_or(Error::InvalidImage)?;
+        let mut needs = vec![];
+        for _ in 0..count {
+            let mut aux_at = at + self.uint(at + 8, 4)? as usize;
+            let mut auxes = vec![];
+            for _ in 0..self.uint(at + 2, 2)? {
+                auxes.push(aux_at);
+                aux_at += self.uint(aux_at + 12, 4)? as usize;
+            }
+            needs.push((at, auxes));
+            match self.uint(at + 12, 4)? {
+                0 => break,
+                next => at += next as usize,
+            }
+        }
+        Ok(needs)
+    }
+
+    /// apply edits, returns the new dynamic section and string table laid out at `vaddr`
+    fn rebuild_dynamic(
+        &mut self,
+        edits: &[DynamicEdit],
+        vaddr: u64,
+    ) -> Result<(Vec<u8>, u64), Error> {
+        let mut dynamic = self.dynamic()?;
+        let value = |tag| dynamic.iter().find(|x| x.0 == tag).map(|x| x.1);
+        let (Some(strtab), Some(strsz)) = (value(DT_STRTAB), value(DT_STRSZ)) else {
+            return Err(Error::InvalidImage);
+        };
+        let mut strings = self
+            .read_at(strtab, strsz as usize)
+            .ok_or(Error::InvalidImage)?
+            .to_vec();
+        let name_at = |strings: &[u8], offset: u64| -> Vec<u8> {
+            read_c_str(strings, offset as usize).unwrap_or(&[]).to_vec()
+        };
+        let needs = self.version_needs(&dynamic)?;
+        // version requirement words to change, written once every edit applied
+        let mut writes: Vec<(usize, u64)> = vec![];
+        let word_at = |writes: &[(usize, u64)], at: usize| match writes.iter().rfind(|x| x.0 == at)
+        {
+            Some((_, value)) => Ok(*value),
+            None => self.uint(at, 4),
+        };
+
+        for edit in edits {
+            match edit {
+                DynamicEdit::Add(library) => {
+                    let name = intern(&mut strings, library);
+                    if dynamic.iter().any(|x| x.0 == DT_NEEDED && x.1 == name) {
+                        continue;
+                    }
+                    let at = dynamic
+                        .iter()
+                        .rposition(|x| x.0 == DT_NEEDED)
+                        .map_or(0, |x| x + 1);
+                    dynamic.insert(at, (DT_NEEDED, name));
+                }
+                DynamicEdit::Replace(from, to) => {
+                    let needed = |strings: &[u8], x: &(u64, u64)| {
+                        x.0 == DT_NEEDED && name_at(strings, x.1) == from.as_bytes()
+                    };
+                    if !dynamic.iter().any(|x| needed(&strings, x)) {
+                        return Err(Error::ModuleNotFound);
+                    }
+                    let to = intern(&mut strings, to);
+                    for entry in dynamic.iter_mut() {
+                        if needed(&strings, entry) {
+                            entry.1 = to;
+                        }
+                    }
+                    for (need, _) in &needs {
+                        if name_at(&strings, word_at(&writes, need + 4)?) == from.as_bytes() {
+                            writes.push((need + 4, to));
+                        }
+                    }
+                }
+                DynamicEdit::Version { library, from, to } => {
+                    let aux = needs
+                        .iter()
+                        .filter(|(need, _)| {
+                            word_at(&writes, need + 4)
+                                .is_ok_and(|x| name_at(&strings, x) == library.as_bytes())
+                        })
+                        .flat_map(|(_, auxes)| auxes)
+                        .find(|aux| {
+                            word_at(&writes, *aux + 8)
+                                .is_ok_and(|x| name_at(&strings, x) == from.as_bytes())
+                        })
+                        .copied()
+                        .ok_or(Error::ModuleNotFound)?;
+                    let to_name = intern(&mut strings, to);
+                    writes.push((aux, elf_hash(to.as_bytes()) as u64));
+                    writes.push((aux + 8, to_name));
+                }
+            }
+        }
+        for (at, value) in writes {
+            self.set_uint(at, 4, value)?;
+        }
+
+        let word = if self.is_64 { 8 } else { 4 };
+        let dynamic_size = ((dynamic.len() + 1) * word * 2) as u64;
+        for entry in dynamic.iter_mut() {
+            match entry.0 {
+                DT_STRTAB => entry.1 = vaddr + dynamic_size,
+                DT_STRSZ => entry.1 = strings.len() as u64,
+                _ => {}
+            }
+        }
+        let mut data = vec![0u8; dynamic_size as usize];
+        for (i, (tag, value)) in dynamic.iter().enumerate() {
+            write_uint(&mut data, i * word * 2, word, *tag);
+            write_uint(&mut data, i * word * 2 + word, word, *value);
+        }
+        data.extend(strings);
+        Ok((data, dynamic_size))
+    }
+}
+
+enum DynamicEdit {
+    Add(String),
+    Replace(String, String),
+    Version {
+        library: String,
+        from: String,
+        to: String,
+    },
+}
+
+/// detour baked into a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StaticHook {
+    pub target: u64,
+    pub detour: u64,
+    /// relocated prologue followed by a jump back, call it to reach the original
+    pub trampoline: u64,
+    /// size of the `endbr` kept at `target`, the jump is written after it
+    pub pad: usize,
+    pub fetch: usize,
+}
+
+impl StaticHook {
+    /// bytes of the target the hook took over
+    fn range(&self) -> std::ops::Range<u64> {
+        self.target..self.target + (self.pad + self.fetch) as u64
+    }
+}
+
+/// patch function entries and imports of an ELF file offline
+///
+/// trampolines and detour code go into a new executable segment at the end of the file,
+/// together with a copy of the program header table which grew by that segment. The
+/// table copy is placed so the kernel finds it for `AT_PHDR` the same way as before.
+/// Import edits rebuild the dynamic section and string table in a second, writable
+/// segment behind it.
+pub struct ElfPatcher {
+    elf: ElfFile,
+    offset: u64,
+    vaddr: u64,
+    code: Vec<u8>,
+    hooks: Vec<StaticHook>,
+    edits: Vec<DynamicEdit>,
+}
+
+impl ElfPatcher {
+    pub fn new(elf: ElfFile) -> Result<ElfPatcher, Error> {
+        let loads = || elf.segments.iter().filter(|x| x.kind == PT_LOAD);
+        let first = loads().min_by_key(|x| x.vaddr).ok_or(Error::InvalidImage)?;
+        let end = loads()
+            .map(|x| x.vaddr + x.memsz)
+            .max()
+            .ok_or(Error::InvalidImage)?;
+        // keep vaddr - offset equal to the first segment, loaders deriving the header
+        // table address from `e_phoff` land on the copy
+        let delta = first.vaddr.wrapping_sub(first.offset);
+        let offset = (elf.data.len() as u64)
+            .max(end.wrapping_sub(delta))
+            .next_multiple_of(PAGE_SIZE);
+        Ok(ElfPatcher {
+            vaddr: offset.wrapping_add(delta),
+            offset,
+            elf,
+            code: vec![],
+            hooks: vec![],
+            edits: vec![],
+        })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<ElfPatcher, Error> {
+        ElfPatcher::new(ElfFile::open(path)?)
+    }
+
+    pub fn elf(&self) -> &ElfFile {
+        &self.elf
+    }
+
+    pub fn hooks(&self) -> &[StaticHook] {
+        &self.hooks
+    }
+
+    /// header table with room for the code and the dynamic segment
+    fn table_size(&self) -> u64 {
+        ((self.elf.segments.len() + 2) * self.elf.phentsize()) as u64
+    }
+
+    /// first address of the new segment available for code
+    pub fn code_base(&self) -> u64 {
+        self.vaddr + self.table_size().next_multiple_of(0x10)
+    }
+
+    /// append code to the new segment, returns its address
+    pub fn add_code(&mut self, bytes: &[u8]) -> u64 {
+        self.code
+            .resize(self.code.len().next_multiple_of(0x10), 0xcc);
+        let addr = self.code_base() + self.code.len() as u64;
+        self.code.extend_from_slice(bytes);
+        addr
+    }
+
+    /// overwrite bytes in the file or in code added before
+    pub fn write(&mut self, vaddr: u64, bytes: &[u8]) -> Result<(), Error> {
+        let base = self.code_base();
+        if vaddr >= base && vaddr + bytes.len() as u64 <= base + self.code.len() as u64 {
+            let at = (vaddr - base) as usize;
+            self.code[at..at + bytes.len()].copy_from_slice(bytes);
+            return Ok(());
+        }
+        self.elf.write_at(vaddr, bytes)
+    }
+
+    /// load `library` with the program, after the libraries it already needs
+    pub fn add_needed(&mut self, library: &str) -> &mut ElfPatcher {
+        self.edits.push(DynamicEdit::Add(library.to_string()));
+        self
+    }
+
+    /// load `to` instead of `from`, version requirements on `from` move along
+    pub fn replace_needed(&mut self, from: &str, to: &str) -> &mut ElfPatcher {
+        self.edits
+            .push(DynamicEdit::Replace(from.to_string(), to.to_string()));
+        self
+    }
+
+    /// bind symbols of `library` requiring version `from` to version `to`, e.g. to accept
+    /// an older `GLIBC_2.x`
+    pub fn redirect_version(&mut self, library: &str, from: &str, to: &str) -> &mut ElfPatcher {
+        self.edits.push(DynamicEdit::Version {
+            library: library.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        self
+    }
+
+    pub fn hook_symbol(&mut self, name: &str, detour: u64) -> Result<StaticHook, Error> {
+        let target = self.elf.symbol(name).ok_or(Error::SignatureNotFound)?;
+        self.hook(target, detour)
+    }
+
+    /// jump from `target` to `detour`, both addresses as linked
+    ///
+    /// the prologue is moved like [`copy::relocate`] does at runtime, a leading `endbr`
+    /// stays in place
+    pub fn hook(&mut self, target: u64, detour: u64) -> Result<StaticHook, Error> {
+        let (bitness, endbr) = match self.elf.machine {
+            EM_386 => (32, ENDBR32),
+            EM_X86_64 => (64, ENDBR64),
+            _ => return Err(Error::InvalidImage),
+        };
+        let overlaps = |hooks: &[StaticHook], start: u64, end: u64| {
+            hooks
+                .iter()
+                .any(|x| start < x.range().end && x.target < end)
+        };
+        if overlaps(&self.hooks, target, target + 1) {
+            return Err(Error::InvalidAddress);
+        }
+
+        let available = (1..=PREFETCH_SIZE)
+            .rev()
+            .find(|x| self.elf.offset_of(target, *x as u64).is_some())
+            .ok_or(Error::InvalidAddress)?;
+        let code = self
+            .elf
+            .read_at(target, available)
+            .ok_or(Error::InvalidAddress)?
+            .to_vec();
+        let first = Decoder::with_ip(bitness, &code, target, DecoderOptions::NONE).decode();
+        let pad = match first.code() {
+            Code::Endbr64 | Code::Endbr32 => first.len(),
+            _ => 0,
+        };
+        let patched = target + pad as u64;
+
+        // trampolines start with a landing pad, they are reached through pointers
+        let trampoline_at = self.add_code(&[]);
+        let copied = copy::relocate_with(
+            &FileImage(&self.elf),
+            bitness,
+            &code[pad..],
+            patched as usize,
+            (trampoline_at as usize) + endbr.len(),
+            JMP_REL32_SIZE,
+        )?;
+        // endbr marks a CET build, which may run with shadow stacks
+        if copied.uses_ret && pad != 0 {
+            return Err(Error::ShadowStackIncompatible);
+        }
+        let fetch = copied.len;
+        let end = patched + fetch as u64;
+        let executable =
+            self.elf.segments.iter().any(|x| {
+                x.kind == PT_LOAD && x.flags & PF_X != 0 && x.contains(target, end - target)
+            });
+        if !executable || overlaps(&self.hooks, target, end) {
+            return Err(Error::InvalidAddress);
+        }
+
+        let mut trampoline = endbr.to_vec();
+        trampoline.extend(&copied.code);
+        let jmp = if bitness == 64 {
+            Code::Jmp_rel32_64
+        } else {
+            Code::Jmp_rel32_32
+        };
+        let back = [Instruction::with_branch(jmp, end).map_err(|_| Error::InvalidAddress)?];
+        let back_at = trampoline_at + trampoline.len() as u64;
+        trampoline.extend(
+            BlockEncoder::encode(
+                bitness,
+                InstructionBlock::new(&back, back_at),
+                BlockEncoderOptions::NONE,
+            )
+            .map_err(|_| Error::InvalidAddress)?
+            .code_buffer,
+        );
+        let trampoline = self.add_code(&trampoline);
+
+        let src = patched + JMP_REL32_SIZE as u64;
+        let reachable = bitness == 32 || i32::try_from(detour.wrapping_sub(src) as i64).is_ok();
+        let destination = if reachable {
+            detour
+        } else {
+            // jmp [rip+0]
+            let mut stub = vec![0xff, 0x25, 0, 0, 0, 0];
+            stub.extend(detour.to_le_bytes());
+            self.add_code(&stub)
+        };
+
+        let mut patch = vec![0xcc; fetch];
+        patch[0] = 0xe9;
+        patch[1..JMP_REL32_SIZE]
+            .copy_from_slice(&(destination.wrapping_sub(src) as u32).to_le_bytes());
+        self.elf.write_at(patched, &patch)?;
+
+        let hook = StaticHook {
+            target,
+            detour,
+            trampoline,
+            pad,
+            fetch,
+        };
+        self.hooks.push(hook);
+        Ok(hook)
+    }
+
+    /// file with the new segments appended
+    pub fn build(self) -> Result<Vec<u8>, Error> {
+        let table_size = self.table_size();
+        let code_offset = table_size.next_multiple_of(0x10);
+        let ElfPatcher {
+            mut elf,
+            offset,
+            vaddr,
+            code,
+            edits,
+            ..
+        } = self;
+        let code_size = code_offset + code.len() as u64;
+        let load = ElfSegment {
+            kind: PT_LOAD,
+            flags: PF_R | PF_X,
+            offset,
+            vaddr,
+            paddr: vaddr,
+            filesz: code_size,
+            memsz: code_size,
+            align: PAGE_SIZE,
+        };
+
+        let mut segments = elf.segments.clone();
+        for segment in segments.iter_mut().filter(|x| x.kind == PT_PHDR) {
+            segment.offset = offset;
+            segment.vaddr = vaddr;
+            segment.paddr = vaddr;
+            segment.filesz = table_size;
+            segment.memsz = table_size;
+        }
+        let last_load = segments
+            .iter()
+            .rposition(|x| x.kind == PT_LOAD)
+            .ok_or(Error::InvalidImage)?;
+        segments.insert(last_load + 1, load);
+
+        let data_offset = (offset + code_size).next_multiple_of(PAGE_SIZE);
+        let data_vaddr = vaddr + (data_offset - offset);
+        let dynamic = match edits.is_empty() {
+            true => None,
+            false => Some(elf.rebuild_dynamic(&edits, data_vaddr)?),
+        };
+        match &dynamic {
+            Some((data, dynamic_size)) => {
+                segments.insert(
+                    last_load + 2,
+                    ElfSegment {
+                        kind: PT_LOAD,
+                        flags: PF_R | PF_W,
+                        offset: data_offset,
+                        vaddr: data_vaddr,
+                        paddr: data_vaddr,
+                        filesz: data.len() as u64,
+                        memsz: data.len() as u64,
+                        align: PAGE_SIZE,
+                    },
+                );
+                for segment in segments.iter_mut().filter(|x| x.kind == PT_DYNAMIC) {
+                    segment.offset = data_offset;
+                    segment.vaddr = data_vaddr;
+                    segment.paddr = data_vaddr;
+                    segment.filesz = *dynamic_size;
+                    segment.memsz = *dynamic_size;
+                }
+                // keep section based tools in line with the loader
+                let sections = elf.sections()?;
+                if let Some(section) = sections.iter().find(|x| x.kind == SHT_DYNAMIC) {
+                    let word = if elf.is_64 { 8 } else { 4 };
+                    let (addr, off, size) = if elf.is_64 {
+                        (0x10, 0x18, 0x20)
+                    } else {
+                        (0xc, 0x10, 0x14)
+                    };
+                    let strtab = sections.get(section.link as usize).map(|x| x.header);
+                    let strtab_size = data.len() as u64 - dynamic_size;
+                    for (header, at, len) in [
+                        (Some(section.header), 0, *dynamic_size),
+                        (strtab, *dynamic_size, strtab_size),
+                    ] {
+                        let Some(header) = header else {
+                            continue;
+                        };
+                        elf.set_uint(header + addr, word, data_vaddr + at)?;
+                        elf.set_uint(header + off, word, data_offset + at)?;
+                        elf.set_uint(header + size, word, len)?;
+                    }
+                }
+            }
+            // unused slot reserved in the table
+            None => segments.push(ElfSegment {
+                kind: 0,
+                flags: 0,
+                offset: 0,
+                vaddr: 0,
+                paddr: 0,
+                filesz: 0,
+                memsz: 0,
+                align: 0,
+            }),
+        }
+
+        if elf.is_64 {
+            elf.set_uint(0x20, 8, offset)?;
+            elf.set_uint(0x38, 2, segments.len() as u64)?;
+        } else {
+            elf.set_uint(0x1c, 4, offset)?;
+            elf.set_uint(0x2c, 2, segments.len() as u64)?;
+        }
+
+        let mut table: Vec<u8> = segments.iter().flat_map(|x| elf.segment_bytes(x)).collect();
+        table.resize(code_offset as usize, 0);
+        let mut data = elf.data;
+        data.resize(offset as usize, 0);
+        data.extend(table);
+        data.extend(code);
+        if let Some((dynamic, _)) = dynamic {
+            data.resize(data_offset as usize, 0);
+            data.extend(dynamic);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELF32: &[u8] = include_bytes!("../../tests/fixtures/elf32");
+    const ELF64: &[u8] = include_bytes!("../../tests/fixtures/elf64");
+
+    fn patcher(file: &[u8]) -> ElfPatcher {
+        ElfPatcher::new(ElfFile::parse(file.to_vec()).unwrap()).unwrap()
+    }
+
+    /// up to 0x20 bytes at `addr`, the added code may end sooner
+    fn code_at(elf: &ElfFile, addr: u64) -> &[u8] {
+        (1..=0x20).rev().find_map(|x| elf.read_at(addr, x)).unwrap()
+    }
+
+    /// code and trampoline of `hook` in the built file, decoded
+    fn built(patcher: ElfPatcher, hook: &StaticHook) -> (Vec<u8>, Vec<Code>, Instruction) {
+        let bitness = if patcher.elf.is_64 { 64 } else { 32 };
+        let elf = ElfFile::parse(patcher.build().unwrap()).unwrap();
+        let target = elf
+            .read_at(hook.target, hook.pad + hook.fetch)
+            .unwrap()
+            .to_vec();
+        let code = code_at(&elf, hook.trampoline);
+        let mut decoder = Decoder::with_ip(bitness, code, hook.trampoline, DecoderOptions::NONE);
+        let mut codes = vec![];
+        loop {
+            let inst = decoder.decode();
+            if inst.is_jmp_near() {
+                return (target, codes, inst);
+            }
+            codes.push(inst.code());
+        }
+    }
+
+    #[test]
+    fn symbols_and_segments() {
+        for (file, is_64, base) in [(ELF32, false, 0x8048000), (ELF64, true, 0x400000)] {
+            let elf = ElfFile::parse(file.to_vec()).unwrap();
+            assert_eq!(elf.is_64(), is_64);
+            assert_eq!(elf.segments().len(), 4);
+            assert_eq!(elf.symbol("plain"), Some(base + 0x1000));
+            assert_eq!(elf.symbol("endbr"), Some(base + 0x1020));
+            assert_eq!(elf.symbol("missing"), None);
+        }
+    }
+
+    #[test]
+    fn endbr_stays_in_place() {
+        let mut patcher = patcher(ELF64);
+        let hook = patcher.hook(0x401020, 0x401040).unwrap();
+        assert_eq!((hook.pad, hook.fetch), (4, 8));
+
+        let (target, trampoline, back) = built(patcher, &hook);
+        let rel = (0x401040u32 - 0x401029).to_le_bytes();
+        assert_eq!(target[..4], ENDBR64);
+        assert_eq!(target[4..9], [0xe9, rel[0], rel[1], rel[2], rel[3]]);
+        assert_eq!(target[9..], [0xcc; 3]);
+        assert_eq!(
+            trampoline,
+            [
+                Code::Endbr64,
+                Code::Push_r64,
+                Code::Mov_rm64_r64,
+                Code::Sub_rm64_imm8
+            ]
+        );
+        assert_eq!(back.near_branch_target(), 0x40102c);
+    }
+
+    #[test]
+    fn pc_thunk_loads_original_address() {
+        let mut patcher = patcher(ELF32);
+        let hook = patcher.hook(0x8049040, 0x8049000).unwrap();
+        assert_eq!((hook.pad, hook.fetch), (0, 5));
+
+        let elf = ElfFile::parse(patcher.build().unwrap()).unwrap();
+        let code = code_at(&elf, hook.trampoline);
+        let mut decoder = Decoder::with_ip(32, code, hook.trampoline, DecoderOptions::NONE);
+        assert_eq!(decoder.decode().code(), Code::Endbr32);
+        let load = decoder.decode();
+        assert_eq!(load.code(), Code::Mov_r32_imm32);
+        assert_eq!(load.op0_register(), iced_x86::Register::EBX);
+        assert_eq!(load.immediate32(), 0x8049045);
+        assert_eq!(decoder.decode().near_branch_target(), 0x8049045);
+    }
+
+    #[test]
+    fn rip_relative_prologue_is_moved() {
+        let mut patcher = patcher(ELF64);
+        let hook = patcher.hook(0x401040, 0x401000).unwrap();
+        assert_eq!((hook.pad, hook.fetch), (0, 7));
+
+        let elf = ElfFile::parse(patcher.build().unwrap()).unwrap();
+        let code = code_at(&elf, hook.trampoline);
+        let mut decoder = Decoder::with_ip(64, code, hook.trampoline, DecoderOptions::NONE);
+        assert_eq!(decoder.decode().code(), Code::Endbr64);
+        let load = decoder.decode();
+        assert!(load.is_ip_rel_memory_operand());
+        assert_eq!(load.ip_rel_memory_address(), 0x401080);
+        assert_eq!(decoder.decode().near_branch_target(), 0x401047);
+    }
+
+    #[test]
+    fn overlaps_cover_the_moved_prologue() {
+        let mut patcher = patcher(ELF64);
+        patcher.hook(0x401020, 0x401040).unwrap();
+        // past the jump but inside the moved `sub rsp, 0x20`
+        assert!(matches!(
+            patcher.hook(0x401028, 0x401040),
+            Err(Error::InvalidAddress)
+        ));
+        assert!(matches!(
+            patcher.hook(0x401020, 0x401040),
+            Err(Error::InvalidAddress)
+        ));
+    }
+
+    #[test]
+    fn earlier_hook_inside_the_moved_prologue() {
+        // `sub esp, 0x20` at +7 lies past a rel32 jump from +4 but is moved along
+        let mut patcher = patcher(ELF32);
+        patcher.hook(0x8049027, 0x8049000).unwrap();
+        assert!(matches!(
+            patcher.hook(0x8049020, 0x8049000),
+            Err(Error::InvalidAddress)
+        ));
+    }
+
+    /// needed libraries, version need files and their version names
+    fn imports(elf: &ElfFile) -> (Vec<String>, Vec<(String, Vec<String>)>) {
+        let dynamic = elf.dynamic().unwrap();
+        let value = |tag| dynamic.iter().find(|x| x.0 == tag).unwrap().1;
+        let strings = elf
+            .read_at(value(DT_STRTAB), value(DT_STRSZ) as usize)
+            .unwrap();
+        let name = |at: u64| {
+            String::from_utf8(read_c_str(strings, at as usize).unwrap().to_vec()).unwrap()
+        };
+        let needed = dynamic
+            .iter()
+            .filter(|x| x.0 == DT_NEEDED)
+            .map(|x| name(x.1))
+            .collect();
+        let needs = elf
+            .version_needs(&dynamic)
+            .unwrap()
+            .into_iter()
+            .map(|(need, auxes)| {
+                let versions = auxes
+                    .iter()
+                    .map(|aux| {
+                        assert_eq!(
+                            elf.uint(*aux, 4).unwrap(),
+                            elf_hash(name(elf.uint(aux + 8, 4).unwrap()).as_bytes()) as u64
+                        );
+                        name(elf.uint(aux + 8, 4).unwrap())
+                    })
+                    .collect();
+                (name(elf.uint(need + 4, 4).unwrap()), versions)
+            })
+            .collect();
+        (needed, needs)
+    }
+
+    #[test]
+    fn needed_libraries_are_edited() {
+        for file in [ELF32, ELF64] {
+            let mut patcher = patcher(file);
+            let version = if patcher.elf.is_64 {
+                "GLIBC_2.2.5"
+            } else {
+                "GLIBC_2.0"
+            };
+            patcher
+                .add_needed("libhook.so")
+                .add_needed("libm.so.6")
+                .replace_needed("libc.so.6", "libc.musl.so")
+                .redirect_version("libc.musl.so", version, "GLIBC_2.1");
+            let elf = ElfFile::parse(patcher.build().unwrap()).unwrap();
+            let (needed, needs) = imports(&elf);
+            assert_eq!(needed, ["libc.musl.so", "libm.so.6", "libhook.so"]);
+            assert_eq!(
+                needs,
+                [("libc.musl.so".to_string(), vec!["GLIBC_2.1".to_string()])]
+            );
+        }
+    }
+
+    #[test]
+    fn failed_edits_leave_the_file_untouched() {
+        let replace = |from: &str| DynamicEdit::Replace(from.to_string(), "libc.musl.so".into());
+        let version = DynamicEdit::Version {
+            library: "libm.so.6".into(),
+            from: "GLIBC_2.2.5".into(),
+            to: "GLIBC_2.1".into(),
+        };
+        for edits in [
+            vec![replace("libnone.so")],
+            vec![replace("libc.so.6"), version],
+        ] {
+            let mut elf = ElfFile::parse(ELF64.to_vec()).unwrap();
+            assert!(matches!(
+                elf.rebuild_dynamic(&edits, 0x500000),
+                Err(Error::ModuleNotFound)
+            ));
+            assert_eq!(elf.data(), ELF64);
+        }
+
+        let mut patcher = patcher(ELF64);
+        patcher.replace_needed("libnone.so", "libc.musl.so");
+        assert!(matches!(patcher.build(), Err(Error::ModuleNotFound)));
+    }
+}
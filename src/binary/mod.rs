@@ -0,0 +1,29 @@
+//! editing executables on disk
+//!
+//! works on file bytes only, so binaries of any platform can be edited on any host
+
+mod elf;
+mod pe;
+
+pub use crate::pe::Import;
+pub use elf::{ElfFile, ElfPatcher, ElfSegment, StaticHook};
+pub use pe::PeFile;
+
+/// little endian unsigned integer of `size` bytes at `offset`
+fn read_uint(data: &[u8], offset: usize, size: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(size)?)?;
+    let mut value = [0u8; 8];
+    value[..size].copy_from_slice(bytes);
+    Some(u64::from_le_bytes(value))
+}
+
+fn write_uint(data: &mut [u8], offset: usize, size: usize, value: u64) -> Option<()> {
+    data.get_mut(offset..offset.checked_add(size)?)?
+        .copy_from_slice(&value.to_le_bytes()[..size]);
+    Some(())
+}
+
+fn read_c_str(data: &[u8], offset: usize) -> Option<&[u8]> {
+    let bytes = data.get(offset..)?;
+    bytes.iter().position(|x| *x == 0).map(|end| &bytes[..end])
+}
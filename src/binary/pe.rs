@@ -0,0 +1,357 @@
+use crate::Error;
+use crate::binary::{read_uint, write_uint};
+use crate::pe::{
+    IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT, IMAGE_DIRECTORY_ENTRY_IMPORT,
+    IMAGE_DIRECTORY_ENTRY_SECURITY, IMPORT_DESCRIPTOR_SIZE, Import, PeImage, SECTION_HEADER_SIZE,
+};
+use std::path::Path;
+
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x40;
+const IMAGE_SCN_MEM_READ: u32 = 0x40000000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x80000000;
+
+const SECTION_NAME: &[u8; 8] = b".detour\0";
+
+fn append_str(section: &mut Vec<u8>, value: &str) -> usize {
+    let at = section.len();
+    section.extend(value.as_bytes());
+    section.push(0);
+    at
+}
+
+/// edit the import table of a PE file, PE32 and PE32+
+///
+/// like `DetourBinaryEditImports`, descriptors are rebuilt in a new section at the end of
+/// the image so existing code and data stay where they are
+pub struct PeFile {
+    data: Vec<u8>,
+    is_64: bool,
+    file_header: usize,
+    optional_header: usize,
+    added: Vec<(String, Vec<Import>)>,
+    replaced: Vec<(String, String)>,
+}
+
+impl PeFile {
+    pub fn parse(data: Vec<u8>) -> Result<PeFile, Error> {
+        let image = PeImage::parse(&data)?;
+        let (is_64, file_header, optional_header) =
+            (image.is_64(), image.file_header, image.optional_header);
+        Ok(PeFile {
+            data,
+            is_64,
+            file_header,
+            optional_header,
+            added: vec![],
+            replaced: vec![],
+        })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<PeFile, Error> {
+        let data = std::fs::read(path)
+            .map_err(|err| Error::ErrorCode(err.raw_os_error().unwrap_or(0) as usize))?;
+        PeFile::parse(data)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn is_64(&self) -> bool {
+        self.is_64
+    }
+
+    fn image(&self) -> Result<PeImage<'_>, Error> {
+        PeImage::parse(&self.data)
+    }
+
+    fn uint(&self, offset: usize, size: usize) -> Result<u64, Error> {
+        read_uint(&self.data, offset, size).ok_or(Error::InvalidImage)
+    }
+
+    fn set_uint(&mut self, offset: usize, size: usize, value: u64) -> Result<(), Error> {
+        write_uint(&mut self.data, offset, size, value).ok_or(Error::InvalidImage)
+    }
+
+    /// names of imported dlls in descriptor order
+    pub fn imports(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .image()?
+            .import_descriptors()?
+            .into_iter()
+            .map(|x| x.dll)
+            .collect())
+    }
+
+    /// import `functions` from `dll`, loading it with the program
+    ///
+    /// an empty list imports ordinal 1 as `withdll` does, the loader skips descriptors
+    /// without thunks
+    pub fn add_import(&mut self, dll: &str, functions: &[Import]) -> &mut PeFile {
+        let functions = match functions.is_empty() {
+            true => vec![Import::Ordinal(1)],
+            false => functions.to_vec(),
+        };
+        self.added.push((dll.to_string(), functions));
+        self
+    }
+
+    /// import from `to` whatever was imported from `from`, names compare case insensitive
+    pub fn replace_import(&mut self, from: &str, to: &str) -> &mut PeFile {
+        self.replaced.push((from.to_string(), to.to_string()));
+        self
+    }
+
+    /// file with the edits applied
+    ///
+    /// the new section goes behind the raw data of the others, an overlay such as the
+    /// certificate table moves behind it. A signature no longer verifies after the edit
+    pub fn build(mut self) -> Result<Vec<u8>, Error> {
+        if self.added.is_empty() && self.replaced.is_empty() {
+            return Ok(self.data);
+        }
+        let image = self.image()?;
+        let sections = image.sections();
+        let count = sections.len();
+        let table = self.optional_header + self.uint(self.file_header + 16, 2)? as usize;
+        let new_header = table + count * SECTION_HEADER_SIZE;
+        let size_of_headers = image.size_of_headers() as usize;
+        let first_raw = sections
+            .iter()
+            .filter(|x| x.size_of_raw_data != 0)
+            .map(|x| x.pointer_to_raw_data as usize)
+            .min()
+            .unwrap_or(size_of_headers);
+        if new_header + SECTION_HEADER_SIZE > size_of_headers.min(first_raw) {
+            return Err(Error::NotEnoughMemory);
+        }
+
+        // anything behind the raw data of the sections is overlay
+        let raw_end = sections
+            .iter()
+            .map(|x| x.pointer_to_raw_data as usize + x.size_of_raw_data as usize)
+            .max()
+            .unwrap_or(0)
+            .max(size_of_headers)
+            .min(self.data.len());
+        let security = image.directory_offset(IMAGE_DIRECTORY_ENTRY_SECURITY).ok();
+
+        let section_alignment = image.section_alignment();
+        let file_alignment = image.file_alignment();
+        let rva = sections
+            .iter()
+            .map(|x| x.range().end)
+            .max()
+            .unwrap_or(section_alignment)
+            .next_multiple_of(section_alignment);
+
+        // descriptors first, then thunks and names of the added ones
+        let descriptors = image.import_descriptors()?;
+        let total = descriptors.len() + self.added.len() + 1;
+        let mut section = vec![0u8; total * IMPORT_DESCRIPTOR_SIZE];
+        for (i, descriptor) in descriptors.iter().enumerate() {
+            section[i * IMPORT_DESCRIPTOR_SIZE..(i + 1) * IMPORT_DESCRIPTOR_SIZE]
+                .copy_from_slice(image.read(descriptor.rva, IMPORT_DESCRIPTOR_SIZE)?);
+        }
+        let import = image.directory_offset(IMAGE_DIRECTORY_ENTRY_IMPORT)?;
+        let bound = image
+            .directory_offset(IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT)
+            .ok();
+
+        for (from, to) in &self.replaced {
+            let mut found = false;
+            for (i, descriptor) in descriptors.iter().enumerate() {
+                if !descriptor.dll.eq_ignore_ascii_case(from) {
+                    continue;
+                }
+                let name = append_str(&mut section, to) as u64;
+                write_uint(
+                    &mut section,
+                    i * IMPORT_DESCRIPTOR_SIZE + 12,
+                    4,
+                    rva as u64 + name,
+                );
+                found = true;
+            }
+            if !found {
+                return Err(Error::ModuleNotFound);
+            }
+        }
+
+        let (thunk, ordinal_flag) = if self.is_64 {
+            (8, 1u64 << 63)
+        } else {
+            (4, 1u64 << 31)
+        };
+        for (i, (dll, functions)) in self.added.iter().enumerate() {
+            section.resize(section.len().next_multiple_of(thunk), 0);
+            let ilt = section.len();
+            let iat = ilt + thunk * (functions.len() + 1);
+            section.resize(iat + thunk * (functions.len() + 1), 0);
+            let name = append_str(&mut section, dll);
+            for (k, function) in functions.iter().enumerate() {
+                let value = match function {
+                    Import::Ordinal(ordinal) => ordinal_flag | *ordinal as u64,
+                    Import::Name(function) => {
+                        section.resize(section.len().next_multiple_of(2), 0);
+                        let hint = section.len();
+                        section.extend([0, 0]);
+                        append_str(&mut section, function);
+                        rva as u64 + hint as u64
+                    }
+                };
+                write_uint(&mut section, ilt + k * thunk, thunk, value);
+                write_uint(&mut section, iat + k * thunk, thunk, value);
+            }
+            let descriptor = (descriptors.len() + i) * IMPORT_DESCRIPTOR_SIZE;
+            write_uint(&mut section, descriptor, 4, rva as u64 + ilt as u64);
+            write_uint(&mut section, descriptor + 12, 4, rva as u64 + name as u64);
+            write_uint(&mut section, descriptor + 16, 4, rva as u64 + iat as u64);
+        }
+
+        let virtual_size = section.len() as u32;
+        let size_of_raw_data = virtual_size.next_multiple_of(file_alignment);
+        let pointer_to_raw_data = (raw_end as u32).next_multiple_of(file_alignment);
+        self.data[new_header..new_header + SECTION_HEADER_SIZE].fill(0);
+        self.data[new_header..new_header + 8].copy_from_slice(SECTION_NAME);
+        for (at, value) in [
+            (8, virtual_size),
+            (12, rva),
+            (16, size_of_raw_data),
+            (20, pointer_to_raw_data),
+            (
+                36,
+                IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+            ),
+        ] {
+            self.set_uint(new_header + at, 4, value as u64)?;
+        }
+        self.set_uint(self.file_header + 2, 2, count as u64 + 1)?;
+        self.set_uint(
+            self.optional_header + 56,
+            4,
+            (rva + virtual_size).next_multiple_of(section_alignment) as u64,
+        )?;
+        // checksum is only enforced for drivers, zero marks it as not computed
+        self.set_uint(self.optional_header + 64, 4, 0)?;
+
+        self.set_uint(import, 4, rva as u64)?;
+        self.set_uint(import + 4, 4, (total * IMPORT_DESCRIPTOR_SIZE) as u64)?;
+        // bound addresses describe the old table
+        if let Some(bound) = bound {
+            self.set_uint(bound, 8, 0)?;
+        }
+
+        // certificate entries are 8 byte aligned, the shift keeps them so
+        let section_end = (pointer_to_raw_data + size_of_raw_data) as usize;
+        let shift = (section_end - raw_end).next_multiple_of(8);
+        if let Some(security) = security {
+            let offset = self.uint(security, 4)? as usize;
+            if offset >= raw_end {
+                self.set_uint(security, 4, (offset + shift) as u64)?;
+            }
+        }
+
+        let mut data = self.data;
+        let overlay = data.split_off(raw_end);
+        data.resize(pointer_to_raw_data as usize, 0);
+        data.extend(section);
+        data.resize(raw_end + shift, 0);
+        data.extend(overlay);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::tests::{PE32, PE64, map};
+
+    fn name(function: &str) -> Import {
+        Import::Name(function.to_string())
+    }
+
+    /// imports of `pe` as dll and import pairs in descriptor order
+    fn imports(pe: &PeImage) -> Vec<(String, Import)> {
+        let thunks = pe.imports().unwrap();
+        thunks.into_iter().map(|x| (x.dll, x.import)).collect()
+    }
+
+    #[test]
+    fn added_and_replaced_imports() {
+        for file in [PE32, PE64] {
+            let mut pe = PeFile::parse(file.to_vec()).unwrap();
+            pe.add_import("detoured.dll", &[])
+                .add_import("hooks.dll", &[name("Hook"), Import::Ordinal(7)])
+                .replace_import("ws2_32.dll", "wsock32.dll");
+            let built = pe.build().unwrap();
+
+            let original = PeImage::parse(file).unwrap();
+            for image in [
+                PeImage::parse(&built).unwrap(),
+                PeImage::parse_mapped(&map(&built)).unwrap(),
+            ] {
+                let dlls = image.import_descriptors().unwrap();
+                let dlls = dlls.iter().map(|x| x.dll.as_str()).collect::<Vec<_>>();
+                assert_eq!(
+                    dlls,
+                    ["KERNEL32.dll", "wsock32.dll", "detoured.dll", "hooks.dll"]
+                );
+                let found = imports(&image);
+                assert_eq!(found[..2], imports(&original)[..2]);
+                assert_eq!(found[2], ("wsock32.dll".into(), Import::Ordinal(115)));
+                assert_eq!(found[3], ("wsock32.dll".into(), name("connect")));
+                assert_eq!(found[4], ("detoured.dll".into(), Import::Ordinal(1)));
+                assert_eq!(found[5], ("hooks.dll".into(), name("Hook")));
+                assert_eq!(found[6], ("hooks.dll".into(), Import::Ordinal(7)));
+
+                let section = image.sections().last().unwrap();
+                assert_eq!(section.name, ".detour");
+                assert_eq!(section.virtual_address, original.size_of_image());
+                assert!(image.size_of_image() >= section.range().end);
+            }
+            // existing sections stay where they are
+            let headers = original.size_of_headers() as usize;
+            assert_eq!(built[headers..file.len()], file[headers..]);
+        }
+    }
+
+    #[test]
+    fn replacing_missing_import_fails() {
+        let mut pe = PeFile::parse(PE64.to_vec()).unwrap();
+        pe.add_import("detoured.dll", &[])
+            .replace_import("user32.dll", "hooks.dll");
+        assert!(matches!(pe.build(), Err(Error::ModuleNotFound)));
+
+        let unchanged = PeFile::parse(PE32.to_vec()).unwrap().build().unwrap();
+        assert_eq!(unchanged, PE32);
+    }
+
+    #[test]
+    fn overlay_moves_behind_new_section() {
+        let certificate = b"\x10\0\0\0\0\x02\x02\0signed!\0";
+        for file in [PE32, PE64] {
+            let mut data = file.to_vec();
+            let at = data.len();
+            data.extend(certificate);
+            let security = PeImage::parse(&data)
+                .unwrap()
+                .directory_offset(IMAGE_DIRECTORY_ENTRY_SECURITY)
+                .unwrap();
+            write_uint(&mut data, security, 4, at as u64);
+            write_uint(&mut data, security + 4, 4, certificate.len() as u64);
+
+            let mut pe = PeFile::parse(data).unwrap();
+            pe.add_import("detoured.dll", &[]);
+            let built = pe.build().unwrap();
+            let image = PeImage::parse(&built).unwrap();
+            let section = image.sections().last().unwrap();
+            assert_eq!(section.pointer_to_raw_data as usize, at);
+            let moved = read_uint(&built, security, 4).unwrap() as usize;
+            assert!(moved >= at + section.size_of_raw_data as usize);
+            assert_eq!(moved % 8, 0);
+            assert_eq!(&built[moved..], certificate);
+            assert_eq!(image.import_descriptors().unwrap().len(), 3);
+        }
+    }
+}
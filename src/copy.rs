@@ -0,0 +1,1009 @@
+//! relocating copy of instructions
+//!
+//! branches and rip relative operands are re-encoded for the address the copy runs at, like
+//! `DetourCopyInstruction`. Position dependent idioms are rewritten to produce the values the
+//! original code would have seen, operands out of reach of the copy are emulated through a
+//! scratch register and branches out of reach jump through an inline literal
+
+use crate::backend::{MemoryBackend, ProcessMemory};
+use crate::{Error, inst};
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Code, Decoder, DecoderOptions, FlowControl, IcedError,
+    Instruction, InstructionBlock, InstructionInfoFactory, MemoryOperand, OpKind, Register,
+};
+use std::ffi::c_void;
+use std::ops::Range;
+
+// distance a rip relative operand of the copy may safely span, leaves room for the code
+// growing while encoded
+const REACH: u64 = i32::MAX as u64 - 0x1000;
+
+// registers free to borrow around an emulated instruction, saved and restored on the stack
+const SCRATCH: [Register; 10] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::RBX,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+];
+
+// `jmp [rip+0]` followed by the 8 byte target
+const JMP_LITERAL: [u8; 6] = [0xff, 0x25, 0, 0, 0, 0];
+// `call [rip+2]; jmp short` over the 8 byte target which follows
+const CALL_LITERAL: [u8; 8] = [0xff, 0x15, 2, 0, 0, 0, 0xeb, 8];
+const LITERAL_JUMP_LEN: u8 = JMP_LITERAL.len() as u8 + 8;
+// size of the sequence `jump_through` emits
+const JUMP_THROUGH_LEN: u8 = 26;
+// bytes below rsp leaf functions may use without moving it, sequences saving registers
+// step over them
+const RED_ZONE: i64 = 128;
+
+/// copied code and where each source instruction ended up
+#[derive(Clone, Debug)]
+pub struct CopyResult {
+    /// code to place at the destination
+    pub code: Vec<u8>,
+    /// source bytes consumed
+    pub len: usize,
+    /// offset of every source instruction and of its copy, ordered
+    pub offsets: Vec<(usize, usize)>,
+    /// an emulated branch returns into its target, shadow stacks reject it
+    pub uses_ret: bool,
+}
+
+impl CopyResult {
+    /// offset in the copy of the source instruction starting at `offset`
+    pub fn translate(&self, offset: usize) -> Option<usize> {
+        self.offsets.iter().find(|x| x.0 == offset).map(|x| x.1)
+    }
+}
+
+/// nothing after this instruction belongs to the same path
+fn ends_function(inst: &Instruction) -> bool {
+    inst.is_invalid()
+        || inst.code() == Code::Int3
+        || matches!(
+            inst.flow_control(),
+            FlowControl::UnconditionalBranch | FlowControl::IndirectBranch | FlowControl::Return
+        )
+}
+
+/// `mov reg, imm` loading `value` into the full width `reg`
+fn load_immediate(reg: Register, value: u64) -> Result<Instruction, Error> {
+    let inst = match reg.size() {
+        8 => Instruction::with2(Code::Mov_r64_imm64, reg, value),
+        _ => Instruction::with2(Code::Mov_r32_imm32, reg, value as u32),
+    };
+    inst.map_err(|_| Error::InvalidAddress)
+}
+
+/// register loaded by `call __x86.get_pc_thunk.reg`, a thunk of `mov reg, [esp]; ret`
+fn pc_thunk_register(
+    backend: &dyn MemoryBackend,
+    bitness: u32,
+    inst: &Instruction,
+) -> Option<Register> {
+    if inst.code() != Code::Call_rel32_32 {
+        return None;
+    }
+    let thunk = inst.near_branch_target() as usize;
+    let mut code = [0u8; 8];
+    let len = inst::read_code(backend, thunk, &mut code);
+    let mut decoder = Decoder::with_ip(bitness, &code[..len], thunk as u64, DecoderOptions::NONE);
+    let mov = decoder.decode();
+    let ret = decoder.decode();
+    let loads_return = mov.code() == Code::Mov_r32_rm32
+        && mov.memory_base() == Register::ESP
+        && mov.memory_index() == Register::None
+        && mov.memory_displacement32() == 0;
+    (loads_return && ret.code() == Code::Retnd).then(|| mov.op0_register())
+}
+
+/// rip relative operand of `inst` the copy at `dst` can't reach
+fn out_of_reach(inst: &Instruction, dst: usize) -> bool {
+    inst.is_ip_rel_memory_operand() && inst.ip_rel_memory_address().abs_diff(dst as u64).gt(&REACH)
+}
+
+/// `lea rsp, [rsp + displ]`, moves the stack pointer without touching flags
+fn move_stack(displ: i64) -> Result<Instruction, IcedError> {
+    Instruction::with2(
+        Code::Lea_r64_m,
+        Register::RSP,
+        MemoryOperand::with_base_displ(Register::RSP, displ),
+    )
+}
+
+/// push the pointer stored at `addr` below the red zone then return into it, the return
+/// drops the red zone again, all registers are preserved
+fn jump_through(addr: u64, out: &mut Vec<Instruction>) -> Result<(), IcedError> {
+    out.push(move_stack(-RED_ZONE)?);
+    out.push(Instruction::with1(Code::Push_r64, Register::RAX)?);
+    out.push(Instruction::with2(
+        Code::Mov_r64_imm64,
+        Register::RAX,
+        addr,
+    )?);
+    out.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::RAX,
+        MemoryOperand::with_base(Register::RAX),
+    )?);
+    out.push(Instruction::with2(
+        Code::Xchg_rm64_r64,
+        MemoryOperand::with_base(Register::RSP),
+        Register::RAX,
+    )?);
+    out.push(Instruction::with1(Code::Retnq_imm16, RED_ZONE as u32)?);
+    Ok(())
+}
+
+/// replace `inst`, whose rip relative operand is out of reach, with an equivalent sequence
+///
+/// returns whether the sequence jumps with `ret`
+fn emulate(inst: &Instruction, out: &mut Vec<Instruction>) -> Result<bool, Error> {
+    let unrelocatable = |_| Error::Unrelocatable(inst.ip() as usize);
+    let addr = inst.ip_rel_memory_address();
+    let uses_ret = match inst.code() {
+        // `jmp [far]`
+        Code::Jmp_rm64 => {
+            jump_through(addr, out).map_err(unrelocatable)?;
+            true
+        }
+        // `call [far]`, a local call pushes the return address then jumps like above,
+        // returning into a short jump over it
+        Code::Call_rm64 => {
+            let call = [0xe8, 2, 0, 0, 0, 0xeb, JUMP_THROUGH_LEN];
+            out.push(Instruction::with_declare_byte(&call).map_err(unrelocatable)?);
+            jump_through(addr, out).map_err(unrelocatable)?;
+            true
+        }
+        // any other operand is addressed through a borrowed register, instructions which
+        // touch the stack or branch would see it shifted
+        _ => {
+            let info = InstructionInfoFactory::new().info(inst).clone();
+            let used = |reg: Register| {
+                info.used_registers()
+                    .iter()
+                    .any(|x| x.register().full_register() == reg)
+            };
+            let Some(scratch) = SCRATCH.into_iter().find(|x| !used(*x)) else {
+                return Err(Error::Unrelocatable(inst.ip() as usize));
+            };
+            if used(Register::RSP)
+                || inst.stack_pointer_increment() != 0
+                || inst.flow_control() != FlowControl::Next
+            {
+                return Err(Error::Unrelocatable(inst.ip() as usize));
+            }
+            let mut moved = *inst;
+            moved.set_memory_base(scratch);
+            moved.set_memory_displacement64(0);
+            moved.set_memory_displ_size(0);
+            moved.set_ip(0);
+            out.push(move_stack(-RED_ZONE).map_err(unrelocatable)?);
+            out.push(Instruction::with1(Code::Push_r64, scratch).map_err(unrelocatable)?);
+            out.push(
+                Instruction::with2(Code::Mov_r64_imm64, scratch, addr).map_err(unrelocatable)?,
+            );
+            out.push(moved);
+            out.push(Instruction::with1(Code::Pop_r64, scratch).map_err(unrelocatable)?);
+            out.push(move_stack(RED_ZONE).map_err(unrelocatable)?);
+            false
+        }
+    };
+    Ok(uses_ret)
+}
+
+/// near branch of `inst` leaving the `copied` range for a target the copy at `dst` can't reach
+///
+/// `loop` and `jrcxz` only have a rel8 form, the encoder would rewrite them into a sequence
+/// without an offset, they go through the literal once they leave the copy
+fn far_branch(bitness: u32, inst: &Instruction, copied: &Range<u64>, dst: usize) -> bool {
+    let target = inst.near_branch_target();
+    let short_only = inst.is_loop() || inst.is_loopcc() || inst.is_jcx_short();
+    bitness == 64
+        && matches!(
+            inst.op0_kind(),
+            OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64
+        )
+        && !copied.contains(&target)
+        && (short_only || target.abs_diff(dst as u64) > REACH)
+}
+
+/// replace the far branch `inst`, encoded as `raw`, with position independent code jumping
+/// through an inline literal
+fn branch_through_literal(
+    inst: &Instruction,
+    raw: &[u8],
+    out: &mut Vec<Instruction>,
+) -> Result<(), Error> {
+    let unrelocatable = |_| Error::Unrelocatable(inst.ip() as usize);
+    let code = if inst.is_jmp_short_or_near() {
+        JMP_LITERAL.to_vec()
+    } else if inst.is_call_near() {
+        CALL_LITERAL.to_vec()
+    } else if inst.is_jcc_short_or_near() {
+        // the inverted condition skips the jump
+        let mut skip = *inst;
+        skip.negate_condition_code();
+        let jcc = 0x70 + skip.condition_code() as u8 - 1;
+        [&[jcc, LITERAL_JUMP_LEN][..], &JMP_LITERAL].concat()
+    } else if inst.is_loop() || inst.is_loopcc() || inst.is_jcx_short() {
+        // taken the short branch lands on the jump, falling through skips it
+        let short = &raw[..raw.len() - 1];
+        [short, &[2, 0xeb, LITERAL_JUMP_LEN], &JMP_LITERAL].concat()
+    } else {
+        return Err(Error::Unrelocatable(inst.ip() as usize));
+    };
+    out.push(Instruction::with_declare_byte(&code).map_err(unrelocatable)?);
+    out.push(Instruction::with_declare_qword_1(inst.near_branch_target()));
+    Ok(())
+}
+
+/// copy at least `min_len` bytes of whole instructions from `code`, which lives at `src`,
+/// into a sequence running at `dst`
+///
+/// copying stops early after an instruction which ends the function, less than `min_len`
+/// bytes is then an error. An instruction which can neither be moved nor emulated is reported
+/// as [`Error::Unrelocatable`]
+pub(crate) fn relocate(
+    backend: &dyn MemoryBackend,
+    code: &[u8],
+    src: usize,
+    dst: usize,
+    min_len: usize,
+) -> Result<CopyResult, Error> {
+    relocate_with(backend, inst::BITNESS, code, src, dst, min_len)
+}
+
+/// [`relocate`] for code of `bitness`, which may differ from the current process
+pub(crate) fn relocate_with(
+    backend: &dyn MemoryBackend,
+    bitness: u32,
+    code: &[u8],
+    src: usize,
+    dst: usize,
+    min_len: usize,
+) -> Result<CopyResult, Error> {
+    // whole instructions to move, with the register a position load is rewritten for
+    let mut sources = vec![];
+    let mut decoder = Decoder::with_ip(bitness, code, src as u64, DecoderOptions::NONE);
+    let mut len = 0;
+    while len < min_len && decoder.can_decode() {
+        let inst = decoder.decode();
+        if inst.is_invalid() {
+            break;
+        }
+        len += inst.len();
+
+        // the copy would see its own address, load the original one instead
+        let call_next = matches!(inst.code(), Code::Call_rel32_32 | Code::Call_rel32_64)
+            && inst.near_branch_target() == inst.next_ip();
+        if let Some(reg) = pc_thunk_register(backend, bitness, &inst) {
+            sources.push((inst, Some(reg)));
+            continue;
+        } else if call_next {
+            // `call $+5; pop reg`, the pop is moved along even past `min_len`
+            let position = decoder.position();
+            let pop = decoder.decode();
+            if matches!(pop.code(), Code::Pop_r32 | Code::Pop_r64) {
+                len += pop.len();
+                sources.push((inst, Some(pop.op0_register())));
+                continue;
+            }
+            decoder
+                .set_position(position)
+                .map_err(|_| Error::InvalidAddress)?;
+        }
+
+        sources.push((inst, None));
+        if ends_function(&inst) {
+            break;
+        }
+    }
+    if len < min_len {
+        return Err(Error::InvalidAddress);
+    }
+
+    // helpers keep ip 0, only the first instruction of a replacement stands for its source
+    let copied = src as u64..(src + len) as u64;
+    let mut instructions = vec![];
+    let mut starts = vec![];
+    let mut uses_ret = false;
+    for (inst, pc) in &sources {
+        let start = instructions.len();
+        match pc {
+            Some(reg) => instructions.push(load_immediate(*reg, inst.next_ip())?),
+            None if far_branch(bitness, inst, &copied, dst) => {
+                let offset = inst.ip() as usize - src;
+                let raw = &code[offset..offset + inst.len()];
+                branch_through_literal(inst, raw, &mut instructions)?
+            }
+            None if out_of_reach(inst, dst) => uses_ret |= emulate(inst, &mut instructions)?,
+            None => instructions.push(*inst),
+        }
+        instructions[start].set_ip(inst.ip());
+        starts.push(start);
+    }
+
+    // nothing is left out of reach, the encoder only has to widen short branches
+    let block = InstructionBlock::new(&instructions, dst as u64);
+    let encoded = BlockEncoder::encode(
+        bitness,
+        block,
+        BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+    )
+    .map_err(|_| Error::InvalidAddress)?;
+    let offsets = sources
+        .iter()
+        .zip(starts)
+        .map(
+            |((inst, _), start)| match encoded.new_instruction_offsets[start] {
+                u32::MAX => Err(Error::Unrelocatable(inst.ip() as usize)),
+                offset => Ok((inst.ip() as usize - src, offset as usize)),
+            },
+        )
+        .collect::<Result<_, _>>()?;
+    Ok(CopyResult {
+        code: encoded.code_buffer,
+        len,
+        offsets,
+        uses_ret,
+    })
+}
+
+/// operand kinds holding an immediate
+fn is_immediate(kind: OpKind) -> bool {
+    matches!(
+        kind,
+        OpKind::Immediate8
+            | OpKind::Immediate8_2nd
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate64
+            | OpKind::Immediate8to16
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate32to64
+    )
+}
+
+/// memory operands of both resolve to the same address, `scratch` is a register holding the
+/// address of a rip relative operand in the copy
+fn same_address(
+    original: &Instruction,
+    copy: &Instruction,
+    scratch: Option<(Register, u64)>,
+) -> bool {
+    if !original.is_ip_rel_memory_operand() {
+        return original.memory_base() == copy.memory_base()
+            && original.memory_index() == copy.memory_index()
+            && original.memory_index_scale() == copy.memory_index_scale()
+            && original.memory_displacement64() == copy.memory_displacement64();
+    }
+    let address = match scratch {
+        Some((reg, address))
+            if copy.memory_base() == reg
+                && copy.memory_index() == Register::None
+                && copy.memory_displacement64() == 0 =>
+        {
+            address
+        }
+        _ if copy.is_ip_rel_memory_operand() => copy.ip_rel_memory_address(),
+        _ => return false,
+    };
+    address == original.ip_rel_memory_address()
+}
+
+fn same_operand(
+    original: &Instruction,
+    copy: &Instruction,
+    operand: u32,
+    translate: &dyn Fn(u64) -> u64,
+    scratch: Option<(Register, u64)>,
+) -> bool {
+    let near = |kind| {
+        matches!(
+            kind,
+            OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64
+        )
+    };
+    match (original.op_kind(operand), copy.op_kind(operand)) {
+        (a, b) if near(a) && near(b) => {
+            translate(original.near_branch_target()) == copy.near_branch_target()
+        }
+        (OpKind::Memory, OpKind::Memory) => {
+            original.memory_size() == copy.memory_size()
+                && original.memory_segment() == copy.memory_segment()
+                && same_address(original, copy, scratch)
+        }
+        (OpKind::Register, OpKind::Register) => {
+            original.op_register(operand) == copy.op_register(operand)
+        }
+        (OpKind::FarBranch16 | OpKind::FarBranch32, b) if b == original.op_kind(operand) => {
+            original.far_branch_selector() == copy.far_branch_selector()
+                && original.far_branch32() == copy.far_branch32()
+        }
+        (a, b) if a == b && is_immediate(a) => {
+            original.immediate(operand) == copy.immediate(operand)
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// `copy` performs `original`, operand by operand
+fn same_instruction(
+    original: &Instruction,
+    copy: &Instruction,
+    translate: &dyn Fn(u64) -> u64,
+    scratch: Option<(Register, u64)>,
+) -> bool {
+    original.mnemonic() == copy.mnemonic()
+        && original.op_count() == copy.op_count()
+        && (0..original.op_count()).all(|i| same_operand(original, copy, i, translate, scratch))
+}
+
+/// `inst` is the `lea rsp, [rsp + displ]` of `move_stack`
+fn moves_stack(inst: &Instruction, displ: i64) -> bool {
+    inst.code() == Code::Lea_r64_m
+        && inst.op0_register() == Register::RSP
+        && inst.memory_base() == Register::RSP
+        && inst.memory_index() == Register::None
+        && inst.memory_displacement64() == displ as u64
+}
+
+/// qword a `jmp/call [rip+disp]` of `copy`, placed at `ip`, reads from inside the copy
+fn literal(copy: &[u8], ip: u64, inst: &Instruction) -> Option<u64> {
+    if !inst.is_ip_rel_memory_operand() {
+        return None;
+    }
+    let offset = inst.ip_rel_memory_address().checked_sub(ip)? as usize;
+    let bytes = copy.get(offset..offset + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// `copy` placed at `ip` replaces the `original` instructions, in any of the forms
+/// `relocate` produces
+fn same_group(
+    backend: &dyn MemoryBackend,
+    bitness: u32,
+    original: &[Instruction],
+    copy: &[u8],
+    ip: u64,
+    translate: &dyn Fn(u64) -> u64,
+) -> bool {
+    let moved: Vec<Instruction> = Decoder::with_ip(bitness, copy, ip, DecoderOptions::NONE)
+        .iter()
+        .collect();
+    let (Some(first), Some(head)) = (original.first(), moved.first()) else {
+        return false;
+    };
+    // past the end decodes as an invalid instruction, which matches nothing
+    let at = |i: usize| moved.get(i).copied().unwrap_or_default();
+    let end = ip + copy.len() as u64;
+    let skips_to_end = |i: usize| at(i).is_jmp_short() && at(i).near_branch_target() == end;
+
+    // `mov reg, original address` in place of a position load
+    if matches!(first.code(), Code::Call_rel32_32 | Code::Call_rel32_64)
+        && matches!(head.code(), Code::Mov_r32_imm32 | Code::Mov_r64_imm64)
+    {
+        let loaded = match original.get(1) {
+            // `call $+5; pop reg`
+            Some(pop) => Some(pop.op0_register()),
+            None => pc_thunk_register(backend, bitness, first),
+        };
+        return moved.len() == 1
+            && head.immediate(1) == first.next_ip()
+            && loaded == Some(head.op0_register());
+    }
+    if original.len() != 1 {
+        return false;
+    }
+
+    let single = moved.len() == 1 && same_instruction(first, head, translate, None);
+    if matches!(
+        first.op0_kind(),
+        OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64
+    ) {
+        // branch through an inline literal, literal bytes decode as junk after it
+        let target = translate(first.near_branch_target());
+        let literal_jump =
+            |i: usize| at(i).code() == Code::Jmp_rm64 && literal(copy, ip, &at(i)) == Some(target);
+        let through_literal = if first.is_jmp_short_or_near() {
+            literal_jump(0) && copy.len() == LITERAL_JUMP_LEN as usize
+        } else if first.is_call_near() {
+            head.code() == Code::Call_rm64
+                && literal(copy, ip, head) == Some(target)
+                && skips_to_end(1)
+        } else if first.is_jcc_short_or_near() {
+            let mut skip = *first;
+            skip.negate_condition_code();
+            head.is_jcc_short()
+                && head.condition_code() == skip.condition_code()
+                && head.near_branch_target() == end
+                && literal_jump(1)
+        } else {
+            head.mnemonic() == first.mnemonic()
+                && head.near_branch_target() == at(2).ip()
+                && skips_to_end(1)
+                && literal_jump(2)
+        };
+        return through_literal || single;
+    }
+
+    if first.is_ip_rel_memory_operand() {
+        // `jmp/call [far]` returning into the pointer read from the same address
+        let address = first.ip_rel_memory_address();
+        let jumps_through = |i: usize| {
+            moved.len() == i + 6
+                && moves_stack(&at(i), -RED_ZONE)
+                && at(i + 1).code() == Code::Push_r64
+                && at(i + 1).op0_register() == Register::RAX
+                && at(i + 2).code() == Code::Mov_r64_imm64
+                && at(i + 2).op0_register() == Register::RAX
+                && at(i + 2).immediate(1) == address
+                && at(i + 3).code() == Code::Mov_r64_rm64
+                && at(i + 3).memory_base() == Register::RAX
+                && at(i + 4).code() == Code::Xchg_rm64_r64
+                && at(i + 4).memory_base() == Register::RSP
+                && at(i + 5).code() == Code::Retnq_imm16
+                && at(i + 5).immediate(0) == RED_ZONE as u64
+        };
+        let emulated = match first.code() {
+            Code::Jmp_rm64 => jumps_through(0),
+            Code::Call_rm64 => {
+                head.code() == Code::Call_rel32_64
+                    && head.near_branch_target() == at(2).ip()
+                    && skips_to_end(1)
+                    && jumps_through(2)
+            }
+            _ => false,
+        };
+        // operand addressed through a borrowed register
+        let scratch = at(1).op0_register();
+        let borrowed = moved.len() == 6
+            && moves_stack(head, -RED_ZONE)
+            && at(1).code() == Code::Push_r64
+            && at(2).code() == Code::Mov_r64_imm64
+            && at(2).op0_register() == scratch
+            && at(4).code() == Code::Pop_r64
+            && at(4).op0_register() == scratch
+            && moves_stack(&at(5), RED_ZONE)
+            && same_instruction(
+                first,
+                &at(3),
+                translate,
+                Some((scratch, at(2).immediate(1))),
+            );
+        return emulated || borrowed || single;
+    }
+    single
+}
+
+/// decode `original`, copied from `src`, next to its copy `relocated` placed at `dst` and
+/// check both do the same, branches and memory operands have to resolve to the same
+/// absolute addresses
+///
+/// the first original instruction which differs is reported as [`Error::TrampolineMismatch`]
+pub(crate) fn verify(
+    backend: &dyn MemoryBackend,
+    original: &[u8],
+    src: usize,
+    relocated: &CopyResult,
+    dst: usize,
+) -> Result<(), Error> {
+    verify_with(backend, inst::BITNESS, original, src, relocated, dst)
+}
+
+/// [`verify`] for code of `bitness`, which may differ from the current process
+pub(crate) fn verify_with(
+    backend: &dyn MemoryBackend,
+    bitness: u32,
+    original: &[u8],
+    src: usize,
+    relocated: &CopyResult,
+    dst: usize,
+) -> Result<(), Error> {
+    if original.len() != relocated.len {
+        return Err(Error::TrampolineMismatch(src));
+    }
+    // branches into the moved instructions have to reach their copies
+    let translate = |target: u64| {
+        (target as usize)
+            .checked_sub(src)
+            .and_then(|x| relocated.translate(x))
+            .map_or(target, |x| (dst + x) as u64)
+    };
+    let ends = relocated
+        .offsets
+        .iter()
+        .skip(1)
+        .copied()
+        .chain([(relocated.len, relocated.code.len())]);
+    for (&(from, to), (from_end, to_end)) in relocated.offsets.iter().zip(ends) {
+        let addr = src + from;
+        let (Some(source), Some(copy)) =
+            (original.get(from..from_end), relocated.code.get(to..to_end))
+        else {
+            return Err(Error::TrampolineMismatch(addr));
+        };
+        let source: Vec<Instruction> =
+            Decoder::with_ip(bitness, source, addr as u64, DecoderOptions::NONE)
+                .iter()
+                .collect();
+        let ip = (dst + to) as u64;
+        if !same_group(backend, bitness, &source, copy, ip, &translate) {
+            return Err(Error::TrampolineMismatch(addr));
+        }
+    }
+    Ok(())
+}
+
+/// relocate at least `min_len` bytes of whole instructions at `src` to run at `dst`, like
+/// `DetourCopyInstruction`
+///
+/// nothing is written, [`CopyResult::code`] goes to `dst` and execution continues in the
+/// source `len` bytes after `src`. Instructions which can't be moved are reported as
+/// [`Error::Unrelocatable`]
+///
+/// # Safety
+///
+/// `src` must point to readable code of the current process
+pub unsafe fn copy_instructions(
+    src: *const c_void,
+    dst: *const c_void,
+    min_len: usize,
+) -> Result<CopyResult, Error> {
+    // room for the last instruction and a `pop` moved along with a position load
+    let mut code = vec![0u8; min_len + 0x20];
+    let len = inst::read_code(&ProcessMemory, src.addr(), &mut code);
+    relocate(
+        &ProcessMemory,
+        &code[..len],
+        src.addr(),
+        dst.addr(),
+        min_len,
+    )
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::platform::{MEM_TYPE_COMMIT, MEM_TYPE_RESERVE, valloc};
+    use iced_x86::Encoder;
+    use std::sync::OnceLock;
+
+    const KEY: u32 = 0x5a5a_1234;
+
+    /// RWX page at a low address, far from anything mapped by default
+    fn low_page() -> usize {
+        (0..64)
+            .map(|i| 0x1000_0000 + i * 0x10_0000)
+            .find_map(|addr| valloc(addr as *const _, 0x1000, MEM_TYPE_COMMIT | MEM_TYPE_RESERVE))
+            .unwrap() as usize
+    }
+
+    /// RWX page wherever the kernel likes, high above `low_page`
+    fn high_page() -> usize {
+        valloc(std::ptr::null(), 0x1000, MEM_TYPE_COMMIT | MEM_TYPE_RESERVE).unwrap() as usize
+    }
+
+    fn encode(insts: &[Instruction], ip: usize) -> Vec<u8> {
+        let mut encoder = Encoder::new(64);
+        let mut at = ip as u64;
+        for inst in insts {
+            at += encoder.encode(inst, at).unwrap() as u64;
+        }
+        encoder.take_buffer()
+    }
+
+    fn rip(addr: usize) -> MemoryOperand {
+        MemoryOperand::with_base_displ(Register::RIP, addr as i64)
+    }
+
+    fn poke(addr: usize, bytes: &[u8]) {
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), addr as *mut u8, bytes.len()) };
+    }
+
+    /// run `pre`, the relocated `code` and `post` at `dst` as a function returning rax
+    fn run(dst: usize, pre: &[Instruction], code: &[u8], post: &[Instruction]) -> u64 {
+        let head = encode(pre, dst);
+        let copy = relocate(&ProcessMemory, code, src(), dst + head.len(), 1).unwrap();
+        assert_eq!(copy.len, code.len());
+        let tail = encode(post, dst + head.len() + copy.code.len());
+        poke(dst, &[head, copy.code, tail].concat());
+        let f: extern "C" fn() -> u64 = unsafe { std::mem::transmute(dst) };
+        f()
+    }
+
+    /// address the original code claims to live at, near a far data page
+    fn src() -> usize {
+        static SRC: OnceLock<usize> = OnceLock::new();
+        *SRC.get_or_init(|| high_page() + 0x800)
+    }
+
+    fn store_key(displ: i64) -> Instruction {
+        let slot = MemoryOperand::with_base_displ(Register::RSP, displ);
+        Instruction::with2(Code::Mov_rm32_imm32, slot, KEY).unwrap()
+    }
+
+    fn load_key(displ: i64) -> Instruction {
+        let slot = MemoryOperand::with_base_displ(Register::RSP, displ);
+        Instruction::with2(Code::Mov_r32_rm32, Register::EAX, slot).unwrap()
+    }
+
+    fn ret() -> Instruction {
+        Instruction::with(Code::Retnq)
+    }
+
+    #[test]
+    fn far_load_through_scratch_register() {
+        let data = src() - 0x800;
+        poke(data, &0x1122_3344u32.to_le_bytes());
+        let load = Instruction::with2(Code::Mov_r32_rm32, Register::EAX, rip(data)).unwrap();
+        let code = encode(&[load], src());
+        assert_eq!(run(low_page(), &[], &code, &[ret()]), 0x1122_3344);
+    }
+
+    #[test]
+    fn scratch_register_keeps_red_zone() {
+        let data = src() - 0x800;
+        let load = Instruction::with2(Code::Mov_r32_rm32, Register::ECX, rip(data)).unwrap();
+        let code = encode(&[load], src());
+        for displ in [-8, -64, -128] {
+            let got = run(
+                low_page(),
+                &[store_key(displ)],
+                &code,
+                &[load_key(displ), ret()],
+            );
+            assert_eq!(got, u64::from(KEY), "red zone at rsp{displ}");
+        }
+    }
+
+    #[test]
+    fn far_jump_keeps_red_zone() {
+        let slot = src() - 0x800;
+        let landing = low_page();
+        poke(landing, &encode(&[load_key(-8), ret()], landing));
+        poke(slot, &(landing as u64).to_le_bytes());
+
+        let jmp = Instruction::with1(Code::Jmp_rm64, rip(slot)).unwrap();
+        let code = encode(&[jmp], src());
+        assert_eq!(
+            run(low_page(), &[store_key(-8)], &code, &[]),
+            u64::from(KEY)
+        );
+    }
+
+    #[test]
+    fn far_call_returns_after_copy() {
+        let slot = src() - 0x800;
+        let callee = low_page();
+        let seven = Instruction::with2(Code::Mov_r32_imm32, Register::EAX, 7u32).unwrap();
+        poke(callee, &encode(&[seven, ret()], callee));
+        poke(slot, &(callee as u64).to_le_bytes());
+
+        let call = Instruction::with1(Code::Call_rm64, rip(slot)).unwrap();
+        let code = encode(&[call], src());
+        let add = Instruction::with2(Code::Add_EAX_imm32, Register::EAX, 1u32).unwrap();
+        assert_eq!(run(low_page(), &[], &code, &[add, ret()]), 8);
+    }
+
+    #[test]
+    fn stack_relative_far_operand_is_unrelocatable() {
+        let push = Instruction::with1(Code::Push_rm64, rip(src() - 0x800)).unwrap();
+        let code = encode(&[push], src());
+        let copied = relocate(&ProcessMemory, &code, src(), low_page(), 1);
+        assert!(matches!(copied, Err(Error::Unrelocatable(addr)) if addr == src()));
+    }
+
+    // sample prologues, all relocated from `PROLOGUE_SRC`
+    const PROLOGUE_SRC: usize = 0x7fff_1234_0000;
+    const PROLOGUES: &[&[u8]] = &[
+        // push rbp; mov rbp, rsp; sub rsp, 0x20
+        &[0x55, 0x48, 0x89, 0xe5, 0x48, 0x83, 0xec, 0x20],
+        // mov [rsp + 8], rbx; push rdi; sub rsp, 0x20
+        &[0x48, 0x89, 0x5c, 0x24, 0x08, 0x57, 0x48, 0x83, 0xec, 0x20],
+        // lea rax, [rip + 0x100]; ret
+        &[0x48, 0x8d, 0x05, 0x00, 0x01, 0x00, 0x00, 0xc3],
+        // mov eax, [rip + 0x7ffffff0]
+        &[0x8b, 0x05, 0xf0, 0xff, 0xff, 0x7f],
+        // jz +0x10; nop x 3
+        &[0x74, 0x10, 0x90, 0x90, 0x90],
+        // xor eax, eax; jz +1; nop; ret
+        &[0x31, 0xc0, 0x74, 0x01, 0x90, 0xc3],
+        // call +0x40000000
+        &[0xe8, 0x00, 0x00, 0x00, 0x40],
+        // jmp +0x40000000
+        &[0xe9, 0x00, 0x00, 0x00, 0x40],
+        // jnz +0x40000000
+        &[0x0f, 0x85, 0x00, 0x00, 0x00, 0x40],
+        // jmp [rip + 0x40000000]
+        &[0xff, 0x25, 0x00, 0x00, 0x00, 0x40],
+        // call [rip + 0x40000000]
+        &[0xff, 0x15, 0x00, 0x00, 0x00, 0x40],
+        // cmp [rip + 0x40000000], ebx
+        &[0x39, 0x1d, 0x00, 0x00, 0x00, 0x40],
+        // loop +0x10; jrcxz +0x10; nop
+        &[0xe2, 0x10, 0xe3, 0x10, 0x90],
+    ];
+    // in reach of every operand and branch above, and far from all of them
+    const NEAR_DST: usize = PROLOGUE_SRC - 0x10_0000;
+    const FAR_DST: usize = 0x1000_0000;
+
+    fn relocate_prologue(code: &[u8], dst: usize) -> CopyResult {
+        relocate(&ProcessMemory, code, PROLOGUE_SRC, dst, code.len()).unwrap()
+    }
+
+    #[test]
+    fn relocated_prologues_verify() {
+        for code in PROLOGUES {
+            for dst in [NEAR_DST, FAR_DST] {
+                let copy = relocate_prologue(code, dst);
+                assert_eq!(copy.len, code.len());
+                let verified = verify(&ProcessMemory, code, PROLOGUE_SRC, &copy, dst);
+                assert!(verified.is_ok(), "{code:02x?} at {dst:#x}: {verified:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn verify_catches_corruption() {
+        let mismatch = |code: &[u8], dst: usize, at: usize| {
+            let mut copy = relocate_prologue(code, dst);
+            copy.code[at] ^= 1;
+            verify(&ProcessMemory, code, PROLOGUE_SRC, &copy, dst)
+        };
+        let lea = PROLOGUES[2];
+        assert!(matches!(
+            mismatch(lea, NEAR_DST, 3),
+            Err(Error::TrampolineMismatch(PROLOGUE_SRC))
+        ));
+        // literal of the far jump
+        assert!(mismatch(PROLOGUES[7], FAR_DST, 6).is_err());
+        // condition of the widened jz
+        assert!(mismatch(PROLOGUES[4], NEAR_DST, 1).is_err());
+        // register borrowed for the far load
+        assert!(mismatch(PROLOGUES[3], FAR_DST, 6).is_err());
+
+        // source and copy covering different lengths
+        let mut copy = relocate_prologue(lea, NEAR_DST);
+        copy.len -= 1;
+        assert!(verify(&ProcessMemory, lea, PROLOGUE_SRC, &copy, NEAR_DST).is_err());
+    }
+
+    fn mov_eax(value: u32) -> Instruction {
+        Instruction::with2(Code::Mov_r32_imm32, Register::EAX, value).unwrap()
+    }
+
+    fn mov_ecx(value: u32) -> Instruction {
+        Instruction::with2(Code::Mov_r32_imm32, Register::ECX, value).unwrap()
+    }
+
+    /// `mov eax, 1; ret` in a page near `src`, out of reach of `low_page`
+    fn far_landing() -> usize {
+        let landing = high_page();
+        assert!(landing.abs_diff(src()) < REACH as usize);
+        poke(landing, &encode(&[mov_eax(1), ret()], landing));
+        landing
+    }
+
+    /// branch taken lands on `far_landing` returning 1, falling through returns 2
+    fn branch(code: Code, pre: &[Instruction]) -> u64 {
+        let inst = Instruction::with_branch(code, far_landing() as u64).unwrap();
+        let raw = encode(&[inst], src());
+        run(low_page(), pre, &raw, &[mov_eax(2), ret()])
+    }
+
+    #[test]
+    fn far_branches_through_literal() {
+        assert_eq!(branch(Code::Jmp_rel32_64, &[]), 1);
+        let zero = Instruction::with2(Code::Xor_r32_rm32, Register::EAX, Register::EAX).unwrap();
+        let one = Instruction::with2(Code::Cmp_EAX_imm32, Register::EAX, 1u32).unwrap();
+        assert_eq!(branch(Code::Je_rel32_64, &[zero]), 1);
+        assert_eq!(branch(Code::Je_rel32_64, &[mov_eax(0), one]), 2);
+        assert_eq!(branch(Code::Jne_rel32_64, &[mov_eax(0), one]), 1);
+    }
+
+    #[test]
+    fn far_call_through_literal_returns_after_copy() {
+        let callee = far_landing();
+        let call = Instruction::with_branch(Code::Call_rel32_64, callee as u64).unwrap();
+        let raw = encode(&[call], src());
+        let add = Instruction::with2(Code::Add_EAX_imm32, Register::EAX, 0x10u32).unwrap();
+        assert_eq!(run(low_page(), &[], &raw, &[add, ret()]), 0x11);
+    }
+
+    #[test]
+    fn short_only_branches_through_literal() {
+        let landing = far_landing();
+        // rel8 can't reach the landing page from `src`, the literal is patched to it
+        let short = |op: u8, pre: Vec<Instruction>| {
+            let raw = [op, 0x10];
+            let dst = low_page();
+            let head = encode(&pre, dst);
+            let mut copy = relocate(&ProcessMemory, &raw, src(), dst + head.len(), 1).unwrap();
+            let at = copy.code.len() - 8;
+            copy.code[at..].copy_from_slice(&(landing as u64).to_le_bytes());
+            let tail = encode(&[mov_eax(2), ret()], dst + head.len() + copy.code.len());
+            poke(dst, &[head, copy.code, tail].concat());
+            let f: extern "C" fn() -> u64 = unsafe { std::mem::transmute(dst) };
+            f()
+        };
+        assert_eq!(short(0xe2, vec![mov_ecx(2)]), 1);
+        assert_eq!(short(0xe2, vec![mov_ecx(1)]), 2);
+        assert_eq!(short(0xe3, vec![mov_ecx(0)]), 1);
+        assert_eq!(short(0xe3, vec![mov_ecx(3)]), 2);
+    }
+
+    #[test]
+    fn position_load_gets_original_address() {
+        // call $+5; pop rax
+        let raw = [0xe8, 0, 0, 0, 0, 0x58];
+        let copy = relocate(&ProcessMemory, &raw, src(), low_page(), 1).unwrap();
+        assert_eq!(copy.len, 6);
+        assert_eq!(run(low_page(), &[], &raw, &[ret()]), src() as u64 + 5);
+    }
+
+    #[test]
+    fn pc_thunk_prologue_gets_original_address() {
+        let src = low_page();
+        let dst = low_page();
+        // call __x86.get_pc_thunk.bx; push ebp, the thunk is mov ebx, [esp]; ret
+        let code = [0xe8, 0x1b, 0, 0, 0, 0x55];
+        poke(src, &code);
+        poke(src + 0x20, &[0x8b, 0x1c, 0x24, 0xc3]);
+        let copy = relocate_with(&ProcessMemory, 32, &code, src, dst, 5).unwrap();
+        assert_eq!(copy.len, 5);
+        let load = Decoder::with_ip(32, &copy.code, dst as u64, DecoderOptions::NONE).decode();
+        assert_eq!(load.code(), Code::Mov_r32_imm32);
+        assert_eq!(load.op0_register(), Register::EBX);
+        assert_eq!(load.immediate32() as usize, src + 5);
+        verify_with(&ProcessMemory, 32, &code[..5], src, &copy, dst).unwrap();
+
+        // mov eax, original address loads another register than the thunk
+        let mut other = copy.clone();
+        other.code[0] = 0xb8;
+        assert!(verify_with(&ProcessMemory, 32, &code[..5], src, &other, dst).is_err());
+        // the thunk now loads eax, the copy still loads ebx
+        poke(src + 0x20, &[0x8b, 0x04, 0x24, 0xc3]);
+        assert!(verify_with(&ProcessMemory, 32, &code[..5], src, &copy, dst).is_err());
+    }
+
+    #[test]
+    fn offsets_follow_widened_branch() {
+        // push rbp; jz +0x10; mov rbp, rsp
+        let raw = [0x55, 0x74, 0x10, 0x48, 0x89, 0xe5];
+        let copy = relocate_prologue(&raw, NEAR_DST);
+        assert_eq!(copy.offsets, [(0, 0), (1, 1), (3, 7)]);
+        assert_eq!(copy.code.len(), 10);
+        assert_eq!(copy.translate(3), Some(7));
+        assert_eq!(copy.translate(2), None);
+
+        // a far jcc grows into the inverted short jump over a literal jump
+        let copy = relocate_prologue(&raw, FAR_DST);
+        assert_eq!(copy.offsets, [(0, 0), (1, 1), (3, 17)]);
+    }
+
+    #[test]
+    fn copy_instructions_runs_elsewhere() {
+        // mov eax, edi; lea eax, [rax + rax * 2 + 1]; ret
+        let page = high_page();
+        poke(page, &[0x89, 0xf8, 0x8d, 0x44, 0x40, 0x01, 0xc3]);
+        let cave = low_page();
+        let copy = unsafe { copy_instructions(page as *const _, cave as *const _, 5) }.unwrap();
+        assert_eq!((copy.len, copy.code.len()), (6, 6));
+        assert!(!copy.uses_ret);
+
+        let back = [&JMP_LITERAL[..], &(page as u64 + 6).to_le_bytes()].concat();
+        poke(cave, &[copy.code, back].concat());
+        let f: extern "C" fn(u32) -> u32 = unsafe { std::mem::transmute(cave) };
+        assert_eq!(f(2), 7);
+    }
+}
@@ -1,14 +1,24 @@
+use crate::alloc::AllocationStrategy;
+use crate::backend::{MemoryBackend, MemoryProtector, ProcessMemory};
+use crate::copy::CopyResult;
 use crate::ext::Pointer;
-use crate::mem::{Block, DETOUR_REGION_SIZE, Regions};
-use crate::platform::{MemoryProtector, detour_skip_jmp};
-use crate::platform::{NEEDED_BYTES, detour_does_code_end_function, detour_gen_jmp_immediate};
-use crate::{Error, inst};
+use crate::mem::{Block, DETOUR_REGION_SIZE, RegionMode, RegionStats, Regions};
+use crate::platform::detour_find_jmp_bounds;
+use crate::platform::{
+    NEEDED_BYTES, NEEDED_BYTES_ABSOLUTE, detour_gen_jmp_absolute, detour_gen_jmp_immediate_at,
+};
+use crate::thunk::code_from_pointer;
+use crate::{Error, copy, inst};
 use fnv::FnvHashMap;
+use iced_x86::code_asm::CodeAssembler;
+use iced_x86::{Code, IcedError, Instruction};
 use std::ffi::c_void;
-use std::ptr;
+use std::sync::{Arc, Mutex};
 
 pub struct Detours {
     regions: Regions<BLOCK_COUNT>,
+    // code caves hold on to their regions, see `CodeCave`
+    caves: Arc<Mutex<Regions<BLOCK_COUNT>>>,
     detours: FnvHashMap<usize, Detour>,
 }
 
@@ -20,8 +30,18 @@ impl Default for Detours {
 
 impl Detours {
     pub fn new() -> Detours {
+        Self::with_region_mode(RegionMode::default())
+    }
+
+    pub fn with_region_mode(mode: RegionMode) -> Detours {
+        Self::with_backend(mode, Arc::new(ProcessMemory))
+    }
+
+    /// use another memory backend, e.g. [`crate::backend::SimulatedMemory`] in tests
+    pub fn with_backend(mode: RegionMode, backend: Arc<dyn MemoryBackend>) -> Detours {
         Detours {
-            regions: Regions::new(),
+            regions: Regions::new(mode, backend.clone()),
+            caves: Arc::new(Mutex::new(Regions::new(mode, backend))),
             detours: FnvHashMap::default(),
         }
     }
@@ -30,9 +50,165 @@ impl Detours {
         self.detours.get(address)
     }
 
+    /// where new trampoline regions are placed, only affects regions allocated afterwards
+    pub fn set_allocation_strategy(&mut self, strategy: impl AllocationStrategy + 'static) {
+        self.regions.set_strategy(Box::new(strategy));
+    }
+
+    /// pre-commit `count` trampoline regions near every target in `near`
+    ///
+    /// call it before the process locks down dynamic code, later attaches to targets in reach
+    /// of `near` only take blocks from regions which already exist and fail with
+    /// `NotEnoughMemory` once they run out. Nothing is kept when a region can not be allocated
+    pub fn reserve(&mut self, near: &[usize], count: usize) -> Result<(), Error> {
+        self.regions.reserve(near, count)?;
+        self.regions.lock()
+    }
+
+    pub fn stats(&self) -> RegionStats {
+        self.regions.stats()
+    }
+
     pub fn lock(&mut self) -> Result<DetoursGuard<'_>, Error> {
         DetoursGuard::new(self)
     }
+
+    /// executable memory for at least `len` bytes of code in rel32 reach of `addr`
+    pub fn alloc_code_near(&self, addr: usize, len: usize) -> Result<CodeCave, Error> {
+        let blocks = len.div_ceil(size_of::<Trampoline>()).max(1);
+        // a span never crosses regions
+        if blocks > BLOCK_COUNT {
+            return Err(Error::NotEnoughMemory);
+        }
+        let mut caves = self.caves.lock().unwrap_or_else(|x| x.into_inner());
+        let block = caves
+            .alloc_span(addr, blocks)
+            .ok_or(Error::NotEnoughMemory)?;
+        let cave = CodeCave {
+            regions: self.caves.clone(),
+            block,
+            blocks,
+            replaced: None,
+        };
+        caves.lock()?;
+        Ok(cave)
+    }
+
+    /// assemble the instructions `build` adds for the address of a new cave near `addr`
+    ///
+    /// branches and rip relative operands are encoded for where the cave ends up
+    pub fn assemble_near(
+        &self,
+        addr: usize,
+        build: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+    ) -> Result<CodeCave, Error> {
+        let assembly = |err: IcedError| Error::Assembly(err.to_string());
+        let mut asm = CodeAssembler::new(inst::BITNESS).map_err(assembly)?;
+        build(&mut asm).map_err(assembly)?;
+
+        // size depends on the address, grow the cave until the code fits
+        let mut len = asm.assemble(addr as u64).map_err(assembly)?.len();
+        for _ in 0..4 {
+            let cave = self.alloc_code_near(addr, len)?;
+            let code = asm.assemble(cave.addr() as u64).map_err(assembly)?;
+            if code.len() > cave.size() {
+                len = code.len();
+                continue;
+            }
+            cave.write(0, &code)?;
+            return Ok(cave);
+        }
+        Err(Error::NotEnoughMemory)
+    }
+
+    /// replace `len` bytes at `addr` with the code `build` adds
+    ///
+    /// the code runs in a new cave which jumps back to `addr + len` after it, `addr` jumps
+    /// to the cave and the rest of the bytes become `int3`. Dropping the cave writes the
+    /// original bytes back
+    pub fn replace_code(
+        &self,
+        addr: usize,
+        len: usize,
+        build: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+    ) -> Result<CodeCave, Error> {
+        if len < NEEDED_BYTES {
+            return Err(Error::InvalidAddress);
+        }
+        let mut cave = self.assemble_near(addr, |asm| {
+            build(asm)?;
+            asm.jmp((addr + len) as u64)
+        })?;
+
+        let backend = self.regions.backend();
+        let mut original = vec![0u8; len];
+        backend.read(addr, &mut original)?;
+        let mut patch = vec![0xcc; len];
+        detour_gen_jmp_immediate_at(patch.as_mut_ptr(), addr as *mut _, cave.addr() as *mut _);
+        let guard = MemoryProtector::with_backend(backend, addr, len)?;
+        backend.write(addr, &patch)?;
+        drop(guard);
+        backend.flush_instruction_cache(addr, len)?;
+        cave.replaced = Some((addr, original));
+        Ok(cave)
+    }
+}
+
+/// executable memory from [`Detours::alloc_code_near`], released on drop
+///
+/// the cave keeps its region alive, it stays valid after the `Detours` it came from drops.
+/// Code running in it must have left before it drops
+pub struct CodeCave {
+    regions: Arc<Mutex<Regions<BLOCK_COUNT>>>,
+    block: Block<Trampoline>,
+    blocks: usize,
+    // bytes [`Detours::replace_code`] wrote a jump over, restored on drop
+    replaced: Option<(usize, Vec<u8>)>,
+}
+
+impl CodeCave {
+    /// address the code runs at
+    pub fn addr(&self) -> usize {
+        self.block.exec_ptr().addr()
+    }
+
+    /// usable size, `len` rounded up to whole blocks
+    pub fn size(&self) -> usize {
+        self.blocks * size_of::<Trampoline>()
+    }
+
+    /// write `code` at `offset`
+    pub fn write(&self, offset: usize, code: &[u8]) -> Result<(), Error> {
+        if offset
+            .checked_add(code.len())
+            .is_none_or(|end| end > self.size())
+        {
+            return Err(Error::InvalidAddress);
+        }
+        let mut regions = self.regions.lock().unwrap_or_else(|x| x.into_inner());
+        regions.unlock()?;
+        let written = regions
+            .backend()
+            .write(self.block.write_ptr().addr() + offset, code);
+        regions.lock()?;
+        written
+    }
+}
+
+impl Drop for CodeCave {
+    fn drop(&mut self) {
+        let mut regions = self.regions.lock().unwrap_or_else(|x| x.into_inner());
+        if let Some((addr, original)) = self.replaced.take() {
+            let backend = regions.backend();
+            if let Ok(guard) = MemoryProtector::with_backend(backend, addr, original.len()) {
+                let _ = backend.write(addr, &original);
+                drop(guard);
+                let _ = backend.flush_instruction_cache(addr, original.len());
+            }
+        }
+        regions.free_span(&mut self.block, self.blocks);
+        let _ = regions.lock();
+    }
 }
 
 impl Drop for Detours {
@@ -46,64 +222,274 @@ impl Drop for Detours {
 
 const PREFETCH_INST_SIZE: usize = 0x20;
 
+// landing pad placed at the start of trampolines and relays, indirect branches into them
+// must hit one under IBT
+#[cfg(target_pointer_width = "64")]
+const ENDBR: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+#[cfg(not(target_pointer_width = "64"))]
+const ENDBR: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfb];
+
+/// size of an `endbr64`/`endbr32` which `inst` is, otherwise zero
+fn landing_pad(inst: &Instruction) -> usize {
+    match inst.code() {
+        Code::Endbr64 | Code::Endbr32 => inst.len(),
+        _ => 0,
+    }
+}
+
 const BLOCK_COUNT: usize = DETOUR_REGION_SIZE / size_of::<Trampoline>();
 
 #[repr(C)]
 pub struct Trampoline([u8; PREFETCH_INST_SIZE]);
 
+/// how target and trampoline jump to each other
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JumpKind {
+    /// `jmp rel32`, trampoline lies in reach of the target
+    Near,
+    /// absolute jump, used when no trampoline memory is in reach,
+    /// needs a longer prologue
+    Absolute,
+}
+
+impl JumpKind {
+    fn needed_bytes(&self) -> usize {
+        match self {
+            JumpKind::Near => NEEDED_BYTES,
+            JumpKind::Absolute => NEEDED_BYTES_ABSOLUTE,
+        }
+    }
+}
+
 pub struct Detour {
     target: usize,
+    // landing pad left in place at the start of target
+    pad: usize,
     fetch: usize,
+    // code moved out of target, the trampoline holds it relocated
+    original: Vec<u8>,
+    relocated: CopyResult,
+    jump: JumpKind,
+    backend: Arc<dyn MemoryBackend>,
     block: Block<Trampoline>,
+    // absolute jump to detour placed near target when detour is out of rel32 reach
+    relay: Option<Block<Trampoline>>,
 }
 
 impl Detour {
+    /// write trampoline, relay and the jump at target, the blocks are freed on failure
     pub(crate) fn patch(
+        regions: &mut Regions<BLOCK_COUNT>,
         target: *const c_void,
         detour: *const c_void,
         mut block: Block<Trampoline>,
+        jump: JumpKind,
+        mut relay: Option<Block<Trampoline>>,
     ) -> Result<Detour, Error> {
-        let mut fetch: usize = 0;
-
-        let mut decoder = inst::decoder_with_size(target, PREFETCH_INST_SIZE);
-        for inst in decoder.iter() {
-            fetch += inst.len();
-            if fetch >= NEEDED_BYTES {
-                break;
-            }
-            if detour_does_code_end_function(&inst) {
-                break;
+        match Self::write(
+            regions.backend(),
+            target,
+            detour,
+            &block,
+            jump,
+            relay.as_ref(),
+        ) {
+            Ok((pad, original, relocated)) => Ok(Detour {
+                target: target.addr(),
+                pad,
+                fetch: relocated.len,
+                original,
+                relocated,
+                jump,
+                backend: regions.shared_backend(),
+                block,
+                relay,
+            }),
+            Err(err) => {
+                regions.free_block(&mut block);
+                if let Some(relay) = relay.as_mut() {
+                    regions.free_block(relay);
+                }
+                Err(err)
             }
         }
+    }
+
+    /// returns landing pad size, the original bytes moved and their relocated copy
+    fn write(
+        backend: &dyn MemoryBackend,
+        target: *const c_void,
+        detour: *const c_void,
+        block: &Block<Trampoline>,
+        jump: JumpKind,
+        relay: Option<&Block<Trampoline>>,
+    ) -> Result<(usize, Vec<u8>, CopyResult), Error> {
+        let needed = jump.needed_bytes();
 
-        if fetch < NEEDED_BYTES {
+        let mut code = [0u8; PREFETCH_INST_SIZE];
+        let len = inst::read_code(backend, target.addr(), &mut code);
+        // a leading endbr stays, indirect calls under IBT must still land on it
+        let pad = landing_pad(&inst::decoder_for(&code[..len], target.addr()).decode()).min(len);
+        let patched = target.wrapping_byte_add(pad);
+
+        // trampoline and patches are built locally then written through backend,
+        // code may live in an alias mapping or another process
+        let rb_code = block.exec_ptr();
+        let copied = copy::relocate(
+            backend,
+            &code[pad..len],
+            patched.addr(),
+            rb_code.addr() + ENDBR.len(),
+            needed,
+        )?;
+        let fetch = copied.len;
+        // endbr marks a CET build, which may run with shadow stacks, returning into code
+        // which was never called faults there
+        if copied.uses_ret && pad != 0 {
+            return Err(Error::ShadowStackIncompatible);
+        }
+        let moved = ENDBR.len() + copied.code.len();
+        if moved + needed > PREFETCH_INST_SIZE {
             return Err(Error::InvalidAddress);
         }
 
-        let rb_code = block.as_mut() as *mut Trampoline;
+        let mut trampoline = Trampoline([0xcc; PREFETCH_INST_SIZE]);
+        trampoline.0[..ENDBR.len()].copy_from_slice(&ENDBR);
+        trampoline.0[ENDBR.len()..moved].copy_from_slice(&copied.code);
+        let rb_jmp = trampoline.0[moved..].as_mut_ptr();
+        match jump {
+            JumpKind::Near => detour_gen_jmp_immediate_at(
+                rb_jmp,
+                rb_code.wrapping_byte_add(moved).cast(),
+                patched.wrapping_byte_add(fetch) as *mut _,
+            ),
+            JumpKind::Absolute => detour_gen_jmp_absolute(
+                rb_jmp,
+                rb_code.wrapping_byte_add(moved).cast(),
+                patched.wrapping_byte_add(fetch) as *mut _,
+            ),
+        }
+        backend.write(block.write_ptr().addr(), &trampoline.0)?;
+        // checked before anything jumps into it
+        if cfg!(debug_assertions) {
+            let original = &code[pad..pad + fetch];
+            check_trampoline(backend, rb_code.addr(), original, patched.addr(), &copied)?;
+        }
 
-        unsafe {
-            ptr::copy(target.cast::<u8>(), rb_code.cast(), fetch);
+        let detour = match relay {
+            Some(relay) => {
+                let mut stub = Trampoline([0xcc; PREFETCH_INST_SIZE]);
+                stub.0[..ENDBR.len()].copy_from_slice(&ENDBR);
+                detour_gen_jmp_absolute(
+                    stub.0[ENDBR.len()..].as_mut_ptr(),
+                    relay.exec_ptr().wrapping_byte_add(ENDBR.len()).cast(),
+                    detour as *mut _,
+                );
+                backend.write(relay.write_ptr().addr(), &stub.0)?;
+                relay.exec_ptr() as *const c_void
+            }
+            None => detour,
+        };
+
+        let mut patch = [0u8; PREFETCH_INST_SIZE];
+        match jump {
+            JumpKind::Near => {
+                detour_gen_jmp_immediate_at(patch.as_mut_ptr(), patched as *mut _, detour as *mut _)
+            }
+            JumpKind::Absolute => {
+                detour_gen_jmp_absolute(patch.as_mut_ptr(), patched as *mut _, detour as *mut _)
+            }
         }
 
-        let rb_code = rb_code.wrapping_byte_add(fetch);
+        let _guard_origin = MemoryProtector::with_backend(backend, patched.addr(), fetch)?;
+        backend.write(patched.addr(), &patch[..needed])?;
 
-        detour_gen_jmp_immediate(rb_code.cast(), target.wrapping_byte_add(fetch) as *mut _);
+        Ok((pad, code[pad..pad + fetch].to_vec(), copied))
+    }
 
-        let _guard_origin = MemoryProtector::new(target.addr(), fetch)?;
+    pub fn target(&self) -> usize {
+        self.target
+    }
 
-        detour_gen_jmp_immediate(target as *mut _, detour as *mut _);
+    /// size of the `endbr` kept at the start of target, the jump is written after it
+    pub fn landing_pad(&self) -> usize {
+        self.pad
+    }
 
-        Ok(Detour {
-            target: target.addr(),
-            block,
-            fetch,
-        })
+    /// bytes of original code moved into the trampoline
+    pub fn fetch(&self) -> usize {
+        self.fetch
+    }
+
+    pub fn jump_kind(&self) -> JumpKind {
+        self.jump
+    }
+
+    /// target jumps to a relay stub instead of the detour itself
+    pub fn has_relay(&self) -> bool {
+        self.relay.is_some()
+    }
+
+    /// read the trampoline back and compare it with the moved instructions, operand by operand
+    ///
+    /// branch targets and memory operands have to resolve to the same absolute addresses and
+    /// the trampoline has to jump back behind the moved code, attach checks every trampoline
+    /// with it in debug builds before the target jumps there
+    pub fn verify(&self) -> Result<(), Error> {
+        check_trampoline(
+            &*self.backend,
+            self.block.exec_ptr().addr(),
+            &self.original,
+            self.target + self.pad,
+            &self.relocated,
+        )
     }
 
     pub fn trampoline<T>(&self) -> &T {
-        unsafe { std::mem::transmute::<_, &T>(&self.block.as_ref()) }
+        // slot holds trampoline address, read through it as function pointer
+        unsafe { &*(self.block.exec_slot() as *const _ as *const T) }
+    }
+}
+
+/// compare the trampoline at `exec`, read through `backend`, with the `original` code at
+/// `patched` it stands for
+fn check_trampoline(
+    backend: &dyn MemoryBackend,
+    exec: usize,
+    original: &[u8],
+    patched: usize,
+    relocated: &CopyResult,
+) -> Result<(), Error> {
+    let mismatch = Error::TrampolineMismatch(patched);
+    let mut trampoline = [0u8; PREFETCH_INST_SIZE];
+    backend.read(exec, &mut trampoline)?;
+    let moved = ENDBR.len() + relocated.code.len();
+    let Some(code) = trampoline.get(ENDBR.len()..moved) else {
+        return Err(mismatch);
+    };
+    if trampoline[..ENDBR.len()] != ENDBR {
+        return Err(mismatch);
     }
+    let written = CopyResult {
+        code: code.to_vec(),
+        ..relocated.clone()
+    };
+    copy::verify(backend, original, patched, &written, exec + ENDBR.len())?;
+
+    // execution continues behind the moved instructions
+    let back = (patched + relocated.len) as u64;
+    let jmp = inst::decoder_for(&trampoline[moved..], exec + moved).decode();
+    let lands = match jmp.code() {
+        Code::Jmp_rel32_32 | Code::Jmp_rel32_64 => jmp.near_branch_target() == back,
+        Code::Jmp_rm64 if jmp.is_ip_rel_memory_operand() => {
+            let at = (jmp.ip_rel_memory_address() as usize).wrapping_sub(exec);
+            trampoline
+                .get(at..at.saturating_add(8))
+                .is_some_and(|x| x == back.to_le_bytes())
+        }
+        _ => false,
+    };
+    if lands { Ok(()) } else { Err(mismatch) }
 }
 
 pub struct DetoursGuard<'a> {
@@ -111,21 +497,24 @@ pub struct DetoursGuard<'a> {
 }
 
 impl DetoursGuard<'_> {
-    fn new(detours: &'_ mut Detours) -> Result<DetoursGuard, Error> {
+    fn new(detours: &'_ mut Detours) -> Result<DetoursGuard<'_>, Error> {
         detours.regions.unlock()?;
         Ok(DetoursGuard { detours })
     }
 
     pub(crate) fn internal_detach(regions: &mut Regions<BLOCK_COUNT>, detour: &mut Detour) {
-        let Ok(mut mem) = MemoryProtector::new(detour.target, detour.fetch) else {
+        let backend = regions.backend();
+        let patched = detour.target + detour.pad;
+        let Ok(mem) = MemoryProtector::with_backend(backend, patched, detour.fetch) else {
             return;
         };
-
-        unsafe {
-            mem.write_from_with_size(detour.block.as_ref() as *const Trampoline, detour.fetch)
-        };
+        let _ = backend.write(patched, &detour.original);
+        drop(mem);
 
         regions.free_block(&mut detour.block);
+        if let Some(relay) = detour.relay.as_mut() {
+            regions.free_block(relay);
+        }
     }
 
     pub fn attach_ptr<const ADDR: usize, T>(
@@ -141,8 +530,13 @@ impl DetoursGuard<'_> {
             return Err(Error::InvalidAddress);
         }
 
-        let target = detour_skip_jmp(inst::decoder(target).decode()) as *const c_void;
-        let detour = detour_skip_jmp(inst::decoder(detour).decode()) as *const c_void;
+        // following import jumps reads memory in place, only possible in the current process
+        let backend = self.detours.regions.backend();
+        let (target, detour) = if backend.is_local() {
+            (code_from_pointer(target), code_from_pointer(detour))
+        } else {
+            (target, detour)
+        };
 
         if target.addr() == detour.addr() {
             return Err(Error::InvalidAddress);
@@ -152,12 +546,37 @@ impl DetoursGuard<'_> {
 
         self.detach(&target_addr);
 
-        let Some(block) = self.detours.regions.alloc_block(target.cast()) else {
-            return Err(Error::NotEnoughMemory);
+        // fall back to absolute jumps when nothing is free in rel32 reach
+        let (mut block, jump) = match self.detours.regions.alloc_block(target.cast()) {
+            Some(block) => (block, JumpKind::Near),
+            None => match self.detours.regions.alloc_block_anywhere(target.cast()) {
+                Some(block) => (block, JumpKind::Absolute),
+                None => return Err(Error::NotEnoughMemory),
+            },
         };
 
-        let detour = Detour::patch(target, detour, block);
-        self.detours.detours.insert(target.addr(), detour?);
+        // detour out of reach needs a relay next to the target, like Detours rbCodeIn
+        let first = inst::decode_from(self.detours.regions.backend(), target.addr());
+        let bound = detour_find_jmp_bounds(&first);
+        let relay = if jump == JumpKind::Near && !bound.contains(&detour.addr()) {
+            let Some(relay) = self.detours.regions.alloc_block(target.cast()) else {
+                self.detours.regions.free_block(&mut block);
+                return Err(Error::NotEnoughMemory);
+            };
+            Some(relay)
+        } else {
+            None
+        };
+
+        let detour = Detour::patch(
+            &mut self.detours.regions,
+            target,
+            detour,
+            block,
+            jump,
+            relay,
+        )?;
+        self.detours.detours.insert(target.addr(), detour);
         Ok(())
     }
 
@@ -179,3 +598,448 @@ impl Drop for DetoursGuard<'_> {
         let _ = self.detours.regions.lock();
     }
 }
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::backend::{Protection, SimulatedMemory};
+    use crate::platform::{MEM_TYPE_COMMIT, MEM_TYPE_RESERVE, valloc, vfree};
+    use std::hint::black_box;
+
+    /// RWX page holding `code`, mapped wherever the kernel likes, far from this binary
+    fn code_page(code: &[u8]) -> usize {
+        let page = valloc(std::ptr::null(), 0x1000, MEM_TYPE_COMMIT | MEM_TYPE_RESERVE).unwrap();
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), page as *mut u8, code.len()) };
+        page as usize
+    }
+
+    #[inline(never)]
+    extern "C" fn scale(x: u32) -> u32 {
+        black_box(x).wrapping_mul(3).wrapping_add(black_box(1))
+    }
+
+    extern "C" fn scale_detour(x: u32) -> u32 {
+        x + 1000
+    }
+
+    #[test]
+    fn attach_and_detach_in_process() {
+        let target = scale as extern "C" fn(u32) -> u32;
+        let call = |x| black_box(target)(x);
+        assert_eq!(call(2), 7);
+
+        let mut detours = Detours::new();
+        let mut guard = detours.lock().unwrap();
+        guard
+            .attach(target as *const c_void, scale_detour as *const c_void)
+            .unwrap();
+        drop(guard);
+        assert_eq!(call(2), 1002);
+
+        let detour = detours.get(&(target as usize)).unwrap();
+        assert_eq!(detour.jump_kind(), JumpKind::Near);
+        assert!(!detour.has_relay());
+        let original = *detour.trampoline::<extern "C" fn(u32) -> u32>();
+        assert_eq!(original(2), 7);
+
+        detours.lock().unwrap().detach(&(target as usize));
+        assert_eq!(call(2), 7);
+    }
+
+    #[test]
+    fn relay_reaches_far_detour() {
+        let page = code_page(&SCALE);
+        let target: extern "C" fn(u32) -> u32 = unsafe { std::mem::transmute(page) };
+        assert!(page.abs_diff((scale_detour as *const c_void).addr()) > u32::MAX as usize);
+
+        let mut detours = Detours::new();
+        detours
+            .lock()
+            .unwrap()
+            .attach(page as *const c_void, scale_detour as *const c_void)
+            .unwrap();
+        assert_eq!(black_box(target)(2), 1002);
+        let detour = detours.get(&page).unwrap();
+        assert!(detour.has_relay());
+        assert_eq!(detour.fetch(), 6);
+        assert_eq!((*detour.trampoline::<extern "C" fn(u32) -> u32>())(2), 7);
+
+        drop(detours);
+        assert_eq!(black_box(target)(2), 7);
+        vfree(page as *mut c_void).unwrap();
+    }
+
+    #[test]
+    fn failed_patch_frees_blocks() {
+        // ret, too short to hold a jump
+        let page = code_page(&[0xc3]);
+        let mut detours = Detours::new();
+        let mut guard = detours.lock().unwrap();
+        let attached = guard.attach(page as *const c_void, scale_detour as *const c_void);
+        assert!(matches!(attached, Err(Error::InvalidAddress)));
+        drop(guard);
+        assert_eq!(detours.stats().used_blocks, 0);
+        vfree(page as *mut c_void).unwrap();
+    }
+
+    #[inline(never)]
+    extern "C" fn shift(x: u32) -> u32 {
+        black_box(x).wrapping_shl(black_box(2)) ^ black_box(0x55)
+    }
+
+    #[test]
+    fn attach_with_dual_mapped_regions() {
+        let target = shift as extern "C" fn(u32) -> u32;
+        let mut detours = Detours::with_region_mode(RegionMode::DualMapped);
+        detours
+            .lock()
+            .unwrap()
+            .attach(target as *const c_void, scale_detour as *const c_void)
+            .unwrap();
+        assert_eq!(black_box(target)(1), 1001);
+        let detour = detours.get(&(target as usize)).unwrap();
+        assert_eq!((*detour.trampoline::<extern "C" fn(u32) -> u32>())(1), 0x51);
+        drop(detours);
+        assert_eq!(black_box(target)(1), 0x51);
+    }
+
+    #[test]
+    fn replace_code_jumps_back_and_restores() {
+        // lea eax, [rdi + 1]; add eax, 2; ret
+        let code = [0x8d, 0x47, 0x01, 0x83, 0xc0, 0x02, 0xc3];
+        let page = code_page(&code);
+        let target: extern "C" fn(u32) -> u32 = unsafe { std::mem::transmute(page) };
+
+        let detours = Detours::new();
+        let cave = detours
+            .replace_code(page, 6, |asm| {
+                use iced_x86::code_asm::*;
+                asm.lea(eax, ptr(rdi + 100))
+            })
+            .unwrap();
+        assert!(cave.addr().abs_diff(page) < i32::MAX as usize);
+        assert_eq!(black_box(target)(1), 101);
+        assert_eq!(unsafe { *(page as *const u8).add(5) }, 0xcc);
+
+        drop(cave);
+        assert_eq!(
+            unsafe { std::slice::from_raw_parts(page as *const u8, 7) },
+            code
+        );
+        assert_eq!(black_box(target)(1), 4);
+        vfree(page as *mut c_void).unwrap();
+    }
+
+    #[test]
+    fn cave_outlives_detours() {
+        let detours = Detours::new();
+        let cave = detours
+            .alloc_code_near((scale as *const c_void).addr(), 0x40)
+            .unwrap();
+        assert_eq!(cave.size(), 0x40);
+        drop(detours);
+
+        // mov eax, 42; ret
+        cave.write(0, &[0xb8, 42, 0, 0, 0, 0xc3]).unwrap();
+        let code: extern "C" fn() -> u32 = unsafe { std::mem::transmute(cave.addr()) };
+        assert_eq!(black_box(code)(), 42);
+        assert!(cave.write(0x3c, &[0; 8]).is_err());
+    }
+
+    #[test]
+    fn cave_larger_than_region_is_rejected() {
+        let detours = Detours::new();
+        let cave = detours.alloc_code_near((scale as *const c_void).addr(), DETOUR_REGION_SIZE + 1);
+        assert!(matches!(cave, Err(Error::NotEnoughMemory)));
+        assert!(
+            detours
+                .alloc_code_near((scale as *const c_void).addr(), DETOUR_REGION_SIZE)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn unrelocatable_prologue_frees_blocks() {
+        // push qword [rip + 0x70000000], its copy far below can neither reach nor emulate it
+        let page = code_page(&[0xff, 0x35, 0xfa, 0xff, 0xff, 0x6f, 0xc3]);
+        let arena = page - 0x7000_0000..page - 0x6ff0_0000;
+        let mut detours = Detours::new();
+        detours.set_allocation_strategy(crate::alloc::ArenaStrategy::new(vec![arena]));
+        let mut guard = detours.lock().unwrap();
+        let attached = guard.attach(page as *const c_void, scale_detour as *const c_void);
+        assert!(
+            matches!(attached, Err(Error::Unrelocatable(addr)) if addr == page),
+            "{attached:?}"
+        );
+        drop(guard);
+        let stats = detours.stats();
+        assert_eq!((stats.used_blocks, stats.reclaimed_regions), (0, 1));
+        vfree(page as *mut c_void).unwrap();
+    }
+
+    /// process memory which corrupts the copied code of every trampoline written
+    struct CorruptTrampolines;
+
+    impl MemoryBackend for CorruptTrampolines {
+        fn query(&self, addr: usize) -> Option<crate::alloc::RegionInfo> {
+            ProcessMemory.query(addr)
+        }
+
+        fn write(&self, addr: usize, data: &[u8]) -> Result<(), Error> {
+            let mut data = data.to_vec();
+            if data.len() == PREFETCH_INST_SIZE && data[..ENDBR.len()] == ENDBR {
+                data[ENDBR.len() + 1] ^= 1;
+            }
+            ProcessMemory.write(addr, &data)
+        }
+
+        fn protect(
+            &self,
+            addr: usize,
+            size: usize,
+            protection: crate::backend::Protection,
+        ) -> Result<crate::backend::Protection, Error> {
+            ProcessMemory.protect(addr, size, protection)
+        }
+
+        fn alloc(&self, addr: usize, size: usize) -> Option<usize> {
+            ProcessMemory.alloc(addr, size)
+        }
+
+        fn commit(&self, addr: usize, size: usize) -> Option<usize> {
+            ProcessMemory.commit(addr, size)
+        }
+
+        fn free(&self, addr: usize) -> Result<(), Error> {
+            ProcessMemory.free(addr)
+        }
+
+        fn decommit(&self, addr: usize, size: usize) -> Result<(), Error> {
+            ProcessMemory.decommit(addr, size)
+        }
+    }
+
+    // mov eax, edi; lea eax, [rax + rax * 2 + 1]; ret
+    const SCALE: [u8; 7] = [0x89, 0xf8, 0x8d, 0x44, 0x40, 0x01, 0xc3];
+
+    #[test]
+    #[cfg_attr(
+        not(debug_assertions),
+        ignore = "trampolines are checked in debug builds"
+    )]
+    fn corrupt_trampoline_is_caught_before_patching() {
+        let page = code_page(&SCALE);
+        let mut detours =
+            Detours::with_backend(RegionMode::default(), Arc::new(CorruptTrampolines));
+        let mut guard = detours.lock().unwrap();
+        let attached = guard.attach(page as *const c_void, scale_detour as *const c_void);
+        assert!(matches!(attached, Err(Error::TrampolineMismatch(addr)) if addr == page));
+        drop(guard);
+        assert_eq!(
+            unsafe { std::slice::from_raw_parts(page as *const u8, 7) },
+            SCALE
+        );
+        assert_eq!(detours.stats().used_blocks, 0);
+        vfree(page as *mut c_void).unwrap();
+    }
+
+    #[test]
+    fn verify_reads_trampoline_back() {
+        let page = code_page(&SCALE);
+        let mut detours = Detours::new();
+        detours
+            .lock()
+            .unwrap()
+            .attach(page as *const c_void, scale_detour as *const c_void)
+            .unwrap();
+        let detour = detours.get(&page).unwrap();
+        detour.verify().unwrap();
+
+        // jump back one byte short, then a broken landing pad
+        let exec = detour.block.exec_ptr().addr();
+        let moved = ENDBR.len() + detour.relocated.code.len();
+        let jmp = exec + moved;
+        let _guard = MemoryProtector::new(exec, PREFETCH_INST_SIZE).unwrap();
+        let rel = unsafe { std::ptr::read_unaligned((jmp + 1) as *const i32) };
+        unsafe { std::ptr::write_unaligned((jmp + 1) as *mut i32, rel - 1) };
+        assert!(matches!(detour.verify(), Err(Error::TrampolineMismatch(addr)) if addr == page));
+        unsafe { std::ptr::write_unaligned((jmp + 1) as *mut i32, rel) };
+        detour.verify().unwrap();
+        unsafe { *(exec as *mut u8) = 0x90 };
+        assert!(detour.verify().is_err());
+        unsafe { *(exec as *mut u8) = ENDBR[0] };
+        detour.verify().unwrap();
+    }
+
+    #[test]
+    fn landing_pad_stays_in_front_of_jump() {
+        let page = code_page(&[&ENDBR[..], &SCALE].concat());
+        let target: extern "C" fn(u32) -> u32 = unsafe { std::mem::transmute(page) };
+        let mut detours = Detours::new();
+        detours
+            .lock()
+            .unwrap()
+            .attach(page as *const c_void, scale_detour as *const c_void)
+            .unwrap();
+
+        let detour = detours.get(&page).unwrap();
+        assert_eq!((detour.landing_pad(), detour.fetch()), (4, 6));
+        let head = unsafe { std::slice::from_raw_parts(page as *const u8, 5) };
+        assert_eq!((&head[..4], head[4]), (&ENDBR[..], 0xe9));
+        let trampoline = unsafe { *(detour.block.exec_ptr() as *const [u8; 4]) };
+        assert_eq!(trampoline, ENDBR);
+        assert_eq!(black_box(target)(2), 1002);
+        assert_eq!((*detour.trampoline::<extern "C" fn(u32) -> u32>())(2), 7);
+
+        drop(detours);
+        assert_eq!(
+            unsafe { std::slice::from_raw_parts(page as *const u8, 11) }[4..],
+            SCALE
+        );
+        vfree(page as *mut c_void).unwrap();
+    }
+
+    #[test]
+    fn return_based_copy_under_landing_pad_is_rejected() {
+        // endbr64; call [rip + 0x6ffffff0], emulated with a return from a trampoline far below
+        let mut code = ENDBR.to_vec();
+        code.extend([0xff, 0x15, 0xf0, 0xff, 0xff, 0x6f]);
+        let page = code_page(&code);
+        let arena = page - 0x7000_0000..page - 0x6ff0_0000;
+        let mut detours = Detours::new();
+        detours.set_allocation_strategy(crate::alloc::ArenaStrategy::new(vec![arena]));
+        let mut guard = detours.lock().unwrap();
+        let attached = guard.attach(page as *const c_void, scale_detour as *const c_void);
+        assert!(matches!(attached, Err(Error::ShadowStackIncompatible)));
+        drop(guard);
+        assert_eq!(detours.stats().used_blocks, 0);
+        assert_eq!(
+            unsafe { std::slice::from_raw_parts(page as *const u8, 10) },
+            code
+        );
+        vfree(page as *mut c_void).unwrap();
+    }
+
+    /// simulated memory holding `SCALE` as target, the detour address is never called
+    fn simulated() -> (Arc<SimulatedMemory>, usize, usize) {
+        let sim = Arc::new(SimulatedMemory::new(16 * DETOUR_REGION_SIZE));
+        let target = sim.range().start + 8 * DETOUR_REGION_SIZE;
+        sim.map(target, 0x1000, Protection::ReadExecute).unwrap();
+        sim.write(target, &SCALE).unwrap();
+        (sim, target, target + 0x800)
+    }
+
+    #[test]
+    fn attach_in_simulated_memory() {
+        let (sim, target, detour) = simulated();
+        let mut detours = Detours::with_backend(RegionMode::default(), sim.clone());
+        let attached = detours
+            .lock()
+            .unwrap()
+            .attach(target as *const c_void, detour as *const c_void);
+        attached.unwrap();
+
+        let patched = sim.read(target, 5).unwrap();
+        let rel = i32::from_le_bytes(patched[1..5].try_into().unwrap());
+        assert_eq!(patched[0], 0xe9);
+        assert_eq!((target + 5).wrapping_add_signed(rel as isize), detour);
+        assert_eq!(sim.protection(target), Some(Protection::ReadExecute));
+        assert!(sim.writable_executable().is_empty());
+
+        let trampoline = *detours.get(&target).unwrap().trampoline::<usize>();
+        assert!(sim.range().contains(&trampoline));
+        assert_eq!(sim.protection(trampoline), Some(Protection::ReadExecute));
+        assert_eq!(sim.read(trampoline, 4).unwrap(), ENDBR);
+        assert_eq!(sim.read(trampoline + 4, 6).unwrap(), SCALE[..6]);
+        detours.get(&target).unwrap().verify().unwrap();
+        assert_eq!(detours.stats().used_blocks, 1);
+
+        detours.lock().unwrap().detach(&target);
+        assert_eq!(sim.read(target, SCALE.len()).unwrap(), SCALE);
+        assert_eq!(detours.stats().regions, 0);
+        assert!(sim.writable_executable().is_empty());
+    }
+
+    /// push rbp; mov rbp, rsp; mov eax, edi; lea eax, [rax + rax * 2 + 1]; add eax, 0x10;
+    /// pop rbp; ret, the first 14 bytes are exactly what an absolute jump overwrites
+    const FRAMED: [u8; 15] = [
+        0x55, 0x48, 0x89, 0xe5, 0x89, 0xf8, 0x8d, 0x44, 0x40, 0x01, 0x83, 0xc0, 0x10, 0x5d, 0xc3,
+    ];
+
+    #[test]
+    fn absolute_jump_without_free_space_in_reach() {
+        // everything within rel32 reach of the targets is taken, free space starts behind
+        let taken = 0x8010_0000;
+        let sim = Arc::new(SimulatedMemory::new(taken + 4 * DETOUR_REGION_SIZE));
+        let base = sim.range().start;
+        sim.map(base, taken, Protection::ReadExecute).unwrap();
+        let (target, short) = (base + 0x10000, base + 0x10100);
+        sim.write(target, &FRAMED).unwrap();
+        sim.write(short, &SCALE).unwrap();
+        let detour = base + 0x20000;
+        let mut detours = Detours::with_backend(RegionMode::default(), sim.clone());
+
+        let attached = detours
+            .lock()
+            .unwrap()
+            .attach(short as *const c_void, detour as *const c_void);
+        assert!(matches!(attached, Err(Error::InvalidAddress)));
+        assert_eq!(sim.read(short, SCALE.len()).unwrap(), SCALE);
+        assert_eq!(detours.stats().used_blocks, 0);
+
+        detours
+            .lock()
+            .unwrap()
+            .attach(target as *const c_void, detour as *const c_void)
+            .unwrap();
+        let patched = sim.read(target, 14).unwrap();
+        assert_eq!(patched[..6], [0xff, 0x25, 0, 0, 0, 0]);
+        assert_eq!(
+            usize::from_le_bytes(patched[6..].try_into().unwrap()),
+            detour
+        );
+
+        let attached = detours.get(&target).unwrap();
+        assert_eq!(attached.jump_kind(), JumpKind::Absolute);
+        let trampoline = *attached.trampoline::<usize>();
+        assert!(trampoline >= base + taken);
+        let code = sim.read(trampoline, 32).unwrap();
+        assert_eq!(code[..4], ENDBR);
+        assert_eq!(code[4..18], FRAMED[..14]);
+        assert_eq!(code[18..24], [0xff, 0x25, 0, 0, 0, 0]);
+        assert_eq!(
+            usize::from_le_bytes(code[24..].try_into().unwrap()),
+            target + 14
+        );
+        attached.verify().unwrap();
+        assert_eq!(detours.stats().used_blocks, 1);
+
+        detours.lock().unwrap().detach(&target);
+        assert_eq!(sim.read(target, FRAMED.len()).unwrap(), FRAMED);
+        assert_eq!(detours.stats().regions, 0);
+    }
+
+    #[test]
+    fn blocked_dynamic_code_needs_reserved_regions() {
+        let (sim, target, detour) = simulated();
+        let mut detours = Detours::with_backend(RegionMode::default(), sim.clone());
+        sim.set_dynamic_code_blocked(true);
+        let attached = detours
+            .lock()
+            .unwrap()
+            .attach(target as *const c_void, detour as *const c_void);
+        assert!(matches!(attached, Err(Error::NotEnoughMemory)));
+        assert_eq!(sim.read(target, SCALE.len()).unwrap(), SCALE);
+
+        sim.set_dynamic_code_blocked(false);
+        detours.reserve(&[target], 1).unwrap();
+        sim.set_dynamic_code_blocked(true);
+        detours
+            .lock()
+            .unwrap()
+            .attach(target as *const c_void, detour as *const c_void)
+            .unwrap();
+        assert_eq!(sim.read(target, 1).unwrap(), [0xe9]);
+        assert_eq!(detours.stats().reserved_regions, 1);
+    }
+}
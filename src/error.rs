@@ -5,6 +5,20 @@ pub enum Error {
     InvalidSignature(usize, usize),
     ErrorCode(usize),
     NotEnoughMemory,
+    NullPointer(usize),
+    UnmappedAddress(usize, usize),
+    ModuleNotFound,
+    InvalidPattern,
+    InvalidOffsetTable,
+    SignatureNotFound,
+    OffsetNotFound(String),
+    LibraryLoad(String),
+    InvalidPayload,
+    InvalidImage,
+    ShadowStackIncompatible,
+    Unrelocatable(usize),
+    TrampolineMismatch(usize),
+    Assembly(String),
 }
 
 impl Debug for Error {
@@ -28,6 +42,51 @@ impl Display for Error {
             Error::NotEnoughMemory => {
                 write!(f, "not enough memory")
             }
+            Error::NullPointer(level) => {
+                write!(f, "null pointer at level {level}")
+            }
+            Error::UnmappedAddress(level, addr) => {
+                write!(f, "unmapped address {addr:#x} at level {level}")
+            }
+            Error::ModuleNotFound => {
+                write!(f, "module not found")
+            }
+            Error::InvalidPattern => {
+                write!(f, "invalid pattern")
+            }
+            Error::InvalidOffsetTable => {
+                write!(f, "invalid offset table")
+            }
+            Error::SignatureNotFound => {
+                write!(f, "signature not found")
+            }
+            Error::OffsetNotFound(ref name) => {
+                write!(f, "no offset or signature for {name}")
+            }
+            Error::LibraryLoad(ref reason) => {
+                write!(f, "library load failed: {reason}")
+            }
+            Error::InvalidPayload => {
+                write!(f, "invalid payload")
+            }
+            Error::InvalidImage => {
+                write!(f, "invalid image")
+            }
+            Error::ShadowStackIncompatible => {
+                write!(
+                    f,
+                    "hook needs a return based jump, rejected by shadow stacks"
+                )
+            }
+            Error::Unrelocatable(addr) => {
+                write!(f, "instruction at {addr:#x} can't be relocated")
+            }
+            Error::TrampolineMismatch(addr) => {
+                write!(f, "trampoline differs from original code at {addr:#x}")
+            }
+            Error::Assembly(ref reason) => {
+                write!(f, "assembly failed: {reason}")
+            }
         }
     }
 }
@@ -1,4 +1,8 @@
-use crate::mem::{raw_read, raw_write};
+use crate::Error;
+use crate::backend::ProcessMemory;
+use crate::mem::{is_committed, raw_read, raw_write};
+use crate::platform;
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
 
@@ -72,3 +76,165 @@ impl<const ADDR: usize, T> DerefMut for Pointer<ADDR, T> {
         unsafe { std::mem::transmute(*self.0) }
     }
 }
+
+#[derive(Clone, Copy)]
+pub enum ChainBase {
+    Absolute(usize),
+    /// module name and offset from its base, `None` means main module
+    Module(Option<&'static str>, usize),
+}
+
+impl ChainBase {
+    pub fn resolve(&self) -> Result<usize, Error> {
+        match *self {
+            ChainBase::Absolute(addr) => Ok(addr),
+            ChainBase::Module(name, offset) => platform::module_base(name)
+                .map(|base| base.wrapping_add(offset))
+                .ok_or(Error::ModuleNotFound),
+        }
+    }
+}
+
+/// multi level pointer `[[[base+0x10]+0x48]+0x8]`
+///
+/// every offset except the last one is followed by a pointer read,
+/// resolve happens on each access so it follows the current object graph
+///
+/// ```ignore
+/// const HEALTH: PointerChain<i32> = PointerChain::with_module(None, 0x1234, &[0x10, 0x48, 0x8]);
+/// ```
+#[derive(Clone, Copy)]
+pub struct PointerChain<T: 'static> {
+    base: ChainBase,
+    offsets: &'static [usize],
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: 'static> PointerChain<T> {
+    pub const fn new(base: usize, offsets: &'static [usize]) -> PointerChain<T> {
+        PointerChain {
+            base: ChainBase::Absolute(base),
+            offsets,
+            _marker: PhantomData,
+        }
+    }
+
+    pub const fn with_module(
+        module: Option<&'static str>,
+        offset: usize,
+        offsets: &'static [usize],
+    ) -> PointerChain<T> {
+        PointerChain {
+            base: ChainBase::Module(module, offset),
+            offsets,
+            _marker: PhantomData,
+        }
+    }
+
+    pub const fn base(&self) -> ChainBase {
+        self.base
+    }
+
+    pub const fn offsets(&self) -> &'static [usize] {
+        self.offsets
+    }
+
+    /// walk the chain and return final address
+    ///
+    /// error level is index of offset which produce the bad pointer,
+    /// level 0 means base itself
+    pub fn resolve(&self) -> Result<usize, Error> {
+        let mut addr = self.base.resolve()?;
+        let Some((last, levels)) = self.offsets.split_last() else {
+            return Ok(addr);
+        };
+        for (level, offset) in levels.iter().enumerate() {
+            if addr == 0 {
+                return Err(Error::NullPointer(level));
+            }
+            let slot = addr.wrapping_add(*offset);
+            if !is_committed(&ProcessMemory, slot, size_of::<usize>()) {
+                return Err(Error::UnmappedAddress(level, slot));
+            }
+            addr = raw_read::<usize>(slot);
+        }
+        if addr == 0 {
+            return Err(Error::NullPointer(levels.len()));
+        }
+        Ok(addr.wrapping_add(*last))
+    }
+
+    fn resolve_checked(&self) -> Result<usize, Error> {
+        let addr = self.resolve()?;
+        if !is_committed(&ProcessMemory, addr, size_of::<T>()) {
+            return Err(Error::UnmappedAddress(
+                self.offsets.len().saturating_sub(1),
+                addr,
+            ));
+        }
+        Ok(addr)
+    }
+
+    pub fn raw_read(&self) -> Result<T, Error> {
+        self.resolve_checked().map(raw_read)
+    }
+
+    pub fn raw_write(&self, val: T) -> Result<usize, Error> {
+        self.resolve_checked().map(|addr| raw_write(addr, val))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::platform::{
+        MEM_TYPE_COMMIT, MEM_TYPE_RESERVE, PAGE_FLAG_NOACCESS, valloc, vfree, vprotect,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static ROOT: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn chain_reports_failing_level() {
+        let page = valloc(std::ptr::null(), 0x2000, MEM_TYPE_COMMIT | MEM_TYPE_RESERVE).unwrap();
+        let page = page as usize;
+        let put = |at: usize, value: usize| unsafe { *((page + at) as *mut usize) = value };
+        // [[page+0x10]+0x8]+0x4 -> page+0x104
+        put(0x10, page + 0x40);
+        put(0x48, page + 0x100);
+        put(0x100, 0x1234_5678);
+
+        let chain = PointerChain::<u32>::new(page, &[0x10, 0x8, 0x4]);
+        assert_eq!(chain.resolve().unwrap(), page + 0x104);
+        unsafe { *((page + 0x104) as *mut u32) = 42 };
+        assert_eq!(chain.raw_read().unwrap(), 42);
+
+        put(0x48, 0);
+        assert!(matches!(chain.resolve(), Err(Error::NullPointer(2))));
+
+        // committed but not readable
+        put(0x48, page + 0x1000);
+        vprotect((page + 0x1000) as *const u8, 0x1000, PAGE_FLAG_NOACCESS).unwrap();
+        let at = page + 0x1000;
+        assert!(matches!(chain.raw_read(), Err(Error::UnmappedAddress(2, x)) if x == at + 4));
+        let chain = PointerChain::<u32>::new(page, &[0x10, 0x8, 0x0, 0x0]);
+        assert!(matches!(chain.resolve(), Err(Error::UnmappedAddress(2, x)) if x == at));
+        vfree(page as *mut std::ffi::c_void).unwrap();
+    }
+
+    #[test]
+    fn chain_resolves_from_module_base() {
+        let base = platform::module_base(None).unwrap();
+        assert_eq!(raw_read::<[u8; 4]>(base), *b"\x7fELF");
+
+        let target = Box::new([0u32, 0, 7, 0]);
+        ROOT.store(target.as_ptr() as usize, Ordering::SeqCst);
+        let offset = &ROOT as *const AtomicUsize as usize - base;
+        let chain = PointerChain::<u32>::with_module(None, offset, &[0x0, 0x8]);
+        assert_eq!(chain.resolve().unwrap(), target.as_ptr() as usize + 8);
+        assert_eq!(chain.raw_read().unwrap(), 7);
+
+        let missing = PointerChain::<u32>::with_module(Some("libmissing.so"), 0, &[0x0]);
+        assert!(matches!(missing.resolve(), Err(Error::ModuleNotFound)));
+    }
+}
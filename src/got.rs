@@ -0,0 +1,428 @@
+//! redirect calls through the global offset table of loaded modules
+//!
+//! lighter than an inline patch, only calls made from the hooked module go to the detour.
+//! Slots are found through `DT_JMPREL`, `DT_RELA` and `DT_REL` of the module's dynamic
+//! section, full RELRO is lifted with [`MemoryProtector`] while a slot is written
+
+use crate::Error;
+use crate::backend::MemoryProtector;
+use std::ffi::{CStr, CString, c_int, c_void};
+use std::path::Path;
+
+const DT_NULL: usize = 0;
+const DT_PLTRELSZ: usize = 2;
+const DT_PLTGOT: usize = 3;
+const DT_STRTAB: usize = 5;
+const DT_SYMTAB: usize = 6;
+const DT_RELA: usize = 7;
+const DT_RELASZ: usize = 8;
+const DT_SYMENT: usize = 11;
+const DT_REL: usize = 17;
+const DT_RELSZ: usize = 18;
+const DT_PLTREL: usize = 20;
+const DT_JMPREL: usize = 23;
+const DT_VERSYM: usize = 0x6ffffff0;
+const DT_VERNEED: usize = 0x6ffffffe;
+const DT_VERNEEDNUM: usize = 0x6fffffff;
+
+// `DT_VERSYM` bit marking a hidden version, not part of the index
+const VERSYM_HIDDEN: u16 = 0x8000;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+
+// same values for R_X86_64_* and R_386_*
+const R_GLOB_DAT: usize = 6;
+const R_JUMP_SLOT: usize = 7;
+
+const WORD: usize = size_of::<usize>();
+
+/// GOT slot and the symbol its relocation names
+pub(crate) struct Slot {
+    pub(crate) addr: usize,
+    pub(crate) name: CString,
+    /// version the module requires, e.g. `GLIBC_2.2.5`
+    pub(crate) version: Option<CString>,
+}
+
+impl Slot {
+    /// address the symbol binds to, looked up with the required version
+    pub(crate) fn resolve(&self) -> Option<usize> {
+        let resolved = match &self.version {
+            #[cfg(target_env = "gnu")]
+            Some(version) => unsafe {
+                libc::dlvsym(libc::RTLD_DEFAULT, self.name.as_ptr(), version.as_ptr())
+            },
+            _ => unsafe { libc::dlsym(libc::RTLD_DEFAULT, self.name.as_ptr()) },
+        } as usize;
+        (resolved != 0).then_some(resolved)
+    }
+}
+
+/// loaded module as reported by `dl_iterate_phdr`
+pub(crate) struct Module {
+    base: usize,
+    dynamic: usize,
+    // address ranges of the load segments
+    segments: Vec<(usize, usize)>,
+}
+
+impl Module {
+    pub(crate) fn contains(&self, addr: usize) -> bool {
+        self.segments
+            .iter()
+            .any(|(start, end)| (*start..*end).contains(&addr))
+    }
+
+    /// address the image is mapped from, the first load segment rounded down to the page
+    pub(crate) fn start(&self) -> Option<usize> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let first = self.segments.iter().map(|x| x.0).min()?;
+        Some(first & !(page - 1))
+    }
+
+    /// dynamic entry values
+    fn entries(&self) -> Vec<(usize, usize)> {
+        let mut entries = vec![];
+        let mut at = self.dynamic;
+        loop {
+            let (tag, value) = unsafe { (*(at as *const usize), *((at + WORD) as *const usize)) };
+            if tag == DT_NULL {
+                break;
+            }
+            entries.push((tag, value));
+            at += 2 * WORD;
+        }
+        entries
+    }
+
+    /// pointer entry with the load bias applied
+    fn pointer(&self, entries: &[(usize, usize)], tag: usize) -> Option<usize> {
+        entries
+            .iter()
+            .find(|x| x.0 == tag)
+            .map(|x| match x.1 < self.base {
+                // glibc relocates the pointers in place, musl and the vdso leave them alone
+                true => x.1 + self.base,
+                false => x.1,
+            })
+    }
+
+    /// address of the GOT, `DT_PLTGOT`
+    pub(crate) fn got(&self) -> Option<usize> {
+        self.pointer(&self.entries(), DT_PLTGOT)
+    }
+
+    /// version names of `DT_VERNEED` by the index `DT_VERSYM` refers to them with
+    fn version_needs(&self, entries: &[(usize, usize)], strtab: usize) -> Vec<(u16, CString)> {
+        let count = entries.iter().find(|x| x.0 == DT_VERNEEDNUM).map(|x| x.1);
+        let (Some(mut at), Some(count)) = (self.pointer(entries, DT_VERNEED), count) else {
+            return vec![];
+        };
+        let read = |addr: usize| unsafe { (addr as *const u32).read_unaligned() } as usize;
+        let mut versions = vec![];
+        for _ in 0..count {
+            let aux_count = unsafe { (at as *const u16).add(1).read_unaligned() };
+            let mut aux = at + read(at + 8);
+            for _ in 0..aux_count {
+                let index = unsafe { ((aux + 6) as *const u16).read_unaligned() };
+                let name = unsafe { CStr::from_ptr((strtab + read(aux + 8)) as *const _) };
+                versions.push((index, name.to_owned()));
+                aux += read(aux + 12);
+            }
+            match read(at + 12) {
+                0 => break,
+                next => at += next,
+            }
+        }
+        versions
+    }
+
+    /// `JUMP_SLOT` and `GLOB_DAT` slots with the symbol they resolve
+    pub(crate) fn slots(&self) -> Vec<Slot> {
+        let entries = self.entries();
+        let value = |tag: usize| entries.iter().find(|x| x.0 == tag).map(|x| x.1);
+        let (Some(symtab), Some(strtab)) = (
+            self.pointer(&entries, DT_SYMTAB),
+            self.pointer(&entries, DT_STRTAB),
+        ) else {
+            return vec![];
+        };
+        let syment = value(DT_SYMENT).unwrap_or(if WORD == 8 { 24 } else { 16 });
+        let jmprel_size = match value(DT_PLTREL) {
+            Some(DT_REL) => 2 * WORD,
+            _ => 3 * WORD,
+        };
+        let versym = self.pointer(&entries, DT_VERSYM);
+        let versions = self.version_needs(&entries, strtab);
+        let version_of = |index: usize| {
+            let versym = versym?;
+            let wanted = unsafe { (versym as *const u16).add(index).read_unaligned() };
+            versions
+                .iter()
+                .find(|x| x.0 == wanted & !VERSYM_HIDDEN)
+                .map(|x| x.1.clone())
+        };
+
+        let mut slots = vec![];
+        for (table, size, entry_size) in [
+            (
+                self.pointer(&entries, DT_JMPREL),
+                value(DT_PLTRELSZ),
+                jmprel_size,
+            ),
+            (self.pointer(&entries, DT_RELA), value(DT_RELASZ), 3 * WORD),
+            (self.pointer(&entries, DT_REL), value(DT_RELSZ), 2 * WORD),
+        ] {
+            let (Some(table), Some(size)) = (table, size) else {
+                continue;
+            };
+            for at in (table..table + size).step_by(entry_size) {
+                let (offset, info) =
+                    unsafe { (*(at as *const usize), *((at + WORD) as *const usize)) };
+                #[cfg(target_pointer_width = "64")]
+                let (index, kind) = (info >> 32, info & 0xffff_ffff);
+                #[cfg(target_pointer_width = "32")]
+                let (index, kind) = (info >> 8, info & 0xff);
+                if index == 0 || (kind != R_JUMP_SLOT && kind != R_GLOB_DAT) {
+                    continue;
+                }
+                let name = unsafe { *((symtab + index * syment) as *const u32) } as usize;
+                let name = unsafe { CStr::from_ptr((strtab + name) as *const _) };
+                slots.push(Slot {
+                    addr: self.base + offset,
+                    name: name.to_owned(),
+                    version: version_of(index),
+                });
+            }
+        }
+        slots
+    }
+}
+
+enum Search<'a> {
+    Name(Option<&'a str>),
+    Address(usize),
+}
+
+fn find_module(search: Search) -> Option<Module> {
+    struct State<'a> {
+        search: Search<'a>,
+        found: Option<Module>,
+    }
+
+    unsafe extern "C" fn callback(
+        info: *mut libc::dl_phdr_info,
+        _size: usize,
+        data: *mut c_void,
+    ) -> c_int {
+        let state = unsafe { &mut *data.cast::<State>() };
+        let info = unsafe { &*info };
+        let base = info.dlpi_addr as usize;
+        let headers =
+            unsafe { std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize) };
+        let mut module = Module {
+            base,
+            dynamic: 0,
+            segments: vec![],
+        };
+        for header in headers {
+            let start = base + header.p_vaddr as usize;
+            match header.p_type {
+                PT_LOAD => module
+                    .segments
+                    .push((start, start + header.p_memsz as usize)),
+                PT_DYNAMIC => module.dynamic = start,
+                _ => {}
+            }
+        }
+
+        let matched = match state.search {
+            // main program is reported first with an empty name
+            Search::Name(None) => true,
+            Search::Name(Some(name)) => {
+                let path = match info.dlpi_name.is_null() {
+                    true => "".into(),
+                    false => unsafe { CStr::from_ptr(info.dlpi_name) }.to_string_lossy(),
+                };
+                path == name || Path::new(path.as_ref()).file_name() == Some(name.as_ref())
+            }
+            Search::Address(addr) => module.contains(addr),
+        };
+        if !matched {
+            return 0;
+        }
+        state.found = Some(module);
+        1
+    }
+
+    let mut state = State {
+        search,
+        found: None,
+    };
+    unsafe { libc::dl_iterate_phdr(Some(callback), (&mut state as *mut State).cast()) };
+    state.found.filter(|x| x.dynamic != 0)
+}
+
+/// loaded module by file name or path, `None` for the main program
+pub(crate) fn module_named(name: Option<&str>) -> Option<Module> {
+    find_module(Search::Name(name))
+}
+
+/// loaded module mapping `addr`
+pub(crate) fn module_at(addr: usize) -> Option<Module> {
+    find_module(Search::Address(addr))
+}
+
+/// GOT slots `module` resolves `symbol` into, ordered by address
+fn find_slots(module: &Module, symbol: &str) -> Vec<Slot> {
+    let mut slots: Vec<Slot> = module
+        .slots()
+        .into_iter()
+        .filter(|x| x.name.to_bytes() == symbol.as_bytes())
+        .collect();
+    slots.sort_unstable_by_key(|x| x.addr);
+    slots.dedup_by_key(|x| x.addr);
+    slots
+}
+
+fn write_slot(slot: usize, value: usize) -> Result<(), Error> {
+    MemoryProtector::new_with::<usize>(slot)?.write_override(value);
+    Ok(())
+}
+
+/// swapped GOT slots of one import, restored on drop
+pub struct GotHook {
+    symbol: String,
+    // slot and the value it held before
+    slots: Vec<(usize, usize)>,
+    original: usize,
+}
+
+impl GotHook {
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn slots(&self) -> Vec<usize> {
+        self.slots.iter().map(|x| x.0).collect()
+    }
+
+    /// address the import resolved to before hooking
+    pub fn original<T>(&self) -> &T {
+        unsafe { &*(&self.original as *const usize as *const T) }
+    }
+}
+
+impl Drop for GotHook {
+    fn drop(&mut self) {
+        for (slot, value) in &self.slots {
+            let _ = write_slot(*slot, *value);
+        }
+    }
+}
+
+/// redirect calls to `symbol` made by `module` to `detour`
+///
+/// `module` is matched by path or file name, `None` hooks the main program. Lazily bound
+/// slots still point into the module's PLT, the original is then resolved with `dlvsym` so
+/// calling it does not bind the slot over the hook
+///
+/// # Safety
+///
+/// `detour` must match the signature of `symbol`, and no other thread may be resolving
+/// the same import while slots are written
+pub unsafe fn hook_import(
+    module: Option<&str>,
+    symbol: &str,
+    detour: *const c_void,
+) -> Result<GotHook, Error> {
+    if detour.is_null() {
+        return Err(Error::InvalidAddress);
+    }
+    let module = module_named(module).ok_or(Error::ModuleNotFound)?;
+    let slots = find_slots(&module, symbol);
+    let first = slots.first().ok_or(Error::SignatureNotFound)?;
+
+    let current = unsafe { *(first.addr as *const usize) };
+    let original = match module.contains(current) {
+        true => first.resolve().ok_or(Error::SignatureNotFound)?,
+        false => current,
+    };
+
+    let mut hook = GotHook {
+        symbol: symbol.to_string(),
+        slots: vec![],
+        original,
+    };
+    for Slot { addr: slot, .. } in slots {
+        let value = unsafe { *(slot as *const usize) };
+        write_slot(slot, detour as usize)?;
+        // a slot still bound lazily is restored to the resolved address instead
+        let value = match module.contains(value) {
+            true => original,
+            false => value,
+        };
+        hook.slots.push((slot, value));
+    }
+    Ok(hook)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// slot the test program imports `malloc` through
+    pub(crate) fn malloc_slot() -> (Module, Slot) {
+        let module = module_at(malloc_slot as *const () as usize).unwrap();
+        let slot = find_slots(&module, "malloc").into_iter().next().unwrap();
+        (module, slot)
+    }
+
+    #[test]
+    fn slots_name_symbol_and_version() {
+        let (module, slot) = malloc_slot();
+        assert_eq!(slot.name.as_c_str(), c"malloc");
+        if cfg!(target_env = "gnu") {
+            let version = slot.version.as_ref().unwrap().to_bytes();
+            assert!(version.starts_with(b"GLIBC_2."));
+        }
+
+        let bound = unsafe { *(slot.addr as *const usize) };
+        if !module.contains(bound) {
+            assert_eq!(slot.resolve(), Some(bound));
+        }
+        assert!(find_slots(&module, "no_such_import").is_empty());
+    }
+
+    extern "C" fn fake_getpgrp() -> libc::pid_t {
+        4242
+    }
+
+    #[test]
+    fn hook_through_read_only_got() {
+        use crate::backend::{MemoryBackend, ProcessMemory, Protection};
+
+        let real = unsafe { libc::getpgrp() };
+        assert_ne!(real, 4242);
+        let module = module_named(None).unwrap();
+        let slot = find_slots(&module, "getpgrp")[0].addr;
+        let protection = || ProcessMemory.query(slot).unwrap().protection;
+        // linked with -z relro -z now, the GOT is read only once relocated
+        assert_eq!(protection(), Protection::Read);
+
+        let detour = fake_getpgrp as *const () as *const c_void;
+        let hook = unsafe { hook_import(None, "getpgrp", detour) }.unwrap();
+        assert_eq!(hook.symbol(), "getpgrp");
+        assert!(hook.slots().contains(&slot));
+        assert_eq!(unsafe { libc::getpgrp() }, 4242);
+        let original = *hook.original::<extern "C" fn() -> libc::pid_t>();
+        assert_eq!(original(), real);
+        assert_eq!(protection(), Protection::Read);
+
+        drop(hook);
+        assert_eq!(unsafe { libc::getpgrp() }, real);
+        assert_eq!(unsafe { *(slot as *const usize) }, original as usize);
+        assert_eq!(protection(), Protection::Read);
+    }
+}
@@ -0,0 +1,178 @@
+//! hook imports of a mapped PE image by swapping its import address table entries
+//!
+//! tables are read with [`crate::pe`], so they can be inspected on any host from a file on
+//! disk or from an image as mapped by the loader
+
+use crate::Error;
+use crate::backend::MemoryProtector;
+use crate::pe::{Import, PeImage};
+use std::ffi::c_void;
+
+pub use crate::pe::ImportThunk;
+
+fn image(image: &[u8], mapped: bool) -> Result<PeImage<'_>, Error> {
+    match mapped {
+        true => PeImage::parse_mapped(image),
+        false => PeImage::parse(image),
+    }
+}
+
+/// IAT entries of the image in `image`, `mapped` when laid out by the loader
+pub fn import_thunks(image: &[u8], mapped: bool) -> Result<Vec<ImportThunk>, Error> {
+    self::image(image, mapped)?.imports()
+}
+
+fn thunk_in(image: &PeImage, dll: &str, import: &Import) -> Result<usize, Error> {
+    let thunks = image.imports()?;
+    if !thunks.iter().any(|x| x.dll.eq_ignore_ascii_case(dll)) {
+        return Err(Error::ModuleNotFound);
+    }
+    thunks
+        .into_iter()
+        .find(|x| x.dll.eq_ignore_ascii_case(dll) && x.import == *import)
+        .map(|x| x.rva)
+        .ok_or(Error::SignatureNotFound)
+}
+
+/// rva of the IAT slot importing `import` from `dll`, dll names compare case insensitive
+pub fn find_thunk(image: &[u8], mapped: bool, dll: &str, import: &Import) -> Result<usize, Error> {
+    thunk_in(&self::image(image, mapped)?, dll, import)
+}
+
+/// swapped IAT slot, the previous target is written back on drop
+pub struct IatHook {
+    slot: usize,
+    original: usize,
+}
+
+impl IatHook {
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// address the slot held before hooking
+    pub fn original<T>(&self) -> &T {
+        unsafe { &*(&self.original as *const usize as *const T) }
+    }
+}
+
+impl Drop for IatHook {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = MemoryProtector::new_with::<usize>(self.slot) {
+            guard.write_override(self.original);
+        }
+    }
+}
+
+/// redirect calls the image mapped at `base` makes to `import` of `dll` to `detour`
+///
+/// # Safety
+///
+/// `base` must point to a mapped PE image of the current process and `detour` must match
+/// the signature of the import
+pub unsafe fn hook_import(
+    base: usize,
+    dll: &str,
+    import: &Import,
+    detour: *const c_void,
+) -> Result<IatHook, Error> {
+    if detour.is_null() {
+        return Err(Error::InvalidAddress);
+    }
+    let image = unsafe { PeImage::from_base(base) }?;
+    // slots are pointer sized, an image of the other bitness is not ours to call into
+    if image.is_64() != (size_of::<usize>() == 8) {
+        return Err(Error::InvalidImage);
+    }
+    let slot = base + thunk_in(&image, dll, import)?;
+
+    let original = unsafe { std::ptr::read_unaligned(slot as *const usize) };
+    let mut guard = MemoryProtector::new_with::<usize>(slot)?;
+    guard.write_override(detour as usize);
+    Ok(IatHook { slot, original })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::tests::{PE32, PE64, map};
+
+    fn name(name: &str) -> Import {
+        Import::Name(name.into())
+    }
+
+    #[test]
+    fn thunks_of_file_and_mapped_image() {
+        for file in [PE32, PE64] {
+            let thunks = import_thunks(file, false).unwrap();
+            assert_eq!(thunks.len(), 4);
+            assert_eq!(import_thunks(&map(file), true).unwrap(), thunks);
+            assert!(matches!(
+                import_thunks(file, true),
+                Err(Error::InvalidImage)
+            ));
+        }
+    }
+
+    #[test]
+    fn find_by_name_and_ordinal() {
+        for (file, width) in [(PE32, 4), (PE64, 8)] {
+            let mapped = map(file);
+            for (image, mapped) in [(file, false), (&mapped[..], true)] {
+                let by_name = find_thunk(image, mapped, "kernel32.DLL", &name("LoadLibraryA"));
+                let first = find_thunk(image, mapped, "KERNEL32.dll", &name("GetProcAddress"));
+                assert_eq!(by_name.unwrap(), first.unwrap() + width);
+                let ordinal = find_thunk(image, mapped, "ws2_32.dll", &Import::Ordinal(115));
+                let after = find_thunk(image, mapped, "WS2_32.dll", &name("connect"));
+                assert_eq!(ordinal.unwrap() + width, after.unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn missing_module_and_import() {
+        for file in [PE32, PE64] {
+            let missing = find_thunk(file, false, "USER32.dll", &name("MessageBoxA"));
+            assert!(matches!(missing, Err(Error::ModuleNotFound)));
+            let missing = find_thunk(file, false, "KERNEL32.dll", &name("ExitProcess"));
+            assert!(matches!(missing, Err(Error::SignatureNotFound)));
+            let missing = find_thunk(file, false, "WS2_32.dll", &Import::Ordinal(116));
+            assert!(matches!(missing, Err(Error::SignatureNotFound)));
+            // imported by ordinal, not by name
+            let missing = find_thunk(file, false, "WS2_32.dll", &name("WSAStartup"));
+            assert!(matches!(missing, Err(Error::SignatureNotFound)));
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn hook_swaps_slot_and_restores() {
+        use crate::platform::{MEM_TYPE_COMMIT, MEM_TYPE_RESERVE, valloc, vfree};
+
+        let image = map(PE64);
+        let base = valloc(
+            std::ptr::null(),
+            image.len(),
+            MEM_TYPE_COMMIT | MEM_TYPE_RESERVE,
+        );
+        let base = base.unwrap() as usize;
+        unsafe { std::ptr::copy_nonoverlapping(image.as_ptr(), base as *mut u8, image.len()) };
+        let rva = find_thunk(&image, true, "WS2_32.dll", &name("connect")).unwrap();
+        let slot = || unsafe { std::ptr::read_unaligned((base + rva) as *const usize) };
+        let before = slot();
+
+        let detour = 0x1234_5678usize as *const c_void;
+        let hook = unsafe { hook_import(base, "ws2_32.dll", &name("connect"), detour) }.unwrap();
+        assert_eq!(
+            (hook.slot(), *hook.original::<usize>()),
+            (base + rva, before)
+        );
+        assert_eq!(slot(), detour as usize);
+        drop(hook);
+        assert_eq!(slot(), before);
+
+        let missing = unsafe { hook_import(base, "USER32.dll", &name("MessageBoxA"), detour) };
+        assert!(matches!(missing, Err(Error::ModuleNotFound)));
+        vfree(base as *mut c_void).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+//! load a shared library into a running process
+//!
+//! the Linux counterpart of `DetourCreateProcessWithDll`, the target is stopped with ptrace
+//! and `dlopen` is called on its main thread, constructors of the library run before
+//! [`inject`] returns
+
+use crate::Error;
+use crate::backend::{CALL_STUB, MemoryBackend, RemoteProcess};
+use std::ffi::{CStr, CString, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const STUB_SIZE: usize = 0x1000;
+
+/// library loaded into the target
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Injection {
+    pub pid: u32,
+    /// `dlopen` handle in the target
+    pub handle: usize,
+}
+
+/// address of a libc function in the target, assuming it maps the same libc file
+fn remote_symbol(process: &RemoteProcess, name: &CStr) -> Result<usize, Error> {
+    let local = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+    if local.is_null() {
+        return Err(Error::ModuleNotFound);
+    }
+    let mut info = unsafe { std::mem::zeroed::<libc::Dl_info>() };
+    if unsafe { libc::dladdr(local, &mut info) } == 0 || info.dli_fname.is_null() {
+        return Err(Error::ModuleNotFound);
+    }
+    let path = unsafe { CStr::from_ptr(info.dli_fname) };
+    let base = process
+        .module_base(Path::new(std::ffi::OsStr::from_bytes(path.to_bytes())))
+        .ok_or(Error::ModuleNotFound)?;
+    Ok(base + (local.addr() - (info.dli_fbase as *const c_void).addr()))
+}
+
+fn read_c_string(process: &RemoteProcess, addr: usize) -> String {
+    let mut bytes = vec![];
+    let mut chunk = [0u8; 0x40];
+    while bytes.len() < 0x1000 && process.read(addr + bytes.len(), &mut chunk).is_ok() {
+        match chunk.iter().position(|x| *x == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                break;
+            }
+            None => bytes.extend_from_slice(&chunk),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// `dlopen` `library` in an already attached process
+pub fn inject_into(process: &RemoteProcess, library: &Path) -> Result<Injection, Error> {
+    let library = library.canonicalize().map_err(|_| Error::ModuleNotFound)?;
+    let library =
+        CString::new(library.as_os_str().as_bytes()).map_err(|_| Error::ModuleNotFound)?;
+    if CALL_STUB.len() + library.as_bytes_with_nul().len() > STUB_SIZE {
+        return Err(Error::NotEnoughMemory);
+    }
+
+    let dlopen = remote_symbol(process, c"dlopen")?;
+    let dlerror = remote_symbol(process, c"dlerror")?;
+
+    let prot = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    let stub = process.syscall(
+        libc::SYS_mmap,
+        [0, STUB_SIZE as u64, prot as u64, flags as u64, u64::MAX, 0],
+    )? as usize;
+
+    let path = stub + CALL_STUB.len();
+    let result = process
+        .write(stub, &CALL_STUB)
+        .and_then(|_| process.write(path, library.as_bytes_with_nul()))
+        .and_then(|_| {
+            let flag = (libc::RTLD_NOW | libc::RTLD_GLOBAL) as u64;
+            process.call(stub, dlopen, [path as u64, flag, 0, 0, 0, 0])
+        })
+        .and_then(|handle| match handle {
+            0 => {
+                let reason = process.call(stub, dlerror, [0; 6])?;
+                Err(Error::LibraryLoad(match reason {
+                    0 => String::from("unknown error"),
+                    reason => read_c_string(process, reason as usize),
+                }))
+            }
+            handle => Ok(Injection {
+                pid: process.pid(),
+                handle: handle as usize,
+            }),
+        });
+
+    let _ = process.syscall(
+        libc::SYS_munmap,
+        [stub as u64, STUB_SIZE as u64, 0, 0, 0, 0],
+    );
+    result
+}
+
+/// attach to `pid`, `dlopen` `library` and detach again
+///
+/// `dlopen` is looked up in the libc file this process maps, a target mapping another libc,
+/// e.g. from a container or a static build, fails with [`Error::ModuleNotFound`]
+pub fn inject(pid: u32, library: &Path) -> Result<Injection, Error> {
+    inject_into(&RemoteProcess::attach(pid)?, library)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::spawn;
+    use std::process::Command;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    #[test]
+    fn constructor_runs_in_target() {
+        let target = spawn(Command::new("sleep").arg("60"));
+        let pid = target.0.id();
+        let library = Path::new(FIXTURES).join("inject.so");
+        let injection = inject(pid, &library).unwrap();
+        assert_eq!(injection.pid, pid);
+        assert_ne!(injection.handle, 0);
+
+        let maps = std::fs::read_to_string(format!("/proc/{pid}/maps")).unwrap();
+        let library = library.canonicalize().unwrap();
+        assert!(maps.contains(library.to_str().unwrap()));
+        // the library renames the thread which ran its constructor
+        let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).unwrap();
+        assert_eq!(comm.trim_end(), "injected");
+    }
+
+    #[test]
+    fn non_library_is_reported() {
+        let target = spawn(Command::new("sleep").arg("60"));
+        let script = Path::new(FIXTURES).join("mkso.py");
+        let injected = inject(target.0.id(), &script);
+        assert!(matches!(injected, Err(Error::LibraryLoad(reason)) if reason.contains("mkso.py")));
+        let missing = Path::new(FIXTURES).join("missing.so");
+        assert!(matches!(
+            inject(target.0.id(), &missing),
+            Err(Error::ModuleNotFound)
+        ));
+    }
+}
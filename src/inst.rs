@@ -1,16 +1,44 @@
 #[cfg(target_pointer_width = "16")]
-const BITNESS: u32 = 16;
+pub(crate) const BITNESS: u32 = 16;
 #[cfg(target_pointer_width = "32")]
-const BITNESS: u32 = 32;
+pub(crate) const BITNESS: u32 = 32;
 #[cfg(target_pointer_width = "64")]
-const BITNESS: u32 = 64;
+pub(crate) const BITNESS: u32 = 64;
 
 #[allow(dead_code)]
 mod __private {
+    use crate::backend::MemoryBackend;
     use crate::inst::BITNESS;
     use iced_x86::{Decoder, DecoderOptions, Instruction};
     use std::ptr::slice_from_raw_parts;
 
+    const PAGE_SIZE: usize = 0x1000;
+
+    /// read up to `buf.len()` bytes, stop at the first unreadable page
+    pub fn read_code(backend: &dyn MemoryBackend, addr: usize, buf: &mut [u8]) -> usize {
+        if backend.read(addr, buf).is_ok() {
+            return buf.len();
+        }
+        let head = (PAGE_SIZE - addr % PAGE_SIZE).min(buf.len());
+        if backend.read(addr, &mut buf[..head]).is_ok() {
+            head
+        } else {
+            0
+        }
+    }
+
+    /// decode one instruction through a memory backend
+    pub fn decode_from(backend: &dyn MemoryBackend, addr: usize) -> Instruction {
+        let mut raw_inst = [0u8; 0xf];
+        let len = read_code(backend, addr, &mut raw_inst);
+        let mut decoder =
+            Decoder::with_ip(BITNESS, &raw_inst[..len], addr as u64, DecoderOptions::NONE);
+        if !decoder.can_decode() {
+            return Instruction::default();
+        }
+        decoder.decode()
+    }
+
     pub unsafe fn decode_instruction<const N: usize>(addr: usize) -> Instruction {
         let raw_inst = unsafe { core::ptr::read::<[u8; N]>(addr as *const _) };
         let mut decoder = Decoder::with_ip(BITNESS, &raw_inst, addr as u64, DecoderOptions::NONE);
@@ -20,6 +48,10 @@ mod __private {
         decoder.decode()
     }
 
+    pub fn decoder_for(data: &[u8], ip: usize) -> Decoder<'_> {
+        Decoder::with_ip(BITNESS, data, ip as u64, DecoderOptions::NONE)
+    }
+
     pub fn decoder<T>(addr: *const T) -> Decoder<'static> {
         decoder_with_size(addr, 0xe)
     }
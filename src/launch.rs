@@ -0,0 +1,518 @@
+//! start programs with hook libraries preloaded and keep them in child processes
+//!
+//! the Linux counterpart of `withdll` and `DetourCreateProcessWithDlls`. [`Launcher`] sets
+//! `LD_PRELOAD`, the hook library invokes [`follow_children!`](crate::follow_children) so
+//! programs which rebuild their environment before `execve`/`posix_spawn` still pass the
+//! libraries and every `DETOURS_` variable on
+
+use crate::Error;
+use crate::payload::{PAYLOAD_FD_ENV, Payload, Payloads};
+use std::ffi::{CStr, CString, OsStr, OsString, c_char, c_int};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::OnceLock;
+
+pub const PRELOAD_ENV: &str = "LD_PRELOAD";
+/// libraries the launcher preloaded, kept apart from whatever else is in `LD_PRELOAD`
+pub const LIBRARIES_ENV: &str = "DETOURS_LIBRARIES";
+/// variables with this prefix are configuration and travel to children
+pub const ENV_PREFIX: &str = "DETOURS_";
+
+pub struct Launcher {
+    command: Command,
+    libraries: Vec<PathBuf>,
+    payloads: Payloads,
+}
+
+impl Launcher {
+    pub fn new(program: impl AsRef<OsStr>) -> Launcher {
+        Launcher {
+            command: Command::new(program),
+            libraries: vec![],
+            payloads: Payloads::new(),
+        }
+    }
+
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Launcher {
+        self.command.arg(arg);
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(&mut self, args: I) -> &mut Launcher {
+        self.command.args(args);
+        self
+    }
+
+    /// preload `path`, relative paths are made absolute so children can find it
+    pub fn library(&mut self, path: impl AsRef<Path>) -> &mut Launcher {
+        let path = path.as_ref();
+        self.libraries
+            .push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+        self
+    }
+
+    /// configuration for the hook library, stored as `DETOURS_<key>`
+    pub fn config(&mut self, key: &str, value: impl AsRef<OsStr>) -> &mut Launcher {
+        self.command.env(format!("{ENV_PREFIX}{key}"), value);
+        self
+    }
+
+    /// hand `value` to the hook library, see [`crate::payload::find`]
+    pub fn payload<T: Payload>(&mut self, value: &T) -> &mut Launcher {
+        self.payloads.insert(value);
+        self
+    }
+
+    /// underlying command for anything else, e.g. working directory or stdio
+    pub fn command(&mut self) -> &mut Command {
+        &mut self.command
+    }
+
+    pub fn spawn(&mut self) -> Result<Child, Error> {
+        let libraries = join(self.libraries.iter().map(|x| x.as_os_str()));
+        let preload = std::env::var_os(PRELOAD_ENV).unwrap_or_default();
+        // kept open until the child inherited it
+        let memfd = match self.payloads.is_empty() {
+            true => None,
+            false => Some(self.payloads.to_memfd()?),
+        };
+        if let Some(fd) = &memfd {
+            self.command.env(PAYLOAD_FD_ENV, fd.as_raw_fd().to_string());
+        }
+        self.command
+            .env(PRELOAD_ENV, merge_preload(&preload, &libraries))
+            .env(LIBRARIES_ENV, libraries)
+            .spawn()
+            .map_err(|err| Error::ErrorCode(err.raw_os_error().unwrap_or(0) as usize))
+    }
+}
+
+fn join<'a>(parts: impl Iterator<Item = &'a OsStr>) -> OsString {
+    let mut joined = OsString::new();
+    for part in parts {
+        if !joined.is_empty() {
+            joined.push(":");
+        }
+        joined.push(part);
+    }
+    joined
+}
+
+/// append `libraries` missing from `preload`
+fn merge_preload(preload: &OsStr, libraries: &OsStr) -> OsString {
+    let present: Vec<&[u8]> = preload
+        .as_bytes()
+        .split(|x| *x == b':' || *x == b' ')
+        .collect();
+    let missing = libraries
+        .as_bytes()
+        .split(|x| *x == b':')
+        .filter(|x| !x.is_empty() && !present.contains(x))
+        .map(OsStr::from_bytes);
+    join(
+        std::iter::once(preload)
+            .filter(|x| !x.is_empty())
+            .chain(missing),
+    )
+}
+
+/// `DETOURS_` variables of this process captured before the program could drop them
+fn inherited() -> &'static Vec<(OsString, OsString)> {
+    static INHERITED: OnceLock<Vec<(OsString, OsString)>> = OnceLock::new();
+    INHERITED.get_or_init(|| {
+        std::env::vars_os()
+            .filter(|(key, _)| key.as_bytes().starts_with(ENV_PREFIX.as_bytes()))
+            .collect()
+    })
+}
+
+/// capture configuration and payloads, called by [`follow_children!`](crate::follow_children)
+/// on load
+pub fn init() {
+    inherited();
+    crate::payload::payloads();
+}
+
+/// environment `envp` completed with the preload libraries and configuration
+pub fn child_env(envp: &[&CStr]) -> Vec<CString> {
+    complete_env(envp, inherited())
+}
+
+fn complete_env(envp: &[&CStr], inherited: &[(OsString, OsString)]) -> Vec<CString> {
+    let key_of = |entry: &[u8]| entry.split(|x| *x == b'=').next().unwrap_or(&[]).to_vec();
+    let libraries = inherited
+        .iter()
+        .find(|(key, _)| key == LIBRARIES_ENV)
+        .map(|(_, value)| value.clone())
+        .unwrap_or_default();
+
+    let mut env: Vec<Vec<u8>> = vec![];
+    let mut preload_seen = false;
+    for entry in envp.iter().map(|x| x.to_bytes()) {
+        if key_of(entry) == PRELOAD_ENV.as_bytes() {
+            let preload = OsStr::from_bytes(&entry[PRELOAD_ENV.len() + 1..]);
+            let mut merged = format!("{PRELOAD_ENV}=").into_bytes();
+            merged.extend(merge_preload(preload, &libraries).into_vec());
+            env.push(merged);
+            preload_seen = true;
+        } else {
+            env.push(entry.to_vec());
+        }
+    }
+    if !preload_seen && !libraries.is_empty() {
+        let mut preload = format!("{PRELOAD_ENV}=").into_bytes();
+        preload.extend(libraries.as_bytes());
+        env.push(preload);
+    }
+    for (key, value) in inherited {
+        if env.iter().any(|x| key_of(x) == key.as_bytes()) {
+            continue;
+        }
+        let mut entry = key.as_bytes().to_vec();
+        entry.push(b'=');
+        entry.extend(value.as_bytes());
+        env.push(entry);
+    }
+
+    env.into_iter()
+        .filter_map(|x| CString::new(x).ok())
+        .collect()
+}
+
+/// # Safety
+///
+/// `envp` must be null or a null terminated array of C strings
+unsafe fn entries<'a>(envp: *const *const c_char) -> Vec<&'a CStr> {
+    let mut entries = vec![];
+    if !envp.is_null() {
+        let mut cursor = envp;
+        while !unsafe { *cursor }.is_null() {
+            entries.push(unsafe { CStr::from_ptr(*cursor) });
+            cursor = cursor.wrapping_add(1);
+        }
+    }
+    entries
+}
+
+/// # Safety
+///
+/// `envp` must be null or a null terminated array of C strings
+unsafe fn with_child_env<R>(
+    envp: *const *const c_char,
+    f: impl FnOnce(*const *const c_char) -> R,
+) -> R {
+    let env = child_env(&unsafe { entries(envp) });
+    let mut ptrs: Vec<*const c_char> = env.iter().map(|x| x.as_ptr()).collect();
+    ptrs.push(std::ptr::null());
+    f(ptrs.as_ptr())
+}
+
+fn next<T>(name: &CStr) -> Option<T> {
+    let addr = unsafe { libc::dlsym(libc::RTLD_NEXT, name.as_ptr()) };
+    (!addr.is_null()).then(|| unsafe { std::mem::transmute_copy::<*mut libc::c_void, T>(&addr) })
+}
+
+/// `-1` with `errno` set like libc reports a missing function
+fn unsupported<T: From<i8>>() -> T {
+    unsafe { *libc::__errno_location() = libc::ENOSYS };
+    T::from(-1)
+}
+
+type ExecveFn =
+    unsafe extern "C" fn(*const c_char, *const *const c_char, *const *const c_char) -> c_int;
+type SpawnFn = unsafe extern "C" fn(
+    *mut libc::pid_t,
+    *const c_char,
+    *const libc::posix_spawn_file_actions_t,
+    *const libc::posix_spawnattr_t,
+    *const *mut c_char,
+    *const *mut c_char,
+) -> c_int;
+type ForkFn = unsafe extern "C" fn() -> libc::pid_t;
+
+/// `execve` passing the libraries on
+///
+/// # Safety
+///
+/// same as `execve`
+pub unsafe fn execve(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    let Some(real) = next::<ExecveFn>(c"execve") else {
+        return unsupported();
+    };
+    unsafe { with_child_env(envp, |envp| real(path, argv, envp)) }
+}
+
+/// `execvpe` passing the libraries on
+///
+/// # Safety
+///
+/// same as `execvpe`
+pub unsafe fn execvpe(
+    file: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    let Some(real) = next::<ExecveFn>(c"execvpe") else {
+        return unsupported();
+    };
+    unsafe { with_child_env(envp, |envp| real(file, argv, envp)) }
+}
+
+unsafe extern "C" {
+    static mut environ: *const *const c_char;
+}
+
+/// `execv` passing the libraries on, libc does not route it through the `execve` symbol
+///
+/// # Safety
+///
+/// same as `execv`
+pub unsafe fn execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+    unsafe { execve(path, argv, environ) }
+}
+
+/// `execvp` passing the libraries on
+///
+/// # Safety
+///
+/// same as `execvp`
+pub unsafe fn execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+    unsafe { execvpe(file, argv, environ) }
+}
+
+unsafe fn spawn(
+    name: &CStr,
+    pid: *mut libc::pid_t,
+    path: *const c_char,
+    file_actions: *const libc::posix_spawn_file_actions_t,
+    attrp: *const libc::posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    let Some(real) = next::<SpawnFn>(name) else {
+        return libc::ENOSYS;
+    };
+    unsafe {
+        with_child_env(envp.cast(), |envp| {
+            real(pid, path, file_actions, attrp, argv, envp.cast())
+        })
+    }
+}
+
+/// `posix_spawn` passing the libraries on
+///
+/// # Safety
+///
+/// same as `posix_spawn`
+pub unsafe fn posix_spawn(
+    pid: *mut libc::pid_t,
+    path: *const c_char,
+    file_actions: *const libc::posix_spawn_file_actions_t,
+    attrp: *const libc::posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    unsafe { spawn(c"posix_spawn", pid, path, file_actions, attrp, argv, envp) }
+}
+
+/// `posix_spawnp` passing the libraries on
+///
+/// # Safety
+///
+/// same as `posix_spawnp`
+pub unsafe fn posix_spawnp(
+    pid: *mut libc::pid_t,
+    file: *const c_char,
+    file_actions: *const libc::posix_spawn_file_actions_t,
+    attrp: *const libc::posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    unsafe { spawn(c"posix_spawnp", pid, file, file_actions, attrp, argv, envp) }
+}
+
+/// `fork` restoring the variables in the child, `execv` and friends read `environ`
+/// inside libc without going through the `execve` symbol
+///
+/// the environment is built before forking, the child of a threaded program may only
+/// point `environ` at it
+///
+/// # Safety
+///
+/// same as `fork`
+pub unsafe fn fork() -> libc::pid_t {
+    let Some(real) = next::<ForkFn>(c"fork") else {
+        return unsupported();
+    };
+    let current = unsafe { entries(environ) };
+    let env = child_env(&current);
+    // only touch the environment when the program dropped something
+    let changed = env.len() != current.len() || env.iter().zip(&current).any(|(a, b)| **a != **b);
+    let mut ptrs: Vec<*const c_char> = env.iter().map(|x| x.as_ptr()).collect();
+    ptrs.push(std::ptr::null());
+
+    let pid = unsafe { real() };
+    if pid == 0 && changed {
+        unsafe { environ = ptrs.as_ptr() };
+        // owned by `environ` now, freeing is not allowed after fork either
+        std::mem::forget(ptrs);
+        std::mem::forget(env);
+    }
+    pid
+}
+
+/// export `execve`, `execv`, `execvp`, `execvpe`, `posix_spawn`, `posix_spawnp` and `fork`
+/// from a hook library so child processes get it preloaded as well
+///
+/// use once in the `cdylib` passed to [`Launcher::library`], the variadic `execl` family
+/// is not covered
+#[macro_export]
+macro_rules! follow_children {
+    () => {
+        #[used]
+        #[unsafe(link_section = ".init_array")]
+        static __DETOURS_FOLLOW_INIT: extern "C" fn() = {
+            extern "C" fn init() {
+                $crate::launch::init();
+            }
+            init
+        };
+
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn execve(
+            path: *const std::ffi::c_char,
+            argv: *const *const std::ffi::c_char,
+            envp: *const *const std::ffi::c_char,
+        ) -> std::ffi::c_int {
+            unsafe { $crate::launch::execve(path, argv, envp) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn execv(
+            path: *const std::ffi::c_char,
+            argv: *const *const std::ffi::c_char,
+        ) -> std::ffi::c_int {
+            unsafe { $crate::launch::execv(path, argv) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn execvp(
+            file: *const std::ffi::c_char,
+            argv: *const *const std::ffi::c_char,
+        ) -> std::ffi::c_int {
+            unsafe { $crate::launch::execvp(file, argv) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn execvpe(
+            file: *const std::ffi::c_char,
+            argv: *const *const std::ffi::c_char,
+            envp: *const *const std::ffi::c_char,
+        ) -> std::ffi::c_int {
+            unsafe { $crate::launch::execvpe(file, argv, envp) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn posix_spawn(
+            pid: *mut $crate::launch::pid_t,
+            path: *const std::ffi::c_char,
+            file_actions: *const $crate::launch::posix_spawn_file_actions_t,
+            attrp: *const $crate::launch::posix_spawnattr_t,
+            argv: *const *mut std::ffi::c_char,
+            envp: *const *mut std::ffi::c_char,
+        ) -> std::ffi::c_int {
+            unsafe { $crate::launch::posix_spawn(pid, path, file_actions, attrp, argv, envp) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn posix_spawnp(
+            pid: *mut $crate::launch::pid_t,
+            file: *const std::ffi::c_char,
+            file_actions: *const $crate::launch::posix_spawn_file_actions_t,
+            attrp: *const $crate::launch::posix_spawnattr_t,
+            argv: *const *mut std::ffi::c_char,
+            envp: *const *mut std::ffi::c_char,
+        ) -> std::ffi::c_int {
+            unsafe { $crate::launch::posix_spawnp(pid, file, file_actions, attrp, argv, envp) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn fork() -> $crate::launch::pid_t {
+            unsafe { $crate::launch::fork() }
+        }
+    };
+}
+
+pub use libc::{pid_t, posix_spawn_file_actions_t, posix_spawnattr_t};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inherited() -> Vec<(OsString, OsString)> {
+        vec![
+            (LIBRARIES_ENV.into(), "/hook/a.so:/hook/b.so".into()),
+            ("DETOURS_LEVEL".into(), "2".into()),
+        ]
+    }
+
+    fn complete(envp: &[&CStr]) -> Vec<CString> {
+        complete_env(envp, &inherited())
+    }
+
+    #[test]
+    fn dropped_variables_are_restored() {
+        let env = complete(&[c"HOME=/root"]);
+        assert_eq!(
+            env,
+            [
+                c"HOME=/root",
+                c"LD_PRELOAD=/hook/a.so:/hook/b.so",
+                c"DETOURS_LIBRARIES=/hook/a.so:/hook/b.so",
+                c"DETOURS_LEVEL=2",
+            ]
+        );
+    }
+
+    #[test]
+    fn preload_is_merged_and_config_kept() {
+        let env = complete(&[
+            c"LD_PRELOAD=/other.so /hook/b.so",
+            c"DETOURS_LEVEL=3",
+            c"DETOURS_LIBRARIES=/hook/a.so:/hook/b.so",
+        ]);
+        assert_eq!(
+            env,
+            [
+                c"LD_PRELOAD=/other.so /hook/b.so:/hook/a.so",
+                c"DETOURS_LEVEL=3",
+                c"DETOURS_LIBRARIES=/hook/a.so:/hook/b.so",
+            ]
+        );
+    }
+
+    #[test]
+    fn complete_environment_is_unchanged() {
+        let envp = [
+            c"LD_PRELOAD=/hook/a.so:/hook/b.so",
+            c"DETOURS_LIBRARIES=/hook/a.so:/hook/b.so",
+            c"DETOURS_LEVEL=2",
+        ];
+        assert_eq!(complete(&envp), envp);
+    }
+
+    #[test]
+    fn missing_function_sets_errno() {
+        assert_eq!(unsupported::<c_int>(), -1);
+        assert_eq!(
+            std::io::Error::last_os_error().raw_os_error(),
+            Some(libc::ENOSYS)
+        );
+    }
+}
@@ -1,9 +1,19 @@
-//! Pure rust version `Detours` library currently only supported windows platform and arch x86
+//! Pure rust version `Detours` library for Windows and Linux on x86 and x86_64
+//!
+//! * inline hooks with relocated trampolines, [`Detours`] patches the current process, a
+//!   [`backend::MemoryBackend`] such as [`backend::RemoteProcess`] or
+//!   [`backend::SimulatedMemory`] moves the patching elsewhere
+//! * import hooks through the IAT ([`iat`]) or the GOT on Linux ([`got`])
+//! * editing PE and ELF files on disk ([`binary`])
+//! * on Linux, starting programs with preloaded libraries ([`launch`], [`payload`]) and
+//!   loading a library into a running process ([`inject`], x86_64 only)
+//! * module offsets keyed by build identity ([`offsets`]) and byte pattern scans ([`scan`])
 //!
 //! # Example
 //!
 //! As DLL hook address function
 //!
+//! ```ignore
 //! use detours_rs::ext::Pointer;
 //! use detours_rs::{Detours, transmute_void};
 //! use parking_lot::RwLock;
@@ -46,16 +56,35 @@
 //!     }
 //!     true
 //! }
-//!
+//! ```
 
+pub mod alloc;
+pub mod backend;
+pub mod binary;
+pub mod copy;
 mod detours;
 mod error;
+#[cfg(target_os = "linux")]
+pub mod got;
+pub mod iat;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub mod inject;
 mod inst;
 #[macro_use]
 pub mod ext;
+#[cfg(target_os = "linux")]
+pub mod launch;
 mod mem;
-pub use mem::{raw_read, raw_write};
+pub mod offsets;
+#[cfg(target_os = "linux")]
+pub mod payload;
+pub mod pe;
+pub mod scan;
+pub mod thunk;
+pub use mem::{RegionMode, RegionStats, raw_read, raw_write};
 pub(crate) mod platform;
 
-pub use detours::{Detour, Detours, DetoursGuard};
+pub use copy::copy_instructions;
+pub use detours::{CodeCave, Detour, Detours, DetoursGuard, JumpKind};
 pub use error::Error;
+pub use thunk::code_from_pointer;
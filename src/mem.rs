@@ -1,296 +1,522 @@
-use crate::platform::{
-    MEM_TYPE_COMMIT, MEM_TYPE_FREE, MEM_TYPE_RESERVE, MemoryProtector, PAGE_FLAG_EXECUTE_READ,
-    PAGE_FLAG_EXECUTE_READWRITE, valloc, vfree, vprotect, vquery,
-};
-#[cfg(target_os = "windows")]
-use crate::platform::{flush_instruction_cache, get_current_process};
+use crate::alloc::{AddressSpace, AllocationStrategy, RegionInfo, RegionState, SearchStrategy};
+use crate::backend::{DualMapping, MemoryBackend, MemoryProtector, ProcessMemory, Protection};
 use crate::{Error, inst, platform};
 use std::ffi::c_void;
 use std::ops::{Range, RangeInclusive};
 use std::ptr;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicPtr, Ordering};
 
 pub(crate) const DETOUR_REGION_SIZE: usize = 0x10000;
 
-const SYSTEM_REGION_BOUND: RangeInclusive<usize> = 0x70000000..=0x80000000;
-
-fn detour_alloc_round_down_to_region(pb_try: usize) -> usize {
-    let extra = pb_try & (DETOUR_REGION_SIZE - 1);
-    if extra != 0 { pb_try - extra } else { pb_try }
+// distance a region may lie from an address and still be reached by rel32 from all of it
+const REL32_REACH: usize = 0x7fff_0000 - DETOUR_REGION_SIZE;
+
+/// how trampoline regions are mapped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RegionMode {
+    /// single mapping toggled between RWX and RX by guard
+    #[default]
+    ReadWriteExecute,
+    /// shared section mapped twice, RW view for writing and RX view for execution,
+    /// no page is ever writable and executable at the same time
+    DualMapped,
 }
 
-fn detour_alloc_round_up_to_region(pb_try: usize) -> usize {
-    let extra = pb_try & (DETOUR_REGION_SIZE - 1);
-    if extra != 0 {
-        pb_try + DETOUR_REGION_SIZE - extra
-    } else {
-        pb_try
-    }
+pub(crate) struct RegionMemory {
+    exec: usize,
+    write: usize,
+    dual: Option<DualMapping>,
+    // committed inside a caller reservation, decommit instead of release
+    decommit: bool,
 }
 
-fn detour_alloc_region_from_hi(range: Range<usize>) -> Option<usize> {
-    let mut pb_try = detour_alloc_round_down_to_region(range.end - DETOUR_REGION_SIZE);
-    while pb_try > range.start {
-        if SYSTEM_REGION_BOUND.contains(&pb_try) {
-            pb_try -= 0x08000000;
-            continue;
+fn detour_alloc_region_at(
+    backend: &dyn MemoryBackend,
+    pb_try: usize,
+    mode: RegionMode,
+) -> Option<RegionMemory> {
+    match mode {
+        RegionMode::ReadWriteExecute => {
+            backend
+                .alloc(pb_try, DETOUR_REGION_SIZE)
+                .map(|pv| RegionMemory {
+                    exec: pv,
+                    write: pv,
+                    dual: None,
+                    decommit: false,
+                })
         }
+        RegionMode::DualMapped => {
+            backend
+                .alloc_dual(pb_try, DETOUR_REGION_SIZE)
+                .map(|dual| RegionMemory {
+                    exec: dual.exec,
+                    write: dual.write,
+                    dual: Some(dual),
+                    decommit: false,
+                })
+        }
+    }
+}
 
-        let Some(mbi) = vquery(pb_try as *const _) else {
-            break;
-        };
+fn detour_free_region(
+    backend: &dyn MemoryBackend,
+    exec: usize,
+    dual: Option<&DualMapping>,
+    decommit: bool,
+) -> Result<(), Error> {
+    match dual {
+        Some(dual) => backend.free_dual(dual),
+        None if decommit => backend.decommit(exec, DETOUR_REGION_SIZE),
+        None => backend.free(exec),
+    }
+}
 
-        if mbi.state == MEM_TYPE_FREE && mbi.region_size >= DETOUR_REGION_SIZE {
-            if let Some(pv) = valloc(
-                pb_try as *const _,
-                DETOUR_REGION_SIZE,
-                MEM_TYPE_COMMIT | MEM_TYPE_RESERVE,
-            ) {
-                #[cfg(target_os = "windows")]
-                if platform::check_dynamic_code_blocked() {
-                    return None;
-                }
-                return Some(pv as _);
-            }
-            pb_try -= DETOUR_REGION_SIZE;
-        } else {
-            pb_try = detour_alloc_round_down_to_region(
-                mbi.allocation_base.wrapping_byte_sub(DETOUR_REGION_SIZE) as usize,
-            );
-        }
+// sections can not be mapped over a reservation, only plain regions commit in place
+fn detour_commit_region_at(
+    backend: &dyn MemoryBackend,
+    pb_try: usize,
+    mode: RegionMode,
+) -> Option<RegionMemory> {
+    if mode != RegionMode::ReadWriteExecute {
+        return None;
     }
-    None
+    backend
+        .commit(pb_try, DETOUR_REGION_SIZE)
+        .map(|pv| RegionMemory {
+            exec: pv,
+            write: pv,
+            dual: None,
+            decommit: true,
+        })
 }
 
-fn detour_alloc_region_from_lo(range: Range<usize>) -> Option<usize> {
-    let mut pb_try = detour_alloc_round_up_to_region(range.start);
-    while pb_try < range.end {
-        if SYSTEM_REGION_BOUND.contains(&pb_try) {
-            pb_try += 0x08000000;
-            continue;
-        }
+/// backend memory as seen by allocation strategy
+struct BackendSpace<'a> {
+    backend: &'a dyn MemoryBackend,
+    mode: RegionMode,
+    // every region the strategy allocated, it may probe more than it returns
+    allocated: Vec<RegionMemory>,
+}
 
-        let Some(mbi) = vquery(pb_try as *const _) else {
-            break;
-        };
+impl AddressSpace for BackendSpace<'_> {
+    fn query(&self, addr: usize) -> Option<RegionInfo> {
+        self.backend.query(addr)
+    }
 
-        if mbi.state == MEM_TYPE_FREE && mbi.region_size >= DETOUR_REGION_SIZE {
-            if let Some(pv) = valloc(
-                pb_try as *const _,
-                DETOUR_REGION_SIZE,
-                MEM_TYPE_COMMIT | MEM_TYPE_RESERVE,
-            ) {
-                #[cfg(target_os = "windows")]
-                if platform::check_dynamic_code_blocked() {
-                    return None;
-                }
-                return Some(pv as _);
-            }
-            pb_try += DETOUR_REGION_SIZE;
+    fn alloc_at(&mut self, addr: usize) -> bool {
+        let reserved = self
+            .query(addr)
+            .is_some_and(|x| x.state == RegionState::Reserve);
+        let memory = if reserved {
+            detour_commit_region_at(self.backend, addr, self.mode)
         } else {
-            pb_try = detour_alloc_round_up_to_region(
-                mbi.base_address.wrapping_byte_add(mbi.region_size) as usize,
-            );
-        }
+            detour_alloc_region_at(self.backend, addr, self.mode)
+        };
+        memory.map(|x| self.allocated.push(x)).is_some()
+    }
+
+    fn is_blocked(&self) -> bool {
+        self.backend.is_dynamic_code_blocked()
     }
-    None
 }
 
-fn detour_alloc_trampoline_allocate_new(
-    target: usize,
-    range: &RangeInclusive<usize>,
-) -> Option<usize> {
-    let mut pb_try = None;
-
-    #[cfg(target_pointer_width = "64")]
-    let _ = {
-        // Try looking 1GB below or lower.
-        if pb_try.is_none() && target > 0x40000000 {
-            pb_try = detour_alloc_region_from_hi(*range.start()..target - 0x40000000);
-        }
-        // Try looking 1GB above or higher.
-        if pb_try.is_none() && target < 0xffffffff40000000 {
-            pb_try = detour_alloc_region_from_lo(target + 0x40000000..*range.end());
-        }
-        if pb_try.is_none() && target < 0xffffffff40000000 {
-            pb_try = detour_alloc_region_from_lo(target + 0x40000000..*range.end());
-        }
-        // Try looking 1GB below or higher.
-        if pb_try.is_none() && target > 0x40000000 {
-            pb_try = detour_alloc_region_from_lo(target - 0x40000000..target);
-        }
-        // Try looking 1GB above or lower.
-        if pb_try.is_none() && target < 0xffffffff40000000 {
-            pb_try = detour_alloc_region_from_hi(target..target + 0x40000000);
-        }
-    };
+/// trampoline slot, `exec` is the address code runs at and `write` the address it is written through,
+/// both are equal unless region is dual mapped
+pub struct Block<T> {
+    exec: AtomicPtr<T>,
+    write: AtomicPtr<T>,
+}
 
-    // Try anything below.
-    if pb_try.is_none() {
-        pb_try = detour_alloc_region_from_hi(*range.start()..target);
+impl<T> Block<T> {
+    pub fn exec_ptr(&self) -> *mut T {
+        self.exec.load(Ordering::Relaxed)
     }
-    // try anything above.
-    if pb_try.is_none() {
-        pb_try = detour_alloc_region_from_lo(target..*range.end());
+
+    pub fn write_ptr(&self) -> *mut T {
+        self.write.load(Ordering::Relaxed)
     }
 
-    pb_try
+    pub(crate) fn exec_slot(&self) -> &AtomicPtr<T> {
+        &self.exec
+    }
 }
 
-pub struct Block<T>(AtomicPtr<T>);
-
 impl<T> Default for Block<T> {
     fn default() -> Self {
-        Self(AtomicPtr::default())
+        Self {
+            exec: AtomicPtr::default(),
+            write: AtomicPtr::default(),
+        }
     }
 }
 
 impl<T> AsRef<T> for Block<T> {
     fn as_ref(&self) -> &T {
-        unsafe { self.0.load(Ordering::Relaxed).as_ref().unwrap() }
+        unsafe { self.exec_ptr().as_ref().unwrap() }
     }
 }
 
 impl<T> AsMut<T> for Block<T> {
     fn as_mut(&mut self) -> &mut T {
-        unsafe { self.0.load(Ordering::Relaxed).as_mut().unwrap() }
+        unsafe { self.write_ptr().as_mut().unwrap() }
     }
 }
 
+const BITMAP_WORD: usize = u64::BITS as usize;
+
+// free list gives O(1) alloc and free, bitmap guards against double free
 pub struct RegionData<const N: usize> {
-    free: Option<usize>,
+    backend: Arc<dyn MemoryBackend>,
     range: Range<usize>,
-    state: [bool; N],
+    write: usize,
+    dual: Option<DualMapping>,
+    decommit: bool,
+    // reserved up front, kept even when empty
+    pinned: bool,
+    free: Vec<u32>,
+    used: Vec<u64>,
 }
 
 impl<const N: usize> RegionData<N> {
+    fn new(backend: Arc<dyn MemoryBackend>, memory: RegionMemory) -> RegionData<N> {
+        RegionData {
+            backend,
+            range: memory.exec..memory.exec + DETOUR_REGION_SIZE,
+            write: memory.write,
+            dual: memory.dual,
+            decommit: memory.decommit,
+            pinned: false,
+            free: (0..N as u32).rev().collect(),
+            used: vec![0; N.div_ceil(BITMAP_WORD)],
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.used[index / BITMAP_WORD] & (1 << (index % BITMAP_WORD)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        let word = &mut self.used[index / BITMAP_WORD];
+        if used {
+            *word |= 1 << (index % BITMAP_WORD);
+        } else {
+            *word &= !(1 << (index % BITMAP_WORD));
+        }
+    }
+
+    fn used_count(&self) -> usize {
+        N - self.free.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.free.len() == N
+    }
+
     fn get_free_addr<T>(&self) -> Option<usize> {
         self.free
-            .map(|index| self.range.start + index * size_of::<T>())
+            .last()
+            .map(|index| self.range.start + *index as usize * size_of::<T>())
     }
 
-    fn find_free_index(&self) -> Option<usize> {
-        self.state
-            .iter()
-            .enumerate()
-            .find(|(_, b)| !**b)
-            .map(|(index, _)| index)
+    fn next_free_block<T>(&mut self) -> Option<Block<T>> {
+        let index = self.free.pop()? as usize;
+        self.set_used(index, true);
+        Some(Block {
+            exec: AtomicPtr::new((self.range.start + index * size_of::<T>()) as *mut T),
+            write: AtomicPtr::new((self.write + index * size_of::<T>()) as *mut T),
+        })
     }
 
-    fn next_free_block<T>(&mut self) -> Option<Block<T>> {
-        let block = self.free.take().or(self.find_free_index()).map(|index| {
-            let block = Block(AtomicPtr::new(
-                (self.range.start + index * size_of::<T>()) as *mut T,
-            ));
-            self.state[index] = true;
-            block
-        })?;
-        self.free = self.find_free_index();
-        Some(block)
+    /// first run of `count` adjacent free blocks
+    fn next_free_span<T>(&mut self, count: usize) -> Option<Block<T>> {
+        let start = (0..=N.checked_sub(count)?)
+            .find(|start| (*start..start + count).all(|index| !self.is_used(index)))?;
+        let span = start..start + count;
+        span.clone().for_each(|index| self.set_used(index, true));
+        self.free.retain(|index| !span.contains(&(*index as usize)));
+        Some(Block {
+            exec: AtomicPtr::new((self.range.start + start * size_of::<T>()) as *mut T),
+            write: AtomicPtr::new((self.write + start * size_of::<T>()) as *mut T),
+        })
     }
 
     fn free_block<T>(&mut self, block: &mut Block<T>) {
-        let addr = block.0.load(Ordering::Relaxed).addr();
-        if self.range.contains(&addr) {
-            let free = (addr - self.range.start) / size_of::<T>();
-            self.state[free] = false;
-            self.free.replace(free);
+        let addr = block.exec_ptr().addr();
+        if !self.range.contains(&addr) {
+            return;
+        }
+        let index = (addr - self.range.start) / size_of::<T>();
+        if index < N && self.is_used(index) {
+            self.set_used(index, false);
+            self.free.push(index as u32);
         }
     }
 }
 
 impl<const N: usize> Drop for RegionData<N> {
     fn drop(&mut self) {
-        let _ = vfree(self.range.start as *mut c_void);
+        let _ = detour_free_region(
+            &*self.backend,
+            self.range.start,
+            self.dual.as_ref(),
+            self.decommit,
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegionStats {
+    pub region_blocks: usize,
+    pub regions: usize,
+    pub empty_regions: usize,
+    pub total_blocks: usize,
+    pub used_blocks: usize,
+    pub reclaimed_regions: usize,
+    pub reserved_regions: usize,
+}
+
+impl RegionStats {
+    pub fn free_blocks(&self) -> usize {
+        self.total_blocks - self.used_blocks
+    }
+
+    /// ratio of free blocks held by regions which can not be released, 0.0 when dense
+    pub fn fragmentation(&self) -> f32 {
+        let held = (self.regions - self.empty_regions) * self.region_blocks;
+        if held == 0 {
+            return 0.0;
+        }
+        (held - self.used_blocks) as f32 / held as f32
     }
 }
 
 // memory layout first chunk is region information then trampoline thunk
 pub struct Regions<const N: usize> {
+    backend: Arc<dyn MemoryBackend>,
+    mode: RegionMode,
+    strategy: Box<dyn AllocationStrategy>,
     regions: Vec<RegionData<N>>,
     current: Option<usize>,
+    reclaimed: usize,
+    // bounds covered by `reserve`, targets inside only take blocks from existing regions
+    reserved: Vec<RangeInclusive<usize>>,
 }
 
 impl<const N: usize> Regions<N> {
-    pub fn new() -> Regions<N> {
+    pub fn new(mode: RegionMode, backend: Arc<dyn MemoryBackend>) -> Regions<N> {
         Regions {
+            backend,
+            mode,
+            strategy: Box::new(SearchStrategy::default()),
             regions: vec![],
             current: None,
+            reclaimed: 0,
+            reserved: vec![],
         }
     }
 
     pub fn unlock(&self) -> Result<(), Error> {
+        if self.mode == RegionMode::DualMapped {
+            return Ok(());
+        }
         for x in self.regions.iter() {
-            vprotect(
-                x.range.start as *const (),
-                x.range.end,
-                PAGE_FLAG_EXECUTE_READWRITE,
+            self.backend.protect(
+                x.range.start,
+                x.range.end - x.range.start,
+                Protection::ReadWriteExecute,
             )?;
         }
         Ok(())
     }
 
-    pub fn lock(&self) -> Result<(), Error> {
-        #[cfg(target_os = "windows")]
-        let process = get_current_process();
+    /// release empty regions then make the rest executable
+    pub fn lock(&mut self) -> Result<(), Error> {
+        self.reclaim();
         for x in self.regions.iter() {
-            vprotect(
-                x.range.start as *const (),
-                x.range.end - x.range.start,
-                PAGE_FLAG_EXECUTE_READ,
-            )?;
-            #[cfg(target_os = "windows")]
-            flush_instruction_cache(
-                process,
-                x.range.start as *const c_void,
-                x.range.end - x.range.start,
-            )?;
+            if self.mode != RegionMode::DualMapped {
+                self.backend.protect(
+                    x.range.start,
+                    x.range.end - x.range.start,
+                    Protection::ReadExecute,
+                )?;
+            }
+            self.backend
+                .flush_instruction_cache(x.range.start, x.range.end - x.range.start)?;
         }
         Ok(())
     }
 
+    fn reclaim(&mut self) {
+        let before = self.regions.len();
+        // dropping region data release memory
+        self.regions.retain(|x| x.pinned || !x.is_empty());
+        self.reclaimed += before - self.regions.len();
+        self.current = self.regions.iter().position(|x| !x.free.is_empty());
+    }
+
+    pub fn backend(&self) -> &dyn MemoryBackend {
+        &*self.backend
+    }
+
+    pub(crate) fn shared_backend(&self) -> Arc<dyn MemoryBackend> {
+        self.backend.clone()
+    }
+
+    pub fn set_strategy(&mut self, strategy: Box<dyn AllocationStrategy>) {
+        self.strategy = strategy;
+    }
+
+    pub fn stats(&self) -> RegionStats {
+        RegionStats {
+            region_blocks: N,
+            regions: self.regions.len(),
+            empty_regions: self.regions.iter().filter(|x| x.is_empty()).count(),
+            total_blocks: self.regions.len() * N,
+            used_blocks: self.regions.iter().map(|x| x.used_count()).sum(),
+            reclaimed_regions: self.reclaimed,
+            reserved_regions: self.regions.iter().filter(|x| x.pinned).count(),
+        }
+    }
+
     fn alloc_region(
         &mut self,
         bound: &RangeInclusive<usize>,
         expect: usize,
     ) -> Option<RegionData<N>> {
         let target = expect - (expect & 0xffff);
-        let block_ptr = detour_alloc_trampoline_allocate_new(target, bound)?;
-        Some(RegionData {
-            free: Some(0),
-            range: block_ptr..block_ptr + DETOUR_REGION_SIZE,
-            state: std::array::from_fn(|_| false),
-        })
+        let mut space = BackendSpace {
+            backend: &*self.backend,
+            mode: self.mode,
+            allocated: vec![],
+        };
+        let base = self.strategy.allocate(target, bound, &mut space);
+        let index = space.allocated.iter().position(|x| Some(x.exec) == base);
+        let memory = index.map(|x| space.allocated.swap_remove(x));
+        for x in space.allocated {
+            let _ = detour_free_region(&*self.backend, x.exec, x.dual.as_ref(), x.decommit);
+        }
+        Some(RegionData::new(self.backend.clone(), memory?))
+    }
+
+    /// commit `count` regions reachable from each address in `near` ahead of time,
+    /// afterwards blocks for targets in their reach only come from existing regions
+    ///
+    /// nothing is kept when one of the regions can not be allocated
+    pub fn reserve(&mut self, near: &[usize], count: usize) -> Result<(), Error> {
+        let before = self.regions.len();
+        let mut bounds = vec![];
+        for addr in near {
+            let inst = inst::decode_from(&*self.backend, *addr);
+            let bound = platform::detour_find_jmp_bounds(&inst);
+            let have = self
+                .regions
+                .iter()
+                .filter(|x| x.pinned && bound.contains(&x.range.start))
+                .filter(|x| bound.contains(&(x.range.end - 1)))
+                .count();
+            for _ in have..count {
+                let Some(mut region) = self.alloc_region(&bound, *addr) else {
+                    // dropping region data releases memory
+                    self.regions.truncate(before);
+                    return Err(Error::NotEnoughMemory);
+                };
+                region.pinned = true;
+                self.regions.push(region);
+            }
+            bounds.push(bound);
+        }
+        self.reserved.extend(bounds);
+        Ok(())
+    }
+
+    fn is_reserved(&self, addr: usize) -> bool {
+        self.reserved.iter().any(|x| x.contains(&addr))
     }
 
     pub fn alloc_block<T>(&mut self, expect: *const c_void) -> Option<Block<T>> {
-        let inst = inst::decoder(expect).decode();
+        let inst = inst::decode_from(&*self.backend, expect.addr());
         let bound = platform::detour_find_jmp_bounds(&inst);
+        self.alloc_block_in(&bound, expect)
+    }
 
-        self.current
-            .and_then(|index| self.regions[index].get_free_addr::<T>().map(|x| (index, x)))
-            .and_then(|(index, addr)| {
-                if bound.contains(&addr) {
-                    Some(index)
-                } else {
-                    None
-                }
-            })
-            .and_then(|index| self.regions[index].next_free_block::<T>())
-            .or_else(|| {
-                let region = self.alloc_region(&bound, expect.addr())?;
-                self.regions.push(region);
-                let index = self.regions.len() - 1;
-                self.current = Some(index);
-                self.regions[index].next_free_block()
-            })
+    /// block at any address, only usable with absolute jumps
+    pub fn alloc_block_anywhere<T>(&mut self, expect: *const c_void) -> Option<Block<T>> {
+        self.alloc_block_in(&(DETOUR_REGION_SIZE..=usize::MAX), expect)
+    }
+
+    fn alloc_block_in<T>(
+        &mut self,
+        bound: &RangeInclusive<usize>,
+        expect: *const c_void,
+    ) -> Option<Block<T>> {
+        let in_bound = |region: &RegionData<N>| {
+            region
+                .get_free_addr::<T>()
+                .is_some_and(|addr| bound.contains(&addr))
+        };
+
+        let index = self
+            .current
+            .filter(|index| in_bound(&self.regions[*index]))
+            .or_else(|| self.regions.iter().position(in_bound));
+
+        if let Some(index) = index {
+            self.current = Some(index);
+            return self.regions[index].next_free_block();
+        }
+
+        if self.is_reserved(expect.addr()) {
+            return None;
+        }
+
+        let region = self.alloc_region(bound, expect.addr())?;
+        self.regions.push(region);
+        let index = self.regions.len() - 1;
+        self.current = Some(index);
+        self.regions[index].next_free_block()
+    }
+
+    /// `count` adjacent blocks in rel32 reach of `near`, for code larger than one block
+    pub fn alloc_span<T>(&mut self, near: usize, count: usize) -> Option<Block<T>> {
+        if count == 0 || count > N {
+            return None;
+        }
+        let bound = near.saturating_sub(REL32_REACH)..=near.saturating_add(REL32_REACH);
+        let in_bound = |region: &RegionData<N>| bound.contains(&region.range.start);
+        for region in self.regions.iter_mut().filter(|x| in_bound(x)) {
+            if let Some(block) = region.next_free_span(count) {
+                return Some(block);
+            }
+        }
+
+        if self.is_reserved(near) {
+            return None;
+        }
+
+        let region = self.alloc_region(&bound, near)?;
+        self.regions.push(region);
+        self.regions.last_mut()?.next_free_span(count)
+    }
+
+    /// release `count` blocks taken by [`Regions::alloc_span`]
+    pub fn free_span<T>(&mut self, block: &mut Block<T>, count: usize) {
+        let start = block.exec_ptr().addr();
+        for index in 0..count {
+            let mut part = Block {
+                exec: AtomicPtr::new((start + index * size_of::<T>()) as *mut T),
+                write: AtomicPtr::default(),
+            };
+            self.free_block(&mut part);
+        }
     }
 
     pub fn free_block<T>(&mut self, block: &mut Block<T>) -> bool {
         if let Some(region) = self
             .regions
             .iter_mut()
-            .find(|x| x.range.contains(&block.0.load(Ordering::Relaxed).addr()))
+            .find(|x| x.range.contains(&block.exec_ptr().addr()))
         {
             region.free_block(block);
             true
@@ -308,6 +534,271 @@ pub fn raw_write<T: Sized>(addr: usize, data: T) -> usize {
     }
 }
 
+/// whether every byte of `addr..addr + size` is committed and readable
+pub(crate) fn is_committed(backend: &dyn MemoryBackend, addr: usize, size: usize) -> bool {
+    let mut cursor = addr;
+    let end = addr.saturating_add(size);
+    while cursor < end {
+        let Some(info) = backend.query(cursor) else {
+            return false;
+        };
+        if info.state != RegionState::Commit || info.size == 0 || !info.protection.is_readable() {
+            return false;
+        }
+        cursor = info.base.saturating_add(info.size);
+    }
+    true
+}
+
 pub fn raw_read<T: Sized>(ptr: usize) -> T {
     unsafe { ptr::read::<T>(ptr as *const T) }
 }
+
+/// `raw_read` of a committed and readable value in the current process
+pub(crate) fn checked_read<T: Sized>(addr: usize) -> Option<T> {
+    is_committed(&ProcessMemory, addr, size_of::<T>()).then(|| raw_read::<T>(addr))
+}
+
+/// copy of `addr..addr + size` in the current process when all of it is readable
+pub(crate) fn checked_copy(addr: usize, size: usize) -> Option<Vec<u8>> {
+    is_committed(&ProcessMemory, addr, size)
+        .then(|| unsafe { std::slice::from_raw_parts(addr as *const u8, size) }.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SimulatedMemory;
+
+    /// allocates every slot in `probe`, then answers `pick`
+    struct Probing {
+        probe: Vec<usize>,
+        pick: Option<usize>,
+    }
+
+    impl AllocationStrategy for Probing {
+        fn allocate(
+            &mut self,
+            _target: usize,
+            _bound: &RangeInclusive<usize>,
+            space: &mut dyn AddressSpace,
+        ) -> Option<usize> {
+            for addr in self.probe.iter() {
+                assert!(space.alloc_at(*addr));
+            }
+            self.pick
+        }
+    }
+
+    fn state(sim: &SimulatedMemory, addr: usize) -> RegionState {
+        sim.query(addr).unwrap().state
+    }
+
+    #[test]
+    fn regions_a_strategy_does_not_return_are_freed() {
+        let slot = |sim: &SimulatedMemory, i: usize| sim.range().start + i * DETOUR_REGION_SIZE;
+        for (pick, kept) in [(Some(1), true), (None, false), (Some(5), false)] {
+            let sim = Arc::new(SimulatedMemory::new(8 * DETOUR_REGION_SIZE));
+            sim.reserve(slot(&sim, 3), DETOUR_REGION_SIZE).unwrap();
+            let mut regions = Regions::<4>::new(RegionMode::ReadWriteExecute, sim.clone());
+            regions.set_strategy(Box::new(Probing {
+                probe: vec![slot(&sim, 0), slot(&sim, 1), slot(&sim, 3)],
+                pick: pick.map(|x| slot(&sim, x)),
+            }));
+
+            let block = regions.alloc_block_anywhere::<[u8; 64]>(ptr::null());
+            assert_eq!(block.is_some(), kept);
+            assert_eq!(regions.stats().regions, kept as usize);
+            let kept = if kept {
+                RegionState::Commit
+            } else {
+                RegionState::Free
+            };
+            assert_eq!(state(&sim, slot(&sim, 1)), kept);
+            assert_eq!(state(&sim, slot(&sim, 0)), RegionState::Free);
+            // committed inside a reservation goes back to reserved
+            assert_eq!(state(&sim, slot(&sim, 3)), RegionState::Reserve);
+            assert_eq!(state(&sim, slot(&sim, 5)), RegionState::Free);
+        }
+    }
+
+    type Trampoline = [u8; 64];
+
+    #[test]
+    fn failed_reserve_keeps_nothing() {
+        let sim = Arc::new(SimulatedMemory::new(4 * DETOUR_REGION_SIZE));
+        let target = sim.range().start + 0x100;
+        let mut regions = Regions::<4>::new(RegionMode::ReadWriteExecute, sim.clone());
+
+        // the second address is out of reach of the simulated memory
+        let reserved = regions.reserve(&[target, 0x1000], 2);
+        assert!(matches!(reserved, Err(Error::NotEnoughMemory)));
+        assert_eq!(regions.stats().regions, 0);
+        let free = (0..4).map(|i| sim.range().start + i * DETOUR_REGION_SIZE);
+        assert!(free.map(|x| state(&sim, x)).all(|x| x == RegionState::Free));
+
+        // nothing is restricted either, blocks are still allocated on demand
+        let block = regions.alloc_block::<Trampoline>(target as *const c_void);
+        assert!(block.is_some());
+        assert!(matches!(
+            regions.reserve(&[target], 8),
+            Err(Error::NotEnoughMemory)
+        ));
+        assert_eq!(regions.stats().regions, 1);
+    }
+
+    #[test]
+    fn reserve_only_restricts_targets_in_reach() {
+        let sim = Arc::new(SimulatedMemory::new(4 * DETOUR_REGION_SIZE));
+        let target = sim.range().start + 0x100;
+        let mut regions = Regions::<4>::new(RegionMode::ReadWriteExecute, sim.clone());
+        regions.reserve(&[target], 1).unwrap();
+        assert_eq!(regions.stats().reserved_regions, 1);
+
+        let expect = target as *const c_void;
+        let blocks = (0..4).map(|_| regions.alloc_block::<Trampoline>(expect));
+        assert!(blocks.collect::<Vec<_>>().iter().all(Option::is_some));
+        assert!(regions.alloc_block::<Trampoline>(expect).is_none());
+        assert!(regions.alloc_block_anywhere::<Trampoline>(expect).is_none());
+        assert!(regions.alloc_span::<Trampoline>(target, 2).is_none());
+        assert_eq!(regions.stats().regions, 1);
+
+        // a target nowhere near the reservation still gets a new region
+        let far = 0x1000 as *const c_void;
+        assert!(regions.alloc_block_anywhere::<Trampoline>(far).is_some());
+        assert_eq!(regions.stats().regions, 2);
+    }
+
+    fn alloc(regions: &mut Regions<4>) -> usize {
+        let block = regions.alloc_block_anywhere::<Trampoline>(ptr::null());
+        block.unwrap().exec_ptr().addr()
+    }
+
+    fn free(regions: &mut Regions<4>, addr: usize) {
+        let mut block = Block::<Trampoline> {
+            exec: AtomicPtr::new(addr as *mut Trampoline),
+            write: AtomicPtr::default(),
+        };
+        assert!(regions.free_block(&mut block));
+    }
+
+    #[test]
+    fn freed_blocks_are_reused_first() {
+        let sim = Arc::new(SimulatedMemory::new(4 * DETOUR_REGION_SIZE));
+        let mut regions = Regions::<4>::new(RegionMode::ReadWriteExecute, sim.clone());
+        let blocks: Vec<_> = (0..4).map(|_| alloc(&mut regions)).collect();
+        let size = size_of::<Trampoline>();
+        assert_eq!(blocks, [0, 1, 2, 3].map(|i| blocks[0] + i * size));
+
+        // out of order, the most recently freed block comes back first
+        free(&mut regions, blocks[1]);
+        free(&mut regions, blocks[3]);
+        let stats = regions.stats();
+        assert_eq!(
+            (stats.regions, stats.used_blocks, stats.free_blocks()),
+            (1, 2, 2)
+        );
+        assert_eq!(stats.fragmentation(), 0.5);
+        assert_eq!(alloc(&mut regions), blocks[3]);
+        assert_eq!(alloc(&mut regions), blocks[1]);
+        assert_eq!(regions.stats().fragmentation(), 0.0);
+
+        // a full region makes room for a new one
+        let last = alloc(&mut regions);
+        assert!(!(blocks[0]..blocks[0] + DETOUR_REGION_SIZE).contains(&last));
+        let stats = regions.stats();
+        assert_eq!((stats.regions, stats.used_blocks), (2, 5));
+        assert_eq!(stats.fragmentation(), 3.0 / 8.0);
+
+        // an empty region can be released and no longer counts
+        free(&mut regions, last);
+        free(&mut regions, blocks[0]);
+        let stats = regions.stats();
+        assert_eq!((stats.empty_regions, stats.used_blocks), (1, 3));
+        assert_eq!(stats.fragmentation(), 1.0 / 4.0);
+
+        // a block freed twice is only listed once
+        free(&mut regions, blocks[0]);
+        assert_eq!(regions.stats().used_blocks, 3);
+    }
+
+    #[test]
+    fn lock_releases_empty_regions_unless_reserved() {
+        let sim = Arc::new(SimulatedMemory::new(4 * DETOUR_REGION_SIZE));
+        let target = sim.range().start + 0x100;
+        let mut regions = Regions::<4>::new(RegionMode::ReadWriteExecute, sim.clone());
+        regions.reserve(&[target], 1).unwrap();
+        let expect = target as *const c_void;
+        let reserved: Vec<_> = (0..4)
+            .map(|_| {
+                regions
+                    .alloc_block::<Trampoline>(expect)
+                    .unwrap()
+                    .exec_ptr()
+                    .addr()
+            })
+            .collect();
+        let far = alloc(&mut regions);
+        assert_eq!(regions.stats().regions, 2);
+
+        free(&mut regions, far);
+        regions.lock().unwrap();
+        let stats = regions.stats();
+        assert_eq!((stats.regions, stats.reclaimed_regions), (1, 1));
+        assert_eq!(state(&sim, far), RegionState::Free);
+
+        // the reserved region stays committed and executable once empty
+        reserved.iter().for_each(|x| free(&mut regions, *x));
+        regions.lock().unwrap();
+        let stats = regions.stats();
+        assert_eq!((stats.regions, stats.empty_regions), (1, 1));
+        assert_eq!((stats.reserved_regions, stats.reclaimed_regions), (1, 1));
+        assert_eq!(sim.protection(reserved[0]), Some(Protection::ReadExecute));
+    }
+
+    #[test]
+    fn committed_follows_backend_protection() {
+        let sim = SimulatedMemory::new(DETOUR_REGION_SIZE);
+        let base = sim.range().start;
+        sim.map(base, 0x1000, Protection::Read).unwrap();
+        sim.map(base + 0x1000, 0x1000, Protection::ReadWriteExecute)
+            .unwrap();
+        sim.map(base + 0x2000, 0x1000, Protection::NoAccess)
+            .unwrap();
+        assert!(is_committed(&sim, base, 0x2000));
+        assert!(is_committed(&sim, base + 0xff8, 0x10));
+        assert!(!is_committed(&sim, base + 0x1ff8, 0x10));
+        assert!(!is_committed(&sim, base + 0x3000, 8));
+        // outside the arena reads as somebody else's inaccessible memory
+        assert!(!is_committed(&sim, sim.range().end, 8));
+        sim.protect(base + 0x2000, 0x1000, Protection::ReadExecute)
+            .unwrap();
+        assert!(is_committed(&sim, base, 0x3000));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn committed_requires_readable_pages() {
+        use crate::backend::ProcessMemory;
+        use crate::platform::{
+            MEM_TYPE_COMMIT, MEM_TYPE_RESERVE, PAGE_FLAG_NOACCESS, PAGE_FLAG_READONLY, valloc,
+            vfree, vprotect,
+        };
+
+        let page =
+            valloc(ptr::null(), 0x3000, MEM_TYPE_COMMIT | MEM_TYPE_RESERVE).unwrap() as usize;
+        assert!(is_committed(&ProcessMemory, page, 0x3000));
+        vprotect((page + 0x1000) as *const u8, 0x1000, PAGE_FLAG_NOACCESS).unwrap();
+        assert!(is_committed(&ProcessMemory, page, 0x1000));
+        assert!(!is_committed(&ProcessMemory, page, 0x1001));
+        assert!(!is_committed(&ProcessMemory, page + 0x1800, 8));
+        assert!(is_committed(&ProcessMemory, page + 0x2000, 0x1000));
+        // executable only
+        vprotect((page + 0x1000) as *const u8, 0x1000, libc::PROT_EXEC as u32).unwrap();
+        assert!(!is_committed(&ProcessMemory, page + 0x1000, 8));
+        vprotect((page + 0x1000) as *const u8, 0x1000, PAGE_FLAG_READONLY).unwrap();
+        assert!(is_committed(&ProcessMemory, page, 0x3000));
+        vfree(page as *mut c_void).unwrap();
+        assert!(!is_committed(&ProcessMemory, page, 8));
+    }
+}
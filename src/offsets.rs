@@ -0,0 +1,524 @@
+//! address sets keyed by module build identity
+//!
+//! ```toml
+//! [builds."pe:5f3a1b2c:123000"]
+//! player = 0x1234
+//!
+//! [builds."elf:4f1c0e7d9a"]
+//! player = 0x2234
+//!
+//! # used when build is unknown or name is missing from the build
+//! [signatures.player]
+//! pattern = "8B 0D ?? ?? ?? ?? 85 C9"
+//! offset = 2
+//! read = "abs"
+//! ```
+//!
+//! the same table as JSON, offsets are plain numbers there
+//!
+//! ```json
+//! {
+//!     "builds": { "pe:5f3a1b2c:123000": { "player": 4660 } },
+//!     "signatures": { "player": { "pattern": "8B 0D ?? ?? ?? ?? 85 C9", "offset": 2, "read": "abs" } }
+//! }
+//! ```
+//!
+//! build offsets are relative to module base, resolved addresses are absolute
+
+use crate::Error;
+use crate::binary::{ElfFile, ElfSegment};
+use crate::mem::{checked_copy, checked_read};
+use crate::pe::PeImage;
+use crate::platform;
+use crate::scan::Pattern;
+use fnv::{FnvHashMap, FnvHashSet};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_GNU_BUILD_ID: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BuildId {
+    Pe { timestamp: u32, size_of_image: u32 },
+    Elf(Vec<u8>),
+}
+
+/// load bias of mapped elf, `ET_EXEC` images are mapped at their link address
+fn elf_bias(base: usize, segments: &[ElfSegment]) -> usize {
+    let first = segments
+        .iter()
+        .filter(|x| x.kind == PT_LOAD)
+        .map(|x| x.vaddr as usize & !0xfff)
+        .min()
+        .unwrap_or(0);
+    base.wrapping_sub(first)
+}
+
+/// descriptor of the GNU build-id note in a note segment
+fn gnu_build_id(notes: &[u8]) -> Option<&[u8]> {
+    let mut cursor = 0;
+    while let Some(header) = notes.get(cursor..).and_then(|x| x.get(..12)) {
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let (namesz, descsz, kind) = (field(0) as usize, field(4) as usize, field(8));
+        let name = cursor + 12;
+        let desc = name.checked_add(namesz.next_multiple_of(4))?;
+        if kind == NT_GNU_BUILD_ID && notes.get(name..name + namesz) == Some(b"GNU\0") {
+            return notes.get(desc..desc.checked_add(descsz)?);
+        }
+        cursor = desc.checked_add(descsz.next_multiple_of(4))?;
+    }
+    None
+}
+
+impl BuildId {
+    /// read identity from a mapped module
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to a mapped PE or ELF image
+    pub unsafe fn from_module_base(base: usize) -> Option<BuildId> {
+        if let Ok(pe) = unsafe { PeImage::from_base(base) } {
+            return Some(BuildId::Pe {
+                timestamp: pe.timestamp(),
+                size_of_image: pe.size_of_image(),
+            });
+        }
+
+        let elf = ElfFile::from_base(base).ok()?;
+        let bias = elf_bias(base, elf.segments());
+        elf.segments()
+            .iter()
+            .filter(|x| x.kind == PT_NOTE)
+            .filter_map(|note| checked_copy(bias + note.vaddr as usize, note.memsz as usize))
+            .find_map(|notes| gnu_build_id(&notes).map(|id| BuildId::Elf(id.to_vec())))
+    }
+
+    /// mapped image size of the module
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to a mapped PE or ELF image
+    pub unsafe fn module_size(&self, base: usize) -> usize {
+        match self {
+            BuildId::Pe { size_of_image, .. } => *size_of_image as usize,
+            BuildId::Elf(_) => {
+                let Ok(elf) = ElfFile::from_base(base) else {
+                    return 0;
+                };
+                let bias = elf_bias(base, elf.segments());
+                elf.segments()
+                    .iter()
+                    .filter(|x| x.kind == PT_LOAD)
+                    .map(|x| (bias + (x.vaddr + x.memsz) as usize).saturating_sub(base))
+                    .max()
+                    .unwrap_or(0)
+            }
+        }
+    }
+}
+
+impl Display for BuildId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildId::Pe {
+                timestamp,
+                size_of_image,
+            } => write!(f, "pe:{timestamp:08x}:{size_of_image:x}"),
+            BuildId::Elf(id) => write!(f, "elf:{}", hex::encode(id)),
+        }
+    }
+}
+
+impl FromStr for BuildId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(':').collect::<Vec<_>>().as_slice() {
+            ["pe", timestamp, size_of_image] => Ok(BuildId::Pe {
+                timestamp: u32::from_str_radix(timestamp, 16)
+                    .map_err(|_| Error::InvalidOffsetTable)?,
+                size_of_image: u32::from_str_radix(size_of_image, 16)
+                    .map_err(|_| Error::InvalidOffsetTable)?,
+            }),
+            ["elf", id] => hex::decode(id)
+                .map(BuildId::Elf)
+                .map_err(|_| Error::InvalidOffsetTable),
+            _ => Err(Error::InvalidOffsetTable),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureRead {
+    /// match address plus offset
+    #[default]
+    None,
+    /// pointer stored at match plus offset
+    Abs,
+    /// rel32 at match plus offset, relative to the end of the displacement
+    Rel32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub pattern: Pattern,
+    pub offset: isize,
+    pub read: SignatureRead,
+}
+
+impl Signature {
+    /// # Safety
+    ///
+    /// committed memory in `start..end` must be readable
+    pub unsafe fn resolve(&self, start: usize, end: usize) -> Option<usize> {
+        let found = unsafe { self.pattern.scan(start, end) }?;
+        let addr = found.wrapping_add_signed(self.offset);
+        // the operand may sit past the scanned range, read it only when mapped
+        match self.read {
+            SignatureRead::None => Some(addr),
+            SignatureRead::Abs => checked_read::<usize>(addr),
+            SignatureRead::Rel32 => {
+                checked_read::<i32>(addr).map(|x| (addr + 4).wrapping_add_signed(x as isize))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawSignature {
+    pattern: String,
+    #[serde(default)]
+    offset: isize,
+    #[serde(default)]
+    read: SignatureRead,
+}
+
+#[derive(Deserialize)]
+struct RawTable {
+    #[serde(default)]
+    builds: FnvHashMap<String, FnvHashMap<String, usize>>,
+    #[serde(default)]
+    signatures: FnvHashMap<String, RawSignature>,
+}
+
+#[derive(Default)]
+pub struct OffsetTable {
+    builds: FnvHashMap<BuildId, FnvHashMap<String, usize>>,
+    signatures: FnvHashMap<String, Signature>,
+}
+
+impl OffsetTable {
+    /// parse table, usually from `include_str!`
+    pub fn from_toml(content: &str) -> Result<OffsetTable, Error> {
+        let raw = toml::from_str::<RawTable>(content).map_err(|_| Error::InvalidOffsetTable)?;
+        OffsetTable::from_raw(raw)
+    }
+
+    pub fn from_json(content: &str) -> Result<OffsetTable, Error> {
+        let raw =
+            serde_json::from_str::<RawTable>(content).map_err(|_| Error::InvalidOffsetTable)?;
+        OffsetTable::from_raw(raw)
+    }
+
+    fn from_raw(raw: RawTable) -> Result<OffsetTable, Error> {
+        let builds = raw
+            .builds
+            .into_iter()
+            .map(|(id, offsets)| Ok((id.parse::<BuildId>()?, offsets)))
+            .collect::<Result<_, Error>>()?;
+        let signatures = raw
+            .signatures
+            .into_iter()
+            .map(|(name, sig)| {
+                Ok((
+                    name,
+                    Signature {
+                        pattern: sig.pattern.parse()?,
+                        offset: sig.offset,
+                        read: sig.read,
+                    },
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(OffsetTable { builds, signatures })
+    }
+
+    pub fn insert_build(&mut self, id: BuildId, offsets: FnvHashMap<String, usize>) {
+        self.builds.insert(id, offsets);
+    }
+
+    pub fn insert_signature(&mut self, name: impl Into<String>, signature: Signature) {
+        self.signatures.insert(name.into(), signature);
+    }
+
+    /// select address set for a loaded module, `None` means main module
+    ///
+    /// # Safety
+    ///
+    /// see [`OffsetTable::select_at`]
+    pub unsafe fn select(&self, module: Option<&str>) -> Result<Offsets, Error> {
+        let base = platform::module_base(module).ok_or(Error::ModuleNotFound)?;
+        unsafe { self.select_at(base) }
+    }
+
+    /// select address set for the module mapped at `base`
+    ///
+    /// names missing from the matched build, or every name when build is unknown,
+    /// are resolved by signature scanning over the module image
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to a mapped PE or ELF image
+    pub unsafe fn select_at(&self, base: usize) -> Result<Offsets, Error> {
+        let build = unsafe { BuildId::from_module_base(base) }.ok_or(Error::InvalidAddress)?;
+        let known = self.builds.get(&build);
+
+        let mut addresses = known
+            .map(|offsets| {
+                offsets
+                    .iter()
+                    .map(|(name, offset)| (name.clone(), base.wrapping_add(*offset)))
+                    .collect::<FnvHashMap<_, _>>()
+            })
+            .unwrap_or_default();
+
+        let end = base + unsafe { build.module_size(base) };
+        let mut unresolved = FnvHashSet::default();
+        for (name, signature) in self.signatures.iter() {
+            if addresses.contains_key(name) {
+                continue;
+            }
+            match unsafe { signature.resolve(base, end) } {
+                Some(addr) => {
+                    addresses.insert(name.clone(), addr);
+                }
+                None => {
+                    unresolved.insert(name.clone());
+                }
+            }
+        }
+
+        Ok(Offsets {
+            build,
+            base,
+            known: known.is_some(),
+            addresses,
+            unresolved,
+        })
+    }
+}
+
+pub struct Offsets {
+    build: BuildId,
+    base: usize,
+    known: bool,
+    addresses: FnvHashMap<String, usize>,
+    // names with a signature which matched nowhere in the module
+    unresolved: FnvHashSet<String>,
+}
+
+impl Offsets {
+    pub fn build(&self) -> &BuildId {
+        &self.build
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// false when the build is not in the table and addresses come from signatures
+    pub fn is_known_build(&self) -> bool {
+        self.known
+    }
+
+    pub fn get(&self, name: &str) -> Option<usize> {
+        self.addresses.get(name).copied()
+    }
+
+    /// `SignatureNotFound` when the signature for `name` matched nowhere,
+    /// `OffsetNotFound` when the table has neither an offset nor a signature for it
+    pub fn require(&self, name: &str) -> Result<usize, Error> {
+        match self.get(name) {
+            Some(addr) => Ok(addr),
+            None if self.unresolved.contains(name) => Err(Error::SignatureNotFound),
+            None => Err(Error::OffsetNotFound(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        [builds."pe:00000000:4000"]
+        exports = 0x20e4
+
+        [builds."elf:4f1c0e7d9a"]
+        exports = 0x10
+
+        [signatures.dll_name]
+        pattern = "66 69 78 74 75 72 65 2E 64 6C 6C 00"
+
+        [signatures.missing]
+        pattern = "DE AD BE EF 01"
+        offset = -4
+        read = "rel32"
+    "#;
+
+    const JSON: &str = r#"{
+        "builds": {
+            "pe:00000000:4000": { "exports": 8420 },
+            "elf:4f1c0e7d9a": { "exports": 16 }
+        },
+        "signatures": {
+            "dll_name": { "pattern": "66 69 78 74 75 72 65 2E 64 6C 6C 00" },
+            "missing": { "pattern": "DE AD BE EF 01", "offset": -4, "read": "rel32" }
+        }
+    }"#;
+
+    #[test]
+    fn build_id_round_trip() {
+        let pe = BuildId::Pe {
+            timestamp: 0x5f3a1b2c,
+            size_of_image: 0x123000,
+        };
+        assert_eq!(pe.to_string(), "pe:5f3a1b2c:123000");
+        assert_eq!("pe:5f3a1b2c:123000".parse::<BuildId>().unwrap(), pe);
+        let elf = BuildId::Elf(vec![0x4f, 0x1c, 0x0e, 0x7d, 0x9a]);
+        assert_eq!(elf.to_string(), "elf:4f1c0e7d9a");
+        assert_eq!("elf:4f1c0e7d9a".parse::<BuildId>().unwrap(), elf);
+
+        for bad in [
+            "",
+            "pe:5f3a1b2c",
+            "pe:xyz:1000",
+            "elf:4f1",
+            "macho:00",
+            "pe:1:2:3",
+        ] {
+            assert!(matches!(
+                bad.parse::<BuildId>(),
+                Err(Error::InvalidOffsetTable)
+            ));
+        }
+    }
+
+    #[test]
+    fn toml_and_json_tables_agree() {
+        for table in [OffsetTable::from_toml(TOML), OffsetTable::from_json(JSON)] {
+            let table = table.unwrap();
+            let pe = "pe:00000000:4000".parse::<BuildId>().unwrap();
+            assert_eq!(table.builds[&pe]["exports"], 0x20e4);
+            assert_eq!(table.builds.len(), 2);
+            let missing = &table.signatures["missing"];
+            assert_eq!((missing.offset, missing.read), (-4, SignatureRead::Rel32));
+            assert_eq!(missing.pattern, "DE AD BE EF 01".parse().unwrap());
+            let name = &table.signatures["dll_name"];
+            assert_eq!((name.offset, name.read), (0, SignatureRead::None));
+        }
+    }
+
+    #[test]
+    fn malformed_tables_are_rejected() {
+        let unknown_build = "[builds.\"coff:1\"]\nx = 1";
+        assert!(matches!(
+            OffsetTable::from_toml(unknown_build),
+            Err(Error::InvalidOffsetTable)
+        ));
+        let bad_read = "[signatures.x]\npattern = \"90\"\nread = \"indirect\"";
+        assert!(matches!(
+            OffsetTable::from_toml(bad_read),
+            Err(Error::InvalidOffsetTable)
+        ));
+        let bad_pattern = "[signatures.x]\npattern = \"9G\"";
+        assert!(matches!(
+            OffsetTable::from_toml(bad_pattern),
+            Err(Error::InvalidPattern)
+        ));
+        let bad_json = r#"{ "builds": { "pe:1:2": { "x": "0x10" } } }"#;
+        assert!(matches!(
+            OffsetTable::from_json(bad_json),
+            Err(Error::InvalidOffsetTable)
+        ));
+        assert!(matches!(
+            OffsetTable::from_json(TOML),
+            Err(Error::InvalidOffsetTable)
+        ));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    #[test]
+    fn select_known_build_and_scan_the_rest() {
+        use crate::pe::tests::{PE64, map};
+
+        let image = map(PE64);
+        let base = image.as_ptr().addr();
+        let table = OffsetTable::from_toml(TOML).unwrap();
+        let offsets = unsafe { table.select_at(base) }.unwrap();
+        assert!(offsets.is_known_build());
+        assert_eq!(offsets.build().to_string(), "pe:00000000:4000");
+        assert_eq!(offsets.require("exports").unwrap(), base + 0x20e4);
+        assert_eq!(offsets.require("dll_name").unwrap(), base + 0x215e);
+        assert!(matches!(
+            offsets.require("missing"),
+            Err(Error::SignatureNotFound)
+        ));
+        assert!(
+            matches!(offsets.require("health"), Err(Error::OffsetNotFound(x)) if x == "health")
+        );
+
+        // unknown build, only signatures resolve
+        let mut table = OffsetTable::default();
+        table.insert_signature("dll_name", table_signature("66 69 78 74 75 72 65"));
+        let offsets = unsafe { table.select_at(base) }.unwrap();
+        assert!(!offsets.is_known_build());
+        assert_eq!(offsets.get("dll_name"), Some(base + 0x215e));
+        assert!(matches!(
+            offsets.require("exports"),
+            Err(Error::OffsetNotFound(_))
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn select_main_module_by_build_id() {
+        let exe = ElfFile::open("/proc/self/exe").unwrap();
+        let id = exe
+            .segments()
+            .iter()
+            .filter(|x| x.kind == PT_NOTE)
+            .find_map(|x| gnu_build_id(&exe.data()[x.offset as usize..][..x.filesz as usize]))
+            .expect("test binary carries a build-id")
+            .to_vec();
+
+        let mut table = OffsetTable::default();
+        let offsets = [("header".to_string(), 0)].into_iter().collect();
+        table.insert_build(BuildId::Elf(id.clone()), offsets);
+        table.insert_signature("magic", table_signature("7F 45 4C 46"));
+        let offsets = unsafe { table.select(None) }.unwrap();
+        let base = platform::module_base(None).unwrap();
+        assert!(offsets.is_known_build());
+        assert_eq!(offsets.build(), &BuildId::Elf(id));
+        assert_eq!(offsets.base(), base);
+        assert_eq!(offsets.require("header").unwrap(), base);
+        assert_eq!(offsets.require("magic").unwrap(), base);
+
+        assert!(matches!(
+            unsafe { table.select(Some("libmissing.so")) },
+            Err(Error::ModuleNotFound)
+        ));
+    }
+
+    fn table_signature(pattern: &str) -> Signature {
+        Signature {
+            pattern: pattern.parse().unwrap(),
+            offset: 0,
+            read: SignatureRead::None,
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! typed configuration handed from the launcher to hook libraries
+//!
+//! the counterpart of `DetourCopyPayloadToProcess`/`DetourFindPayload`. Blobs are keyed by
+//! [`Guid`] and carry a version, [`Launcher`](crate::launch::Launcher) stores them in a
+//! sealed memfd which children inherit, `DETOURS_PAYLOAD_FD` names the descriptor
+
+use crate::Error;
+use std::fmt::{Display, Formatter};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+pub const PAYLOAD_FD_ENV: &str = "DETOURS_PAYLOAD_FD";
+
+const MAGIC: &[u8; 8] = b"DTRSPAY1";
+const ENTRY_HEADER_SIZE: usize = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Guid(pub u128);
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let v = self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            v >> 96,
+            (v >> 80) & 0xffff,
+            (v >> 64) & 0xffff,
+            (v >> 48) & 0xffff,
+            v & 0xffff_ffff_ffff
+        )
+    }
+}
+
+impl FromStr for Guid {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let groups: Vec<&str> = s.trim_matches(['{', '}']).split('-').collect();
+        let lens: Vec<usize> = groups.iter().map(|x| x.len()).collect();
+        if lens != [8, 4, 4, 4, 12] {
+            return Err(Error::InvalidPattern);
+        }
+        u128::from_str_radix(&groups.concat(), 16)
+            .map(Guid)
+            .map_err(|_| Error::InvalidPattern)
+    }
+}
+
+/// plain data struct which can travel as a payload
+///
+/// bump `VERSION` when the layout changes, readers of another version get `None` from
+/// [`Payloads::find`] and may decode [`Payloads::find_raw`] themselves
+///
+/// # Safety
+///
+/// every bit pattern of `size_of::<Self>()` bytes must be a valid `Self`, e.g. a
+/// `#[repr(C)]` struct of integers without padding
+pub unsafe trait Payload: Copy {
+    const GUID: Guid;
+    const VERSION: u32 = 1;
+}
+
+struct Entry {
+    guid: Guid,
+    version: u32,
+    data: Vec<u8>,
+}
+
+/// payloads read back in the hook library, or collected for a launch
+#[derive(Default)]
+pub struct Payloads {
+    entries: Vec<Entry>,
+}
+
+impl Payloads {
+    pub fn new() -> Payloads {
+        Payloads::default()
+    }
+
+    /// replaces a payload with the same guid
+    pub fn insert<T: Payload>(&mut self, value: &T) -> &mut Payloads {
+        let data =
+            unsafe { std::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>()) };
+        self.insert_raw(T::GUID, T::VERSION, data)
+    }
+
+    pub fn insert_raw(&mut self, guid: Guid, version: u32, data: &[u8]) -> &mut Payloads {
+        self.entries.retain(|x| x.guid != guid);
+        self.entries.push(Entry {
+            guid,
+            version,
+            data: data.to_vec(),
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn find<T: Payload>(&self) -> Option<T> {
+        let (version, data) = self.find_raw(T::GUID)?;
+        if version != T::VERSION || data.len() != size_of::<T>() {
+            return None;
+        }
+        Some(unsafe { std::ptr::read_unaligned(data.as_ptr().cast::<T>()) })
+    }
+
+    /// version and bytes of the payload
+    pub fn find_raw(&self, guid: Guid) -> Option<(u32, &[u8])> {
+        self.entries
+            .iter()
+            .find(|x| x.guid == guid)
+            .map(|x| (x.version, x.data.as_slice()))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend((self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            bytes.extend(entry.guid.0.to_le_bytes());
+            bytes.extend(entry.version.to_le_bytes());
+            bytes.extend((entry.data.len() as u32).to_le_bytes());
+            bytes.extend(&entry.data);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Payloads, Error> {
+        let u32_at = |at: usize| -> Result<u32, Error> {
+            Ok(u32::from_le_bytes(
+                bytes
+                    .get(at..at + 4)
+                    .ok_or(Error::InvalidPayload)?
+                    .try_into()
+                    .unwrap(),
+            ))
+        };
+        if bytes.get(..MAGIC.len()) != Some(MAGIC) {
+            return Err(Error::InvalidPayload);
+        }
+        let count = u32_at(MAGIC.len())?;
+        let mut at = MAGIC.len() + 4;
+        let mut payloads = Payloads::new();
+        for _ in 0..count {
+            let guid = bytes.get(at..at + 16).ok_or(Error::InvalidPayload)?;
+            let guid = Guid(u128::from_le_bytes(guid.try_into().unwrap()));
+            let version = u32_at(at + 16)?;
+            let len = u32_at(at + 20)? as usize;
+            at += ENTRY_HEADER_SIZE;
+            let data = bytes.get(at..at + len).ok_or(Error::InvalidPayload)?;
+            payloads.insert_raw(guid, version, data);
+            at += len;
+        }
+        Ok(payloads)
+    }
+
+    /// sealed memfd holding the payloads, inherited by children
+    pub fn to_memfd(&self) -> Result<OwnedFd, Error> {
+        let last_error = || {
+            Error::ErrorCode(std::io::Error::last_os_error().raw_os_error().unwrap_or(0) as usize)
+        };
+        let fd =
+            unsafe { libc::memfd_create(c"detours-payload".as_ptr(), libc::MFD_ALLOW_SEALING) };
+        if fd < 0 {
+            return Err(last_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let bytes = self.to_bytes();
+        let mut written = 0;
+        while written < bytes.len() {
+            let n = unsafe {
+                libc::write(
+                    fd.as_raw_fd(),
+                    bytes[written..].as_ptr().cast(),
+                    bytes.len() - written,
+                )
+            };
+            if n <= 0 {
+                return Err(last_error());
+            }
+            written += n as usize;
+        }
+        let seals =
+            libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(last_error());
+        }
+        Ok(fd)
+    }
+
+    /// read payloads from an inherited descriptor, its file offset is left alone
+    pub fn from_fd(fd: BorrowedFd<'_>) -> Result<Payloads, Error> {
+        let mut bytes = vec![];
+        let mut chunk = [0u8; 0x1000];
+        loop {
+            let n = unsafe {
+                libc::pread(
+                    fd.as_raw_fd(),
+                    chunk.as_mut_ptr().cast(),
+                    chunk.len(),
+                    bytes.len() as libc::off_t,
+                )
+            };
+            match n {
+                0 => break,
+                n if n < 0 => {
+                    return Err(Error::ErrorCode(
+                        std::io::Error::last_os_error().raw_os_error().unwrap_or(0) as usize,
+                    ));
+                }
+                n => bytes.extend_from_slice(&chunk[..n as usize]),
+            }
+        }
+        Payloads::from_bytes(&bytes)
+    }
+
+    /// payloads named by `DETOURS_PAYLOAD_FD`
+    pub fn from_env() -> Result<Payloads, Error> {
+        let fd: RawFd = std::env::var(PAYLOAD_FD_ENV)
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .ok_or(Error::InvalidPayload)?;
+        Payloads::from_fd(unsafe { BorrowedFd::borrow_raw(fd) })
+    }
+}
+
+/// payloads of this process, read once from `DETOURS_PAYLOAD_FD`
+pub fn payloads() -> &'static Payloads {
+    static PAYLOADS: OnceLock<Payloads> = OnceLock::new();
+    PAYLOADS.get_or_init(|| Payloads::from_env().unwrap_or_default())
+}
+
+/// find a payload handed to this process
+pub fn find<T: Payload>() -> Option<T> {
+    payloads().find()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsFd;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    #[repr(C)]
+    struct Config {
+        port: u32,
+        flags: u32,
+    }
+
+    unsafe impl Payload for Config {
+        const GUID: Guid = Guid(0x6f1c_2b9e_0d4a_4c3b_9a7e_55aa_1f00_0001);
+        const VERSION: u32 = 2;
+    }
+
+    const OTHER: Guid = Guid(0x6f1c_2b9e_0d4a_4c3b_9a7e_55aa_1f00_0002);
+    const CONFIG: Config = Config {
+        port: 8080,
+        flags: 0x11,
+    };
+
+    fn payloads() -> Payloads {
+        let mut payloads = Payloads::new();
+        payloads
+            .insert(&Config { port: 1, flags: 0 })
+            .insert_raw(OTHER, 7, b"opaque")
+            .insert(&CONFIG);
+        payloads
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let guid = "6f1c2b9e-0d4a-4c3b-9a7e-55aa1f000001";
+        assert_eq!(Config::GUID.to_string(), guid);
+        assert_eq!(format!("{{{guid}}}").parse::<Guid>().unwrap(), Config::GUID);
+        assert!("6f1c2b9e-0d4a-4c3b-9a7e".parse::<Guid>().is_err());
+
+        let read = Payloads::from_bytes(&payloads().to_bytes()).unwrap();
+        assert_eq!(read.entries.len(), 2);
+        assert_eq!(read.find::<Config>(), Some(CONFIG));
+        assert_eq!(read.find_raw(OTHER), Some((7, &b"opaque"[..])));
+        assert!(
+            Payloads::from_bytes(&Payloads::new().to_bytes())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn other_version_or_size_is_not_decoded() {
+        let mut payloads = payloads();
+        let bytes = payloads.to_bytes();
+        let read = Payloads::from_bytes(&bytes).unwrap();
+        assert!(read.find::<Config>().is_some());
+
+        let raw = read.find_raw(Config::GUID).unwrap().1.to_vec();
+        payloads.insert_raw(Config::GUID, 1, &raw);
+        assert_eq!(payloads.find::<Config>(), None);
+        assert_eq!(payloads.find_raw(Config::GUID), Some((1, raw.as_slice())));
+        payloads.insert_raw(Config::GUID, Config::VERSION, &raw[..4]);
+        assert_eq!(payloads.find::<Config>(), None);
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let bytes = payloads().to_bytes();
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                Payloads::from_bytes(&bytes[..len]),
+                Err(Error::InvalidPayload)
+            ));
+        }
+        let mut magic = bytes.clone();
+        magic[0] ^= 0xff;
+        assert!(matches!(
+            Payloads::from_bytes(&magic),
+            Err(Error::InvalidPayload)
+        ));
+    }
+
+    #[test]
+    fn memfd_is_sealed() {
+        let fd = payloads().to_memfd().unwrap();
+        let read = Payloads::from_fd(fd.as_fd()).unwrap();
+        assert_eq!(read.find::<Config>(), Some(CONFIG));
+
+        let raw = fd.as_raw_fd();
+        let written = unsafe { libc::pwrite(raw, b"x".as_ptr().cast(), 1, 0) };
+        assert_eq!(written, -1);
+        assert_eq!(
+            std::io::Error::last_os_error().raw_os_error(),
+            Some(libc::EPERM)
+        );
+        assert_eq!(unsafe { libc::ftruncate(raw, 0) }, -1);
+        let seals = unsafe { libc::fcntl(raw, libc::F_GET_SEALS) };
+        assert_ne!(seals & libc::F_SEAL_SEAL, 0);
+        // the file offset is left at the end of the write, reading does not depend on it
+        assert_eq!(Payloads::from_fd(fd.as_fd()).unwrap().entries.len(), 2);
+    }
+}
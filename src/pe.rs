@@ -0,0 +1,721 @@
+//! bounds checked PE32 and PE32+ parser
+//!
+//! reads headers, sections, imports, exports, base relocations and TLS from a byte buffer,
+//! either a file as stored on disk or an image as mapped by the loader, on any host
+
+use crate::Error;
+use std::ops::Range;
+
+pub const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+pub const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+pub const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+pub const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+pub const IMAGE_DIRECTORY_ENTRY_TLS: usize = 9;
+pub const IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT: usize = 11;
+pub const IMAGE_DIRECTORY_ENTRY_IAT: usize = 12;
+
+const IMAGE_DOS_SIGNATURE: u64 = 0x5a4d;
+const IMAGE_NT_SIGNATURE: u64 = 0x4550;
+const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u64 = 0x10b;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u64 = 0x20b;
+const IMAGE_REL_BASED_ABSOLUTE: u8 = 0;
+
+pub(crate) const SECTION_HEADER_SIZE: usize = 40;
+pub(crate) const IMPORT_DESCRIPTOR_SIZE: usize = 20;
+
+fn uint(data: &[u8], offset: usize, size: usize) -> Result<u64, Error> {
+    let bytes = data
+        .get(offset..offset.checked_add(size).ok_or(Error::InvalidImage)?)
+        .ok_or(Error::InvalidImage)?;
+    let mut value = [0u8; 8];
+    value[..size].copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+fn c_str(data: &[u8], offset: usize) -> Result<String, Error> {
+    let bytes = data.get(offset..).ok_or(Error::InvalidImage)?;
+    let end = bytes
+        .iter()
+        .position(|x| *x == 0)
+        .ok_or(Error::InvalidImage)?;
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+/// `rva + offset`, an image never spans past 4GB
+fn rva_add(rva: u32, offset: usize) -> Result<u32, Error> {
+    u32::try_from(offset)
+        .ok()
+        .and_then(|x| rva.checked_add(x))
+        .ok_or(Error::InvalidImage)
+}
+
+/// function imported from a dll
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Import {
+    Ordinal(u16),
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub size_of_raw_data: u32,
+    pub pointer_to_raw_data: u32,
+    pub characteristics: u32,
+}
+
+impl Section {
+    /// rva range covered in memory
+    pub fn range(&self) -> Range<u32> {
+        let size = self.virtual_size.max(self.size_of_raw_data);
+        self.virtual_address..self.virtual_address.saturating_add(size)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DataDirectory {
+    pub virtual_address: u32,
+    pub size: u32,
+}
+
+impl DataDirectory {
+    pub fn range(&self) -> Range<u32> {
+        self.virtual_address..self.virtual_address.saturating_add(self.size)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportDescriptor {
+    pub dll: String,
+    /// rva of the descriptor itself
+    pub rva: u32,
+    /// rva of the lookup table, zero when the linker left it out
+    pub lookup: u32,
+    /// rva of the import address table
+    pub iat: u32,
+}
+
+/// one entry of the import address table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportThunk {
+    pub dll: String,
+    pub import: Import,
+    /// rva of the IAT slot
+    pub rva: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExportTarget {
+    Rva(u32),
+    /// `dll.function` or `dll.#ordinal` resolved by the loader
+    Forward(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Export {
+    pub name: Option<String>,
+    pub ordinal: u32,
+    pub target: ExportTarget,
+}
+
+/// one base relocation, `kind` is the `IMAGE_REL_BASED_*` type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    pub rva: u32,
+    pub kind: u8,
+}
+
+/// TLS directory, addresses are virtual addresses relative to the image base in the headers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tls {
+    pub raw_data: Range<u64>,
+    pub index: u64,
+    pub callbacks: Vec<u64>,
+    pub size_of_zero_fill: u32,
+}
+
+/// parsed view of a PE image
+pub struct PeImage<'a> {
+    data: &'a [u8],
+    mapped: bool,
+    is_64: bool,
+    pub(crate) file_header: usize,
+    pub(crate) optional_header: usize,
+    sections: Vec<Section>,
+}
+
+impl<'a> PeImage<'a> {
+    /// image laid out as a file on disk
+    pub fn parse(data: &'a [u8]) -> Result<PeImage<'a>, Error> {
+        PeImage::new(data, false)
+    }
+
+    /// image laid out by the loader, rvas are offsets
+    pub fn parse_mapped(data: &'a [u8]) -> Result<PeImage<'a>, Error> {
+        PeImage::new(data, true)
+    }
+
+    fn new(data: &'a [u8], mapped: bool) -> Result<PeImage<'a>, Error> {
+        if uint(data, 0, 2)? != IMAGE_DOS_SIGNATURE {
+            return Err(Error::InvalidImage);
+        }
+        let nt = uint(data, 0x3c, 4)? as usize;
+        if uint(data, nt, 4)? != IMAGE_NT_SIGNATURE {
+            return Err(Error::InvalidImage);
+        }
+        let file_header = nt + 4;
+        let optional_header = file_header + 20;
+        let is_64 = match uint(data, optional_header, 2)? {
+            IMAGE_NT_OPTIONAL_HDR32_MAGIC => false,
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC => true,
+            _ => return Err(Error::InvalidImage),
+        };
+        let count = uint(data, file_header + 2, 2)? as usize;
+        let table = optional_header + uint(data, file_header + 16, 2)? as usize;
+        let sections = (0..count)
+            .map(|i| {
+                let at = table + i * SECTION_HEADER_SIZE;
+                let name = data.get(at..at + 8).ok_or(Error::InvalidImage)?;
+                let end = name.iter().position(|x| *x == 0).unwrap_or(8);
+                let section = Section {
+                    name: String::from_utf8_lossy(&name[..end]).into_owned(),
+                    virtual_size: uint(data, at + 8, 4)? as u32,
+                    virtual_address: uint(data, at + 12, 4)? as u32,
+                    size_of_raw_data: uint(data, at + 16, 4)? as u32,
+                    pointer_to_raw_data: uint(data, at + 20, 4)? as u32,
+                    characteristics: uint(data, at + 36, 4)? as u32,
+                };
+                let size = section.virtual_size.max(section.size_of_raw_data);
+                rva_add(section.virtual_address, size as usize)?;
+                (section.pointer_to_raw_data as usize)
+                    .checked_add(section.size_of_raw_data as usize)
+                    .ok_or(Error::InvalidImage)?;
+                Ok(section)
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(PeImage {
+            data,
+            mapped,
+            is_64,
+            file_header,
+            optional_header,
+            sections,
+        })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.mapped
+    }
+
+    pub fn is_64(&self) -> bool {
+        self.is_64
+    }
+
+    pub fn machine(&self) -> u16 {
+        self.header(self.file_header, 2) as u16
+    }
+
+    /// link time, `TimeDateStamp` of the file header
+    pub fn timestamp(&self) -> u32 {
+        self.header(self.file_header + 4, 4) as u32
+    }
+
+    pub fn characteristics(&self) -> u16 {
+        self.header(self.file_header + 18, 2) as u16
+    }
+
+    pub fn entry_point(&self) -> u32 {
+        self.header(self.optional_header + 16, 4) as u32
+    }
+
+    pub fn image_base(&self) -> u64 {
+        match self.is_64 {
+            true => self.header(self.optional_header + 24, 8),
+            false => self.header(self.optional_header + 28, 4),
+        }
+    }
+
+    pub fn section_alignment(&self) -> u32 {
+        self.header(self.optional_header + 32, 4) as u32
+    }
+
+    pub fn file_alignment(&self) -> u32 {
+        self.header(self.optional_header + 36, 4) as u32
+    }
+
+    pub fn size_of_image(&self) -> u32 {
+        self.header(self.optional_header + 56, 4) as u32
+    }
+
+    pub fn size_of_headers(&self) -> u32 {
+        self.header(self.optional_header + 60, 4) as u32
+    }
+
+    pub fn dll_characteristics(&self) -> u16 {
+        self.header(self.optional_header + 70, 2) as u16
+    }
+
+    // fields checked to be in bounds by `new`, or reported as zero
+    fn header(&self, offset: usize, size: usize) -> u64 {
+        uint(self.data, offset, size).unwrap_or(0)
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// offset of the data directory entry in the headers
+    pub(crate) fn directory_offset(&self, index: usize) -> Result<usize, Error> {
+        let (count, first) = if self.is_64 { (108, 112) } else { (92, 96) };
+        if index as u64 >= uint(self.data, self.optional_header + count, 4)? {
+            return Err(Error::InvalidImage);
+        }
+        Ok(self.optional_header + first + index * 8)
+    }
+
+    /// data directory `index`, `None` when absent or empty
+    pub fn directory(&self, index: usize) -> Option<DataDirectory> {
+        let at = self.directory_offset(index).ok()?;
+        let directory = DataDirectory {
+            virtual_address: uint(self.data, at, 4).ok()? as u32,
+            size: uint(self.data, at + 4, 4).ok()? as u32,
+        };
+        (directory.virtual_address != 0).then_some(directory)
+    }
+
+    pub fn rva_to_offset(&self, rva: u32) -> Result<usize, Error> {
+        if self.mapped || rva < self.size_of_headers() {
+            return Ok(rva as usize);
+        }
+        self.sections
+            .iter()
+            .find(|x| x.range().contains(&rva))
+            .and_then(|x| {
+                ((rva - x.virtual_address) as usize).checked_add(x.pointer_to_raw_data as usize)
+            })
+            .ok_or(Error::InvalidImage)
+    }
+
+    /// `len` bytes at `rva`
+    pub fn read(&self, rva: u32, len: usize) -> Result<&'a [u8], Error> {
+        let offset = self.rva_to_offset(rva)?;
+        self.data
+            .get(offset..offset.checked_add(len).ok_or(Error::InvalidImage)?)
+            .ok_or(Error::InvalidImage)
+    }
+
+    fn uint_at(&self, rva: u32, size: usize) -> Result<u64, Error> {
+        uint(self.data, self.rva_to_offset(rva)?, size)
+    }
+
+    fn c_str_at(&self, rva: u32) -> Result<String, Error> {
+        c_str(self.data, self.rva_to_offset(rva)?)
+    }
+
+    fn width(&self) -> usize {
+        if self.is_64 { 8 } else { 4 }
+    }
+
+    pub fn import_descriptors(&self) -> Result<Vec<ImportDescriptor>, Error> {
+        let Some(directory) = self.directory(IMAGE_DIRECTORY_ENTRY_IMPORT) else {
+            return Ok(vec![]);
+        };
+        let mut descriptors = vec![];
+        let mut rva = directory.virtual_address;
+        loop {
+            let raw = self.read(rva, IMPORT_DESCRIPTOR_SIZE)?;
+            if raw.iter().all(|x| *x == 0) {
+                break;
+            }
+            descriptors.push(ImportDescriptor {
+                dll: self.c_str_at(uint(raw, 12, 4)? as u32)?,
+                rva,
+                lookup: uint(raw, 0, 4)? as u32,
+                iat: uint(raw, 16, 4)? as u32,
+            });
+            rva = rva_add(rva, IMPORT_DESCRIPTOR_SIZE)?;
+        }
+        Ok(descriptors)
+    }
+
+    /// every IAT slot with the function it imports
+    pub fn imports(&self) -> Result<Vec<ImportThunk>, Error> {
+        let width = self.width();
+        let ordinal_flag = 1u64 << (width * 8 - 1);
+        let mut thunks = vec![];
+        for descriptor in self.import_descriptors()? {
+            // old linkers leave the lookup table out, names are only in the unbound IAT
+            let lookup = match descriptor.lookup {
+                0 => descriptor.iat,
+                lookup => lookup,
+            };
+            for index in 0.. {
+                let value = self.uint_at(rva_add(lookup, index * width)?, width)?;
+                if value == 0 {
+                    break;
+                }
+                let import = match value & ordinal_flag != 0 {
+                    true => Import::Ordinal(value as u16),
+                    false => Import::Name(self.c_str_at(rva_add(value as u32, 2)?)?),
+                };
+                thunks.push(ImportThunk {
+                    dll: descriptor.dll.clone(),
+                    import,
+                    rva: rva_add(descriptor.iat, index * width)? as usize,
+                });
+            }
+        }
+        Ok(thunks)
+    }
+
+    pub fn exports(&self) -> Result<Vec<Export>, Error> {
+        let Some(directory) = self.directory(IMAGE_DIRECTORY_ENTRY_EXPORT) else {
+            return Ok(vec![]);
+        };
+        let rva = directory.virtual_address;
+        let field = |offset: usize| Ok::<_, Error>(self.uint_at(rva_add(rva, offset)?, 4)? as u32);
+        let base = field(16)?;
+        let functions = field(20)? as usize;
+        let names = field(24)? as usize;
+        let address_of_functions = field(28)?;
+        let address_of_names = field(32)?;
+        let address_of_ordinals = field(36)?;
+        // the tables live inside the directory, which bounds the count before allocating
+        if functions as u64 * 4 + names as u64 * 6 > directory.size as u64 {
+            return Err(Error::InvalidImage);
+        }
+
+        let mut named = vec![None; functions];
+        for i in 0..names {
+            let index = self.uint_at(rva_add(address_of_ordinals, i * 2)?, 2)? as usize;
+            let name = self.uint_at(rva_add(address_of_names, i * 4)?, 4)? as u32;
+            if let Some(slot) = named.get_mut(index) {
+                *slot = Some(self.c_str_at(name)?);
+            }
+        }
+        let mut exports = vec![];
+        for (index, name) in named.into_iter().enumerate() {
+            let function = self.uint_at(rva_add(address_of_functions, index * 4)?, 4)? as u32;
+            if function == 0 {
+                continue;
+            }
+            let target = match directory.range().contains(&function) {
+                true => ExportTarget::Forward(self.c_str_at(function)?),
+                false => ExportTarget::Rva(function),
+            };
+            exports.push(Export {
+                name,
+                ordinal: rva_add(base, index)?,
+                target,
+            });
+        }
+        Ok(exports)
+    }
+
+    pub fn export(&self, name: &str) -> Result<Export, Error> {
+        self.exports()?
+            .into_iter()
+            .find(|x| x.name.as_deref() == Some(name))
+            .ok_or(Error::SignatureNotFound)
+    }
+
+    /// base relocations without the `IMAGE_REL_BASED_ABSOLUTE` padding
+    pub fn relocations(&self) -> Result<Vec<Relocation>, Error> {
+        let Some(directory) = self.directory(IMAGE_DIRECTORY_ENTRY_BASERELOC) else {
+            return Ok(vec![]);
+        };
+        let mut relocations = vec![];
+        let end = rva_add(directory.virtual_address, directory.size as usize)?;
+        let mut block = directory.virtual_address;
+        while end.saturating_sub(block) >= 8 {
+            let page = self.uint_at(block, 4)? as u32;
+            let size = self.uint_at(block + 4, 4)? as u32;
+            if size < 8 {
+                return Err(Error::InvalidImage);
+            }
+            let entries = self.read(block + 8, (size - 8) as usize)?;
+            for entry in entries.chunks_exact(2) {
+                let entry = u16::from_le_bytes([entry[0], entry[1]]);
+                let kind = (entry >> 12) as u8;
+                if kind != IMAGE_REL_BASED_ABSOLUTE {
+                    relocations.push(Relocation {
+                        rva: rva_add(page, (entry & 0xfff) as usize)?,
+                        kind,
+                    });
+                }
+            }
+            block = rva_add(block, size as usize)?;
+        }
+        Ok(relocations)
+    }
+
+    pub fn tls(&self) -> Result<Option<Tls>, Error> {
+        let Some(directory) = self.directory(IMAGE_DIRECTORY_ENTRY_TLS) else {
+            return Ok(None);
+        };
+        let rva = directory.virtual_address;
+        let width = self.width();
+        let field = |index: usize| self.uint_at(rva_add(rva, index * width)?, width);
+        let mut callbacks = vec![];
+        let mut at = field(3)?;
+        while at != 0 {
+            let callback = (at.checked_sub(self.image_base()))
+                .and_then(|x| u32::try_from(x).ok())
+                .ok_or(Error::InvalidImage)?;
+            let callback = self.uint_at(callback, width)?;
+            if callback == 0 {
+                break;
+            }
+            callbacks.push(callback);
+            at = at.checked_add(width as u64).ok_or(Error::InvalidImage)?;
+        }
+        Ok(Some(Tls {
+            raw_data: field(0)?..field(1)?,
+            index: field(2)?,
+            callbacks,
+            size_of_zero_fill: self.uint_at(rva_add(rva, width * 4)?, 4)? as u32,
+        }))
+    }
+}
+
+impl PeImage<'static> {
+    /// image mapped by the loader at `base` in the current process
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to a mapped PE image which stays mapped while the result is used
+    pub unsafe fn from_base(base: usize) -> Result<PeImage<'static>, Error> {
+        if base == 0 {
+            return Err(Error::InvalidAddress);
+        }
+        // SizeOfImage sits at the same offset in PE32 and PE32+ optional headers
+        let dos = unsafe { std::slice::from_raw_parts(base as *const u8, 0x40) };
+        if uint(dos, 0, 2)? != IMAGE_DOS_SIGNATURE {
+            return Err(Error::InvalidImage);
+        }
+        let nt = uint(dos, 0x3c, 4)? as usize;
+        let headers = unsafe { std::slice::from_raw_parts(base as *const u8, nt + 84) };
+        if uint(headers, nt, 4)? != IMAGE_NT_SIGNATURE {
+            return Err(Error::InvalidImage);
+        }
+        let size_of_image = uint(headers, nt + 80, 4)? as usize;
+        PeImage::parse_mapped(unsafe {
+            std::slice::from_raw_parts(base as *const u8, size_of_image)
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // generated by tests/fixtures/mkpe.py
+    pub(crate) const PE32: &[u8] = include_bytes!("../tests/fixtures/pe32.dll");
+    pub(crate) const PE64: &[u8] = include_bytes!("../tests/fixtures/pe64.dll");
+
+    /// `file` laid out the way the loader maps it
+    pub(crate) fn map(file: &[u8]) -> Vec<u8> {
+        let pe = PeImage::parse(file).unwrap();
+        let mut image = vec![0; pe.size_of_image() as usize];
+        let headers = pe.size_of_headers() as usize;
+        image[..headers].copy_from_slice(&file[..headers]);
+        for section in pe.sections() {
+            let raw = section.pointer_to_raw_data as usize;
+            let size = section.size_of_raw_data as usize;
+            let at = section.virtual_address as usize;
+            image[at..at + size].copy_from_slice(&file[raw..raw + size]);
+        }
+        image
+    }
+
+    fn put(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn section_header(pe: &PeImage, index: usize) -> usize {
+        let table = pe.optional_header + pe.header(pe.file_header + 16, 2) as usize;
+        table + index * SECTION_HEADER_SIZE
+    }
+
+    fn name(dll: &str, name: &str) -> (String, Import) {
+        (dll.into(), Import::Name(name.into()))
+    }
+
+    #[test]
+    fn headers() {
+        let pe32 = PeImage::parse(PE32).unwrap();
+        assert!(!pe32.is_64());
+        assert_eq!(pe32.machine(), 0x14c);
+        assert_eq!(pe32.image_base(), 0x1000_0000);
+        let pe64 = PeImage::parse(PE64).unwrap();
+        assert!(pe64.is_64());
+        assert_eq!(pe64.machine(), 0x8664);
+        assert_eq!(pe64.image_base(), 0x1_8000_0000);
+
+        for pe in [pe32, pe64] {
+            let names = pe.sections().iter().map(|x| x.name.as_str());
+            assert_eq!(names.collect::<Vec<_>>(), [".text", ".rdata", ".reloc"]);
+            assert_eq!(pe.size_of_image(), 0x4000);
+            assert_eq!(pe.rva_to_offset(0x2010).unwrap(), 0x410);
+            assert!(pe.rva_to_offset(0x5000).is_err());
+        }
+    }
+
+    #[test]
+    fn imports() {
+        let order = [
+            name("KERNEL32.dll", "GetProcAddress"),
+            name("KERNEL32.dll", "LoadLibraryA"),
+            ("WS2_32.dll".into(), Import::Ordinal(115)),
+            name("WS2_32.dll", "connect"),
+        ];
+        for (file, rvas) in [
+            (PE32, [0x206c, 0x2070, 0x209c, 0x20a0]),
+            (PE64, [0x2078, 0x2080, 0x20c0, 0x20c8]),
+        ] {
+            for pe in [
+                PeImage::parse(file).unwrap(),
+                PeImage::parse_mapped(&map(file)).unwrap(),
+            ] {
+                let dlls = pe.import_descriptors().unwrap();
+                assert_eq!(dlls.len(), 2);
+                assert_eq!((dlls[1].dll.as_str(), dlls[1].rva), ("WS2_32.dll", 0x2014));
+                let thunks = pe.imports().unwrap();
+                let found = thunks.iter().map(|x| (x.dll.clone(), x.import.clone()));
+                assert_eq!(found.collect::<Vec<_>>(), order);
+                assert_eq!(thunks.iter().map(|x| x.rva).collect::<Vec<_>>(), rvas);
+            }
+        }
+    }
+
+    #[test]
+    fn exports() {
+        for (file, forward) in [(PE32, 0x20dc), (PE64, 0x210c)] {
+            let pe = PeImage::parse(file).unwrap();
+            let exports = pe.exports().unwrap();
+            let export = |name: Option<&str>, ordinal, target| Export {
+                name: name.map(Into::into),
+                ordinal,
+                target,
+            };
+            assert_eq!(
+                exports,
+                [
+                    export(Some("alpha"), 1, ExportTarget::Rva(0x1000)),
+                    export(Some("beta"), 2, ExportTarget::Rva(0x1010)),
+                    export(
+                        Some("gamma"),
+                        4,
+                        ExportTarget::Forward("KERNEL32.GetProcAddress".into())
+                    ),
+                    export(None, 5, ExportTarget::Rva(0x1020)),
+                ]
+            );
+            assert!(
+                pe.directory(IMAGE_DIRECTORY_ENTRY_EXPORT)
+                    .unwrap()
+                    .range()
+                    .contains(&forward)
+            );
+            assert_eq!(pe.export("beta").unwrap().ordinal, 2);
+            assert!(matches!(pe.export("delta"), Err(Error::SignatureNotFound)));
+        }
+    }
+
+    #[test]
+    fn relocations_and_tls() {
+        for (file, kind, callbacks, index) in
+            [(PE32, 3, 0x2140, 0x213c), (PE64, 10, 0x2170, 0x216c)]
+        {
+            let pe = PeImage::parse(file).unwrap();
+            let reloc = |rva| Relocation { rva, kind };
+            assert_eq!(
+                pe.relocations().unwrap(),
+                [reloc(0x1000), reloc(0x1008), reloc(callbacks)]
+            );
+            let base = pe.image_base();
+            assert_eq!(
+                pe.tls().unwrap().unwrap(),
+                Tls {
+                    raw_data: base + 0x1038..base + 0x1040,
+                    index: base + index,
+                    callbacks: vec![base + 0x1030],
+                    size_of_zero_fill: 0x10,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn section_past_4gb_is_rejected() {
+        for file in [PE32, PE64] {
+            let mut data = file.to_vec();
+            let at = section_header(&PeImage::parse(file).unwrap(), 0);
+            put(&mut data, at + 8, 0x2000);
+            put(&mut data, at + 12, 0xffff_f000);
+            assert!(matches!(PeImage::parse(&data), Err(Error::InvalidImage)));
+            assert!(matches!(
+                PeImage::parse_mapped(&data),
+                Err(Error::InvalidImage)
+            ));
+        }
+        let section = Section {
+            name: ".text".into(),
+            virtual_address: 0xffff_f000,
+            virtual_size: 0x2000,
+            size_of_raw_data: 0,
+            pointer_to_raw_data: 0,
+            characteristics: 0,
+        };
+        assert_eq!(section.range(), 0xffff_f000..u32::MAX);
+    }
+
+    #[test]
+    fn export_count_is_bounded_by_directory() {
+        for file in [PE32, PE64] {
+            let pe = PeImage::parse(file).unwrap();
+            let directory = pe.directory(IMAGE_DIRECTORY_ENTRY_EXPORT).unwrap();
+            let at = pe.rva_to_offset(directory.virtual_address).unwrap();
+            for (field, value) in [(20, u32::MAX), (24, u32::MAX), (16, u32::MAX)] {
+                let mut data = file.to_vec();
+                put(&mut data, at + field, value);
+                let pe = PeImage::parse(&data).unwrap();
+                assert!(matches!(pe.exports(), Err(Error::InvalidImage)));
+            }
+        }
+    }
+
+    #[test]
+    fn wrapping_offsets_are_rejected() {
+        for file in [PE32, PE64] {
+            let pe = PeImage::parse(file).unwrap();
+            let reloc = pe.rva_to_offset(0x3000).unwrap();
+            let mut data = file.to_vec();
+            put(&mut data, reloc + 4, 0xffff_fff8);
+            let pe = PeImage::parse(&data).unwrap();
+            assert!(matches!(pe.relocations(), Err(Error::InvalidImage)));
+
+            // page plus the entry offset wraps
+            let mut data = map(file);
+            put(&mut data, 0x3000, 0xffff_fffc);
+            let pe = PeImage::parse_mapped(&data).unwrap();
+            assert!(matches!(pe.relocations(), Err(Error::InvalidImage)));
+
+            let tls = pe
+                .directory(IMAGE_DIRECTORY_ENTRY_TLS)
+                .unwrap()
+                .virtual_address as usize;
+            let mut data = map(file);
+            let callbacks = tls + 3 * if pe.is_64() { 8 } else { 4 };
+            data[callbacks..callbacks + 4].fill(0xff);
+            let pe = PeImage::parse_mapped(&data).unwrap();
+            assert!(matches!(pe.tls(), Err(Error::InvalidImage)));
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use crate::Error;
+use crate::platform::comm::{MemoryAllocType, MemoryBasicInfo, PageProtectionFlag};
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::sync::Mutex;
+
+pub const MEM_TYPE_COMMIT: MemoryAllocType = 0x1000;
+pub const MEM_TYPE_FREE: MemoryAllocType = 0x10000;
+pub const MEM_TYPE_RESERVE: MemoryAllocType = 0x2000;
+
+pub const PAGE_FLAG_EXECUTE_READWRITE: PageProtectionFlag =
+    (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as PageProtectionFlag;
+pub const PAGE_FLAG_EXECUTE_READ: PageProtectionFlag =
+    (libc::PROT_READ | libc::PROT_EXEC) as PageProtectionFlag;
+pub const PAGE_FLAG_NOACCESS: PageProtectionFlag = libc::PROT_NONE as PageProtectionFlag;
+pub const PAGE_FLAG_READONLY: PageProtectionFlag = libc::PROT_READ as PageProtectionFlag;
+pub const PAGE_FLAG_READWRITE: PageProtectionFlag =
+    (libc::PROT_READ | libc::PROT_WRITE) as PageProtectionFlag;
+
+/// whether pages with protection `flag` can be read
+pub fn is_readable(flag: PageProtectionFlag) -> bool {
+    flag & libc::PROT_READ as PageProtectionFlag != 0
+}
+
+// munmap needs the length windows remembers for VirtualFree, base to size of every valloc
+static ALLOCATIONS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+fn last_error() -> Error {
+    Error::ErrorCode(std::io::Error::last_os_error().raw_os_error().unwrap_or(0) as usize)
+}
+
+/// whether `addr..addr + size` lies inside one valloc allocation
+fn is_allocated(addr: usize, size: usize) -> bool {
+    let allocations = ALLOCATIONS.lock().unwrap_or_else(|x| x.into_inner());
+    allocations
+        .range(..=addr)
+        .next_back()
+        .is_some_and(|(base, len)| addr.checked_add(size).is_some_and(|end| end <= base + len))
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn parse_line(line: &str) -> Option<(usize, usize, PageProtectionFlag)> {
+    let mut fields = line.split_ascii_whitespace();
+    let (start, end) = fields.next()?.split_once('-')?;
+    let perms = fields.next()?.as_bytes();
+    let flag = |at: usize, set: u8, prot: i32| match perms.get(at) == Some(&set) {
+        true => prot as PageProtectionFlag,
+        false => 0,
+    };
+    Some((
+        usize::from_str_radix(start, 16).ok()?,
+        usize::from_str_radix(end, 16).ok()?,
+        flag(0, b'r', libc::PROT_READ)
+            | flag(1, b'w', libc::PROT_WRITE)
+            | flag(2, b'x', libc::PROT_EXEC),
+    ))
+}
+
+/// mapping containing `addr` from `/proc/self/maps`, gaps are reported as free
+pub fn vquery(addr: *const c_void) -> Option<MemoryBasicInfo> {
+    let addr = addr as usize;
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+    let mut free_base = 0;
+    for line in maps.lines() {
+        let (start, end, protect) = parse_line(line)?;
+        if addr < start {
+            break;
+        }
+        if addr < end {
+            return Some(MemoryBasicInfo {
+                base_address: start as *const c_void,
+                allocation_base: start as *const c_void,
+                region_size: end - start,
+                state: MEM_TYPE_COMMIT,
+                protect,
+            });
+        }
+        free_base = end;
+    }
+    let next = maps
+        .lines()
+        .filter_map(parse_line)
+        .map(|x| x.0)
+        .find(|x| *x > addr)
+        .unwrap_or(usize::MAX);
+    Some(MemoryBasicInfo {
+        base_address: free_base as *const c_void,
+        allocation_base: std::ptr::null(),
+        region_size: next - free_base,
+        state: MEM_TYPE_FREE,
+        protect: PAGE_FLAG_NOACCESS,
+    })
+}
+
+/// returns protection of the mapping containing `addr`
+pub fn vprotect<T>(
+    addr: *const T,
+    size: usize,
+    flag: PageProtectionFlag,
+) -> Result<PageProtectionFlag, Error> {
+    let old = vquery(addr.cast())
+        .filter(|x| x.state == MEM_TYPE_COMMIT)
+        .ok_or(Error::InvalidAddress)?
+        .protect;
+    let page = page_size();
+    let start = addr as usize & !(page - 1);
+    let end = (addr as usize + size.max(1)).next_multiple_of(page);
+    if unsafe { libc::mprotect(start as *mut c_void, end - start, flag as i32) } != 0 {
+        return Err(last_error());
+    }
+    Ok(old)
+}
+
+fn mmap_at(
+    addr: *const c_void,
+    size: usize,
+    prot: i32,
+    flags: i32,
+    fd: i32,
+) -> Option<*mut c_void> {
+    let flags = match addr.is_null() {
+        true => flags,
+        false => flags | libc::MAP_FIXED_NOREPLACE,
+    };
+    let pv = unsafe { libc::mmap(addr as *mut c_void, size, prot, flags, fd, 0) };
+    if pv == libc::MAP_FAILED {
+        return None;
+    }
+    // kernels before 4.17 take the address as a hint only
+    if !addr.is_null() && !std::ptr::eq(pv, addr) {
+        unsafe { libc::munmap(pv, size) };
+        return None;
+    }
+    Some(pv)
+}
+
+/// map RWX memory exactly at `addr`, or anywhere for null
+///
+/// committing without `MEM_TYPE_RESERVE` only works inside an earlier allocation
+pub fn valloc(
+    addr: *const c_void,
+    size: usize,
+    alloc_type: MemoryAllocType,
+) -> Option<*const c_void> {
+    if alloc_type & MEM_TYPE_RESERVE == 0 {
+        let committed = is_allocated(addr as usize, size)
+            && unsafe {
+                libc::mprotect(
+                    addr as *mut c_void,
+                    size,
+                    PAGE_FLAG_EXECUTE_READWRITE as i32,
+                )
+            } == 0;
+        return committed.then_some(addr);
+    }
+
+    let pv = mmap_at(
+        addr,
+        size,
+        PAGE_FLAG_EXECUTE_READWRITE as i32,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+    )?;
+    ALLOCATIONS
+        .lock()
+        .unwrap_or_else(|x| x.into_inner())
+        .insert(pv as usize, size);
+    Some(pv)
+}
+
+/// unmap a whole allocation returned by `valloc`
+pub fn vfree(addr: *mut c_void) -> Result<(), Error> {
+    let mut allocations = ALLOCATIONS.lock().unwrap_or_else(|x| x.into_inner());
+    let size = allocations
+        .remove(&(addr as usize))
+        .ok_or(Error::InvalidAddress)?;
+    if unsafe { libc::munmap(addr, size) } != 0 {
+        return Err(last_error());
+    }
+    Ok(())
+}
+
+/// drop the pages and leave the range mapped inaccessible
+pub fn vdecommit(addr: *mut c_void, size: usize) -> Result<(), Error> {
+    if !is_allocated(addr as usize, size) {
+        return Err(Error::InvalidAddress);
+    }
+    let pv = unsafe {
+        libc::mmap(
+            addr,
+            size,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+            -1,
+            0,
+        )
+    };
+    if pv == libc::MAP_FAILED {
+        return Err(last_error());
+    }
+    Ok(())
+}
+
+/// map a memfd twice, RX view at `addr` and RW view anywhere
+///
+/// returns exec view, write view and the memfd
+pub fn valloc_dual(
+    addr: *const c_void,
+    size: usize,
+) -> Option<(*const c_void, *mut c_void, usize)> {
+    let fd = unsafe { libc::memfd_create(c"detours".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return None;
+    }
+    let exec = match unsafe { libc::ftruncate(fd, size as libc::off_t) } {
+        0 => mmap_at(
+            addr,
+            size,
+            PAGE_FLAG_EXECUTE_READ as i32,
+            libc::MAP_SHARED,
+            fd,
+        ),
+        _ => None,
+    };
+    let write = exec.and_then(|_| {
+        let rw = PAGE_FLAG_READWRITE as i32;
+        mmap_at(std::ptr::null(), size, rw, libc::MAP_SHARED, fd)
+    });
+    match (exec, write) {
+        (Some(exec), Some(write)) => Some((exec, write, fd as usize)),
+        _ => {
+            if let Some(exec) = exec {
+                unsafe { libc::munmap(exec, size) };
+            }
+            unsafe { libc::close(fd) };
+            None
+        }
+    }
+}
+
+pub fn vfree_dual(exec: *mut c_void, write: *mut c_void, section: usize) -> Result<(), Error> {
+    let fd = section as i32;
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return Err(last_error());
+    }
+    let size = stat.st_size as usize;
+    let ok = unsafe {
+        libc::munmap(write, size) == 0 && libc::munmap(exec, size) == 0 && libc::close(fd) == 0
+    };
+    if ok { Ok(()) } else { Err(last_error()) }
+}
+
+/// start of the mapped image, `None` means the main program
+pub fn module_base(name: Option<&str>) -> Option<usize> {
+    crate::got::module_named(name)?.start()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_at_free_address_then_release() {
+        let pv = valloc(
+            std::ptr::null(),
+            0x10000,
+            MEM_TYPE_COMMIT | MEM_TYPE_RESERVE,
+        )
+        .unwrap();
+        vfree(pv as *mut c_void).unwrap();
+        let fixed = valloc(pv, 0x10000, MEM_TYPE_COMMIT | MEM_TYPE_RESERVE).unwrap();
+        assert_eq!(fixed, pv);
+        let mbi = vquery(pv).unwrap();
+        assert_eq!(mbi.state, MEM_TYPE_COMMIT);
+        assert_eq!(mbi.protect, PAGE_FLAG_EXECUTE_READWRITE);
+
+        // occupied addresses are never replaced
+        assert!(valloc(pv, 0x10000, MEM_TYPE_COMMIT | MEM_TYPE_RESERVE).is_none());
+
+        vdecommit(pv as *mut c_void, 0x1000).unwrap();
+        assert_eq!(vquery(pv).unwrap().protect, PAGE_FLAG_NOACCESS);
+        assert_eq!(valloc(pv, 0x1000, MEM_TYPE_COMMIT), Some(pv));
+        assert_eq!(vquery(pv).unwrap().protect, PAGE_FLAG_EXECUTE_READWRITE);
+
+        vfree(pv as *mut c_void).unwrap();
+        assert_eq!(vquery(pv).unwrap().state, MEM_TYPE_FREE);
+        assert!(vfree(pv as *mut c_void).is_err());
+    }
+
+    #[test]
+    fn dual_views_share_pages() {
+        let (exec, write, section) = valloc_dual(std::ptr::null(), 0x10000).unwrap();
+        assert_eq!(vquery(exec).unwrap().protect, PAGE_FLAG_EXECUTE_READ);
+        assert_eq!(vquery(write).unwrap().protect, PAGE_FLAG_READWRITE);
+        unsafe { *(write as *mut u32).add(0x100) = 0xc3c3c3c3 };
+        assert_eq!(unsafe { *(exec as *const u32).add(0x100) }, 0xc3c3c3c3);
+        vfree_dual(exec as *mut c_void, write, section).unwrap();
+        assert_eq!(vquery(exec).unwrap().state, MEM_TYPE_FREE);
+        assert_eq!(vquery(write).unwrap().state, MEM_TYPE_FREE);
+    }
+
+    #[test]
+    fn commit_outside_allocation_fails() {
+        let code = alloc_at_free_address_then_release as *const c_void;
+        assert!(valloc(code, 0x1000, MEM_TYPE_COMMIT).is_none());
+        assert!(vdecommit(code as *mut c_void, 0x1000).is_err());
+    }
+}
@@ -3,15 +3,26 @@ mod win;
 #[cfg(target_os = "windows")]
 pub use win::*;
 
-#[cfg(not(target_os = "windows"))]
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
 mod unimpl;
-#[cfg(not(target_os = "windows"))]
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
 pub use unimpl::*;
 
+// memory queries, protection and allocation for the current process
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub use linux::*;
+
 #[cfg(target_arch = "x86")]
 mod x86;
 #[cfg(target_arch = "x86")]
 pub use x86::*;
 
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::*;
+
 mod comm;
 pub use comm::*;
@@ -1,26 +1,41 @@
 use crate::Error;
-use crate::platform::comm::{MemoryAllocType, MemoryBasicInfo, PageProtectionFlag};
+use crate::platform::comm::MemoryAllocType;
 use iced_x86::Instruction;
 use std::ffi::c_void;
-use std::ops::RangeInclusive;
 
-pub const MEM_TYPE_COMMIT: MemoryAllocType = 0;
-pub const MEM_TYPE_FREE: MemoryAllocType = 0;
-pub const MEM_TYPE_RESERVE: MemoryAllocType = 0;
+// linux implements these and the allocation functions in `linux.rs`
+pub use query::*;
 
-pub const PAGE_FLAG_EXECUTE_READWRITE: PageProtectionFlag = 0;
-pub const PAGE_FLAG_EXECUTE_READ: PageProtectionFlag = 0;
+mod query {
+    use crate::Error;
+    use crate::platform::comm::{MemoryAllocType, MemoryBasicInfo, PageProtectionFlag};
+    use std::ffi::c_void;
 
-pub fn vquery(_addr: *const c_void) -> Option<MemoryBasicInfo> {
-    unimplemented!()
-}
+    pub const MEM_TYPE_COMMIT: MemoryAllocType = 0;
+    pub const MEM_TYPE_FREE: MemoryAllocType = 0;
+    pub const MEM_TYPE_RESERVE: MemoryAllocType = 0;
 
-pub fn vprotect<T>(
-    _addr: *const T,
-    _size: usize,
-    _flag: PageProtectionFlag,
-) -> Result<PageProtectionFlag, Error> {
-    unimplemented!()
+    pub const PAGE_FLAG_EXECUTE_READWRITE: PageProtectionFlag = 0;
+    pub const PAGE_FLAG_EXECUTE_READ: PageProtectionFlag = 0;
+    pub const PAGE_FLAG_NOACCESS: PageProtectionFlag = 0;
+    pub const PAGE_FLAG_READONLY: PageProtectionFlag = 0;
+    pub const PAGE_FLAG_READWRITE: PageProtectionFlag = 0;
+
+    pub fn is_readable(_flag: PageProtectionFlag) -> bool {
+        unimplemented!()
+    }
+
+    pub fn vquery(_addr: *const c_void) -> Option<MemoryBasicInfo> {
+        unimplemented!()
+    }
+
+    pub fn vprotect<T>(
+        _addr: *const T,
+        _size: usize,
+        _flag: PageProtectionFlag,
+    ) -> Result<PageProtectionFlag, Error> {
+        unimplemented!()
+    }
 }
 
 pub fn valloc(
@@ -35,20 +50,55 @@ pub fn vfree(_addr: *mut c_void) -> Result<(), Error> {
     unimplemented!()
 }
 
-pub const NEEDED_BYTES: usize = 0;
+pub fn vdecommit(_addr: *mut c_void, _size: usize) -> Result<(), Error> {
+    unimplemented!()
+}
 
-pub fn detour_gen_jmp_immediate(_pb_code: *mut u8, _pb_jmp_val: *mut u8) {
+pub fn valloc_dual(
+    _addr: *const c_void,
+    _size: usize,
+) -> Option<(*const c_void, *mut c_void, usize)> {
     unimplemented!()
 }
 
-pub fn detour_find_jmp_bounds(_inst: &Instruction) -> RangeInclusive<usize> {
+pub fn vfree_dual(_exec: *mut c_void, _write: *mut c_void, _section: usize) -> Result<(), Error> {
     unimplemented!()
 }
 
-pub fn detour_skip_jmp(_inst: Instruction) -> usize {
+pub fn module_base(_name: Option<&str>) -> Option<usize> {
     unimplemented!()
 }
 
-pub fn detour_does_code_end_function(_inst: &Instruction) -> bool {
+// x86 and x86_64 encoders and bounds live in `x86.rs` and `x86_64.rs`
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub use jmp::*;
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+mod jmp {
+    use iced_x86::Instruction;
+    use std::ops::RangeInclusive;
+
+    pub const NEEDED_BYTES: usize = 0;
+    pub const NEEDED_BYTES_ABSOLUTE: usize = 0;
+
+    pub fn detour_gen_jmp_immediate_at(
+        _pb_write: *mut u8,
+        _pb_code: *mut u8,
+        _pb_jmp_val: *mut u8,
+    ) {
+        unimplemented!()
+    }
+
+    pub fn detour_gen_jmp_absolute(_pb_write: *mut u8, _pb_code: *mut u8, _pb_jmp_val: *mut u8) {
+        unimplemented!()
+    }
+
+    pub fn detour_find_jmp_bounds(_inst: &Instruction) -> RangeInclusive<usize> {
+        unimplemented!()
+    }
+}
+
+// linux follows PLT stubs in `thunk.rs`
+pub fn detour_skip_jmp(_inst: Instruction) -> usize {
     unimplemented!()
 }
@@ -1,11 +1,17 @@
 use crate::Error;
 use crate::platform::comm::{MemoryAllocType, MemoryBasicInfo, PageProtectionFlag};
 use std::ffi::c_void;
-use windows_sys::Win32::Foundation::{ERROR_DYNAMIC_CODE_BLOCKED, GetLastError, HANDLE};
+use windows_sys::Win32::Foundation::{
+    CloseHandle, ERROR_DYNAMIC_CODE_BLOCKED, GetLastError, HANDLE, INVALID_HANDLE_VALUE,
+};
 use windows_sys::Win32::System::Diagnostics::Debug::FlushInstructionCache;
+use windows_sys::Win32::System::LibraryLoader::GetModuleHandleA;
 use windows_sys::Win32::System::Memory::{
-    MEM_COMMIT, MEM_FREE, MEM_RESERVE, MEMORY_BASIC_INFORMATION, PAGE_EXECUTE_READ,
-    PAGE_EXECUTE_READWRITE, VirtualAlloc, VirtualFree, VirtualProtect, VirtualQuery,
+    CreateFileMappingW, FILE_MAP_EXECUTE, FILE_MAP_READ, FILE_MAP_WRITE, MEM_COMMIT, MEM_DECOMMIT,
+    MEM_FREE, MEM_RELEASE, MEM_RESERVE, MEMORY_BASIC_INFORMATION, MEMORY_MAPPED_VIEW_ADDRESS,
+    MapViewOfFile, MapViewOfFileEx, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE,
+    PAGE_EXECUTE_WRITECOPY, PAGE_GUARD, PAGE_NOACCESS, PAGE_READONLY, PAGE_READWRITE,
+    PAGE_WRITECOPY, UnmapViewOfFile, VirtualAlloc, VirtualFree, VirtualProtect, VirtualQuery,
 };
 use windows_sys::Win32::System::Threading::GetCurrentProcess;
 
@@ -15,6 +21,20 @@ pub const MEM_TYPE_RESERVE: MemoryAllocType = MEM_RESERVE;
 
 pub const PAGE_FLAG_EXECUTE_READWRITE: PageProtectionFlag = PAGE_EXECUTE_READWRITE;
 pub const PAGE_FLAG_EXECUTE_READ: PageProtectionFlag = PAGE_EXECUTE_READ;
+pub const PAGE_FLAG_NOACCESS: PageProtectionFlag = PAGE_NOACCESS;
+pub const PAGE_FLAG_READONLY: PageProtectionFlag = PAGE_READONLY;
+pub const PAGE_FLAG_READWRITE: PageProtectionFlag = PAGE_READWRITE;
+
+/// whether pages with protection `flag` can be read, guard pages fault on first touch
+pub fn is_readable(flag: PageProtectionFlag) -> bool {
+    const READABLE: PageProtectionFlag = PAGE_READONLY
+        | PAGE_READWRITE
+        | PAGE_WRITECOPY
+        | PAGE_EXECUTE_READ
+        | PAGE_EXECUTE_READWRITE
+        | PAGE_EXECUTE_WRITECOPY;
+    flag & PAGE_GUARD == 0 && flag & READABLE != 0
+}
 
 pub fn vquery(addr: *const c_void) -> Option<MemoryBasicInfo> {
     let mut mbi = unsafe { std::mem::zeroed::<MEMORY_BASIC_INFORMATION>() };
@@ -34,6 +54,7 @@ pub fn vquery(addr: *const c_void) -> Option<MemoryBasicInfo> {
         allocation_base: mbi.AllocationBase,
         region_size: mbi.RegionSize,
         state: mbi.State,
+        protect: mbi.Protect,
     })
 }
 
@@ -63,13 +84,71 @@ pub fn valloc(
 }
 
 pub fn vfree(addr: *mut c_void) -> Result<(), Error> {
-    if (unsafe { VirtualFree(addr, 0, MEM_FREE) }) == 0 {
+    if (unsafe { VirtualFree(addr, 0, MEM_RELEASE) }) == 0 {
+        Err(Error::ErrorCode(unsafe { GetLastError() as usize }))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn vdecommit(addr: *mut c_void, size: usize) -> Result<(), Error> {
+    if (unsafe { VirtualFree(addr, size, MEM_DECOMMIT) }) == 0 {
         Err(Error::ErrorCode(unsafe { GetLastError() as usize }))
     } else {
         Ok(())
     }
 }
 
+/// map a pagefile backed section twice, RX view at `addr` and RW view anywhere
+///
+/// returns exec view, write view and section handle
+pub fn valloc_dual(
+    addr: *const c_void,
+    size: usize,
+) -> Option<(*const c_void, *mut c_void, usize)> {
+    let section = unsafe {
+        CreateFileMappingW(
+            INVALID_HANDLE_VALUE,
+            std::ptr::null(),
+            PAGE_EXECUTE_READWRITE,
+            0,
+            size as u32,
+            std::ptr::null(),
+        )
+    };
+    if section.is_null() {
+        return None;
+    }
+    let exec =
+        unsafe { MapViewOfFileEx(section, FILE_MAP_READ | FILE_MAP_EXECUTE, 0, 0, size, addr) };
+    if exec.Value.is_null() {
+        unsafe { CloseHandle(section) };
+        return None;
+    }
+    let write = unsafe { MapViewOfFile(section, FILE_MAP_WRITE, 0, 0, size) };
+    if write.Value.is_null() {
+        unsafe {
+            UnmapViewOfFile(exec);
+            CloseHandle(section);
+        }
+        return None;
+    }
+    Some((exec.Value, write.Value, section as usize))
+}
+
+pub fn vfree_dual(exec: *mut c_void, write: *mut c_void, section: usize) -> Result<(), Error> {
+    let ok = unsafe {
+        UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: write }) != 0
+            && UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: exec }) != 0
+            && CloseHandle(section as HANDLE) != 0
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(Error::ErrorCode(unsafe { GetLastError() as usize }))
+    }
+}
+
 pub fn get_current_process() -> *const c_void {
     unsafe { GetCurrentProcess() }
 }
@@ -89,3 +168,20 @@ pub fn flush_instruction_cache(
 pub fn check_dynamic_code_blocked() -> bool {
     (unsafe { GetLastError() }) == ERROR_DYNAMIC_CODE_BLOCKED
 }
+
+pub fn module_base(name: Option<&str>) -> Option<usize> {
+    let name = match name {
+        Some(name) => Some(std::ffi::CString::new(name).ok()?),
+        None => None,
+    };
+    let module = unsafe {
+        GetModuleHandleA(
+            name.as_ref()
+                .map_or(std::ptr::null(), |x| x.as_ptr().cast()),
+        )
+    };
+    if module.is_null() {
+        return None;
+    }
+    Some(module as usize)
+}
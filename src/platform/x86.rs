@@ -1,19 +1,11 @@
-use crate::inst;
 use iced_x86::{Code, Instruction};
-use std::ops::{Range, RangeInclusive};
+use std::ops::RangeInclusive;
 use std::ptr;
-use windows_sys::Win32::System::Diagnostics::Debug::IMAGE_DIRECTORY_ENTRY_IAT;
-#[cfg(any(target_pointer_width = "32"))]
-use windows_sys::Win32::System::Diagnostics::Debug::IMAGE_NT_HEADERS32 as IMAGE_NT_HEADERS;
-#[cfg(any(target_pointer_width = "64"))]
-use windows_sys::Win32::System::Diagnostics::Debug::IMAGE_NT_HEADERS64 as IMAGE_NT_HEADERS;
-use windows_sys::Win32::System::Memory::{MEMORY_BASIC_INFORMATION, VirtualQuery};
-use windows_sys::Win32::System::SystemServices::{
-    IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE, IMAGE_NT_SIGNATURE,
-};
 
 const X86_JMP_SIZE: usize = 5;
 pub const NEEDED_BYTES: usize = X86_JMP_SIZE;
+// rel32 wraps around the 32-bit address space, it reaches everything
+pub const NEEDED_BYTES_ABSOLUTE: usize = X86_JMP_SIZE;
 
 #[inline]
 pub fn detour_2gb_below(addr: usize) -> usize {
@@ -41,69 +33,45 @@ pub fn detour_2gb_above(addr: usize) -> usize {
     }
 }
 
-#[inline]
-unsafe fn get_iat_range(nt_headers_addr: *mut core::ffi::c_void) -> Option<Range<usize>> {
-    let header = unsafe { ptr::read::<IMAGE_NT_HEADERS>(nt_headers_addr as *const _) };
-    if header.Signature != IMAGE_NT_SIGNATURE {
-        return None;
-    }
-
-    let iat = header
-        .OptionalHeader
-        .DataDirectory
-        .get(IMAGE_DIRECTORY_ENTRY_IAT as usize)?;
-
-    Some(iat.VirtualAddress as usize..iat.VirtualAddress as usize + iat.Size as usize)
-}
-
-#[inline]
-pub fn detour_is_imported<T>(address: *const T, target: *const T) -> bool {
-    let mut mbi = unsafe { std::mem::zeroed::<MEMORY_BASIC_INFORMATION>() };
-    if (unsafe {
-        VirtualQuery(
-            address as *const _,
-            &mut mbi as *mut _,
-            size_of::<MEMORY_BASIC_INFORMATION>(),
-        )
-    }) == 0
-    {
-        return false;
-    }
-    let header = unsafe { ptr::read::<IMAGE_DOS_HEADER>(mbi.AllocationBase as *const _) };
-    if header.e_magic != IMAGE_DOS_SIGNATURE {
-        return false;
-    }
-    let Some(range) = (unsafe { get_iat_range(mbi.AllocationBase.add(header.e_lfanew as usize)) })
-    else {
-        return false;
-    };
-    if !range.contains(&(target as usize).saturating_sub(mbi.AllocationBase as usize)) {
-        return false;
-    }
-    true
-}
-
-#[inline]
-pub fn detour_skip_jmp(mut inst: Instruction) -> usize {
-    let mut code = inst.ip() as usize;
-
-    if inst.code() == Code::Jmp_rm32 {
-        let target = inst.memory_displacement32() as usize;
-        if detour_is_imported(
-            code as *const core::ffi::c_void,
-            target as *const core::ffi::c_void,
-        ) {
-            // maybe out of mem bounds
-            inst = unsafe { inst::decode_instruction::<2>(target) };
-            code = target;
+// import thunks are found through the PE IAT, linux follows PLT stubs in `thunk.rs`
+#[cfg(target_os = "windows")]
+pub use iat::*;
+
+#[cfg(target_os = "windows")]
+mod iat {
+    use crate::inst;
+    use crate::pe::{IMAGE_DIRECTORY_ENTRY_IAT, PeImage};
+    use iced_x86::{Code, Instruction};
+    use windows_sys::Win32::System::Memory::{MEMORY_BASIC_INFORMATION, VirtualQuery};
+
+    #[inline]
+    pub fn detour_is_imported<T>(address: *const T, target: *const T) -> bool {
+        let mut mbi = unsafe { std::mem::zeroed::<MEMORY_BASIC_INFORMATION>() };
+        if (unsafe {
+            VirtualQuery(
+                address as *const _,
+                &mut mbi as *mut _,
+                size_of::<MEMORY_BASIC_INFORMATION>(),
+            )
+        }) == 0
+        {
+            return false;
         }
+        let base = mbi.AllocationBase as usize;
+        let Ok(image) = (unsafe { PeImage::from_base(base) }) else {
+            return false;
+        };
+        let Some(iat) = image.directory(IMAGE_DIRECTORY_ENTRY_IAT) else {
+            return false;
+        };
+        u32::try_from((target as usize).saturating_sub(base))
+            .is_ok_and(|x| iat.range().contains(&x))
     }
 
-    if inst.code() == Code::Jmp_rel8_32 {
-        code = inst.memory_displacement32() as usize;
-        let code_original = code;
+    #[inline]
+    pub fn detour_skip_jmp(mut inst: Instruction) -> usize {
+        let mut code = inst.ip() as usize;
 
-        let inst = unsafe { inst::decode_instruction::<6>(code) };
         if inst.code() == Code::Jmp_rm32 {
             let target = inst.memory_displacement32() as usize;
             if detour_is_imported(
@@ -111,20 +79,38 @@ pub fn detour_skip_jmp(mut inst: Instruction) -> usize {
                 target as *const core::ffi::c_void,
             ) {
                 // maybe out of mem bounds
+                inst = unsafe { inst::decode_instruction::<2>(target) };
                 code = target;
             }
-        } else if inst.code() == Code::Jmp_rel32_32 {
+        }
+
+        if inst.code() == Code::Jmp_rel8_32 {
             code = inst.memory_displacement32() as usize;
+            let code_original = code;
+
             let inst = unsafe { inst::decode_instruction::<6>(code) };
-            if inst.code() == Code::Jmp_rm32
-                && inst.memory_displacement32() as usize == code.saturating_add(0x1000)
-            {
-                code = code_original;
+            if inst.code() == Code::Jmp_rm32 {
+                let target = inst.memory_displacement32() as usize;
+                if detour_is_imported(
+                    code as *const core::ffi::c_void,
+                    target as *const core::ffi::c_void,
+                ) {
+                    // maybe out of mem bounds
+                    code = target;
+                }
+            } else if inst.code() == Code::Jmp_rel32_32 {
+                code = inst.memory_displacement32() as usize;
+                let inst = unsafe { inst::decode_instruction::<6>(code) };
+                if inst.code() == Code::Jmp_rm32
+                    && inst.memory_displacement32() as usize == code.saturating_add(0x1000)
+                {
+                    code = code_original;
+                }
             }
         }
-    }
 
-    code
+        code
+    }
 }
 
 #[inline]
@@ -143,47 +129,22 @@ pub fn detour_find_jmp_bounds(inst: &Instruction) -> RangeInclusive<usize> {
     lo..=hi
 }
 
+/// write jmp through `pb_write` with displacement computed for `pb_code`,
+/// they differ when code is written through an alias mapping
 #[inline]
-pub fn detour_gen_jmp_immediate(pb_code: *mut u8, pb_jmp_val: *mut u8) {
+pub fn detour_gen_jmp_immediate_at(pb_write: *mut u8, pb_code: *mut u8, pb_jmp_val: *mut u8) {
     let pb_jmp_src = pb_code.wrapping_byte_add(X86_JMP_SIZE);
     unsafe {
-        *pb_code = 0xe9;
-        ptr::write(
-            pb_code.wrapping_byte_add(1).cast::<i32>(),
-            (pb_jmp_val as i32) - pb_jmp_src as i32,
+        *pb_write = 0xe9;
+        ptr::write_unaligned(
+            pb_write.wrapping_byte_add(1).cast::<i32>(),
+            (pb_jmp_val as i32).wrapping_sub(pb_jmp_src as i32),
         );
     }
 }
 
+/// jump which works from any distance, a wrapping rel32 on x86
 #[inline]
-pub fn detour_does_code_end_function(inst: &Instruction) -> bool {
-    if inst.is_invalid() {
-        return true;
-    }
-
-    if inst.is_jmp_near()
-        || inst.is_jmp_near_indirect()
-        || inst.is_jmp_far_indirect()
-        || inst.is_jmp_far()
-        || inst.is_jmp_short_or_near()
-        || inst.is_jmp_short()
-    {
-        return true;
-    }
-
-    let code = inst.code();
-
-    if code == Code::Retnd
-        || code == Code::Retnw
-        || code == Code::Retnd_imm16
-        || code == Code::Retnw_imm16
-    {
-        return true;
-    }
-
-    if code == Code::Int3 {
-        return true;
-    }
-
-    false
+pub fn detour_gen_jmp_absolute(pb_write: *mut u8, pb_code: *mut u8, pb_jmp_val: *mut u8) {
+    detour_gen_jmp_immediate_at(pb_write, pb_code, pb_jmp_val)
 }
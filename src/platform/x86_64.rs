@@ -0,0 +1,115 @@
+use iced_x86::{Code, Instruction};
+use std::ops::RangeInclusive;
+use std::ptr;
+
+// jmp rel32
+const X64_JMP_SIZE: usize = 5;
+// jmp [rip+0]; dq target
+const X64_ABS_JMP_SIZE: usize = 14;
+pub const NEEDED_BYTES: usize = X64_JMP_SIZE;
+pub const NEEDED_BYTES_ABSOLUTE: usize = X64_ABS_JMP_SIZE;
+
+#[inline]
+pub fn detour_2gb_below(addr: usize) -> usize {
+    if addr > 0x7ff80000 {
+        addr - 0x7ff80000
+    } else {
+        0x80000
+    }
+}
+
+#[inline]
+pub fn detour_2gb_above(addr: usize) -> usize {
+    if addr < 0xffffffff80000000 {
+        addr + 0x7ff80000
+    } else {
+        0xfffffffffff80000
+    }
+}
+
+/// addresses a rel32 jump written over `inst` reaches, narrowed to what an existing
+/// `jmp rel32` there reaches as well
+#[inline]
+pub fn detour_find_jmp_bounds(inst: &Instruction) -> RangeInclusive<usize> {
+    let code = inst.ip() as usize;
+    let mut lo = detour_2gb_below(code);
+    let mut hi = detour_2gb_above(code);
+    if inst.code() == Code::Jmp_rel32_64 {
+        let new = inst.near_branch_target() as usize;
+        if new < code {
+            hi = detour_2gb_above(new);
+        } else {
+            lo = detour_2gb_below(new);
+        }
+    }
+    lo..=hi
+}
+
+/// write jmp through `pb_write` with displacement computed for `pb_code`,
+/// they differ when code is written through an alias mapping
+#[inline]
+pub fn detour_gen_jmp_immediate_at(pb_write: *mut u8, pb_code: *mut u8, pb_jmp_val: *mut u8) {
+    let pb_jmp_src = pb_code.addr().wrapping_add(X64_JMP_SIZE);
+    unsafe {
+        *pb_write = 0xe9;
+        ptr::write_unaligned(
+            pb_write.wrapping_byte_add(1).cast::<i32>(),
+            pb_jmp_val.addr().wrapping_sub(pb_jmp_src) as i32,
+        );
+    }
+}
+
+/// position independent jump, works from any distance
+#[inline]
+pub fn detour_gen_jmp_absolute(pb_write: *mut u8, _pb_code: *mut u8, pb_jmp_val: *mut u8) {
+    unsafe {
+        ptr::copy_nonoverlapping([0xff, 0x25, 0, 0, 0, 0].as_ptr(), pb_write, 6);
+        ptr::write_unaligned(
+            pb_write.wrapping_byte_add(6).cast::<u64>(),
+            pb_jmp_val.addr() as u64,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_follow_existing_jump() {
+        let code = 0x7fff_0000_0000u64;
+        let mut plain = Instruction::with(Code::Nopd);
+        plain.set_ip(code);
+        assert_eq!(
+            detour_find_jmp_bounds(&plain),
+            code as usize - 0x7ff80000..=code as usize + 0x7ff80000
+        );
+
+        let mut jmp = Instruction::with_branch(Code::Jmp_rel32_64, code - 0x4000_0000).unwrap();
+        jmp.set_ip(code);
+        let bound = detour_find_jmp_bounds(&jmp);
+        assert_eq!(*bound.end(), code as usize - 0x4000_0000 + 0x7ff80000);
+        assert_eq!(*bound.start(), code as usize - 0x7ff80000);
+
+        let mut low = plain;
+        low.set_ip(0x1000);
+        assert_eq!(*detour_find_jmp_bounds(&low).start(), 0x80000);
+    }
+
+    #[test]
+    fn rel32_jump_is_relative_to_code() {
+        let mut buf = [0u8; X64_JMP_SIZE];
+        let code = 0x7fff_0000_1000usize as *mut u8;
+        detour_gen_jmp_immediate_at(buf.as_mut_ptr(), code, code.wrapping_byte_sub(0x20));
+        assert_eq!(buf, [0xe9, 0xdb, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn absolute_jump_reads_literal() {
+        let mut buf = [0u8; X64_ABS_JMP_SIZE];
+        let target = 0x1122_3344_5566_7788usize as *mut u8;
+        detour_gen_jmp_absolute(buf.as_mut_ptr(), std::ptr::null_mut(), target);
+        assert_eq!(buf[..6], [0xff, 0x25, 0, 0, 0, 0]);
+        assert_eq!(buf[6..], 0x1122_3344_5566_7788u64.to_le_bytes());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::Error;
+use crate::alloc::{RegionInfo, RegionState};
+use crate::backend::{MemoryBackend, ProcessMemory};
+use std::ops::Range;
+use std::str::FromStr;
+
+// bytes read from the backend at once
+const SCAN_WINDOW: usize = 0x10_0000;
+
+fn is_readable(info: &RegionInfo) -> bool {
+    info.state == RegionState::Commit && info.size != 0 && info.protection.is_readable()
+}
+
+/// byte signature with wildcard, `"8B 0D ?? ?? ?? ?? 85 C9"`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern(Vec<Option<u8>>);
+
+impl Pattern {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.0.len()
+            && self
+                .0
+                .iter()
+                .zip(data)
+                .all(|(p, b)| p.is_none_or(|p| p == *b))
+    }
+
+    /// first match offset in `data`
+    pub fn find(&self, data: &[u8]) -> Option<usize> {
+        if self.0.is_empty() || data.len() < self.0.len() {
+            return None;
+        }
+        (0..=data.len() - self.0.len()).find(|i| self.matches(&data[*i..]))
+    }
+
+    /// scan readable memory of the current process in `start..end`
+    ///
+    /// # Safety
+    ///
+    /// memory in range must stay mapped while it is scanned
+    pub unsafe fn scan(&self, start: usize, end: usize) -> Option<usize> {
+        self.scan_with(&ProcessMemory, start, end)
+    }
+
+    /// scan committed readable memory of `backend` in `start..end`, contiguous regions are
+    /// scanned as one so matches may cross region boundaries
+    pub fn scan_with(
+        &self,
+        backend: &dyn MemoryBackend,
+        start: usize,
+        end: usize,
+    ) -> Option<usize> {
+        let mut cursor = start;
+        while cursor < end {
+            let chunk_start = cursor;
+            while cursor < end {
+                let Some(info) = backend.query(cursor).filter(is_readable) else {
+                    break;
+                };
+                cursor = info.base.saturating_add(info.size).min(end);
+            }
+            if cursor > chunk_start {
+                if let Some(found) = self.find_with(backend, chunk_start..cursor) {
+                    return Some(found);
+                }
+            } else {
+                // skip uncommitted or inaccessible region
+                let info = backend.query(cursor)?;
+                cursor = info.base.saturating_add(info.size.max(1)).min(end);
+            }
+        }
+        None
+    }
+
+    // reads in windows overlapping by the pattern length, chunks may be large
+    fn find_with(&self, backend: &dyn MemoryBackend, range: Range<usize>) -> Option<usize> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let mut buf = vec![];
+        let mut at = range.start;
+        while range.end - at >= self.0.len() {
+            buf.resize((range.end - at).min(SCAN_WINDOW.max(self.0.len())), 0);
+            backend.read(at, &mut buf).ok()?;
+            if let Some(offset) = self.find(&buf) {
+                return Some(at + offset);
+            }
+            at += buf.len() + 1 - self.0.len();
+        }
+        None
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s
+            .split_whitespace()
+            .map(|x| match x {
+                "?" | "??" => Ok(None),
+                _ => {
+                    let mut out = [0u8; 1];
+                    hex::decode_to_slice(x, &mut out)
+                        .map(|_| Some(out[0]))
+                        .map_err(|_| Error::InvalidPattern)
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if pattern.is_empty() {
+            return Err(Error::InvalidPattern);
+        }
+        Ok(Pattern(pattern))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Protection, SimulatedMemory};
+
+    const MAGIC: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+
+    fn magic() -> Pattern {
+        "DE AD ?? EF".parse().unwrap()
+    }
+
+    #[test]
+    fn pattern_from_str() {
+        let pattern: Pattern = "8B 0d ?? ? C9".parse().unwrap();
+        assert_eq!(pattern.0, [Some(0x8b), Some(0x0d), None, None, Some(0xc9)]);
+        assert!(pattern.matches(&[0x8b, 0x0d, 1, 2, 0xc9, 0xff]));
+        assert!(!pattern.matches(&[0x8b, 0x0d, 1, 2, 0xc8]));
+        assert!(!pattern.matches(&[0x8b, 0x0d, 1, 2]));
+        assert_eq!(pattern.find(&[0, 0x8b, 0x0d, 1, 2, 0xc9]), Some(1));
+        assert_eq!(pattern.find(&[0x8b, 0x0d]), None);
+
+        for bad in ["", "   ", "8B 0", "8B0D", "8G", "???", "*"] {
+            assert!(
+                matches!(bad.parse::<Pattern>(), Err(Error::InvalidPattern)),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn scan_skips_unreadable_memory() {
+        let sim = SimulatedMemory::new(0x10000);
+        let base = sim.range().start;
+        sim.map(base + 0x1000, 0x1000, Protection::NoAccess)
+            .unwrap();
+        sim.map(base + 0x2000, 0x1000, Protection::Read).unwrap();
+        sim.map(base + 0x3000, 0x1000, Protection::ReadExecute)
+            .unwrap();
+        // free memory and an inaccessible page hold a match too
+        sim.write(base + 0x800, &MAGIC).unwrap();
+        sim.write(base + 0x1100, &MAGIC).unwrap();
+        // across the boundary of two readable regions
+        sim.write(base + 0x2ffe, &MAGIC).unwrap();
+
+        let end = sim.range().end;
+        assert_eq!(magic().scan_with(&sim, base, end), Some(base + 0x2ffe));
+        assert_eq!(magic().scan_with(&sim, base, base + 0x3001), None);
+        assert_eq!(magic().scan_with(&sim, base + 0x2fff, end), None);
+        assert_eq!(magic().scan_with(&sim, base, base + 0x2000), None);
+    }
+
+    #[test]
+    fn scan_finds_match_across_read_windows() {
+        let sim = SimulatedMemory::new(3 * SCAN_WINDOW);
+        let base = sim.range().start;
+        sim.map(base, 2 * SCAN_WINDOW, Protection::Read).unwrap();
+        let at = base + SCAN_WINDOW - 2;
+        sim.write(at, &MAGIC).unwrap();
+        assert_eq!(magic().scan_with(&sim, base, sim.range().end), Some(at));
+        sim.write(at, &[0; 4]).unwrap();
+        sim.write(base + 2 * SCAN_WINDOW - 4, &MAGIC).unwrap();
+        let found = magic().scan_with(&sim, base, sim.range().end);
+        assert_eq!(found, Some(base + 2 * SCAN_WINDOW - 4));
+    }
+}
@@ -0,0 +1,152 @@
+//! find the code a function pointer leads to
+//!
+//! like `DetourCodeFromPointer`, import jumps are followed to the implementation so a hook
+//! lands on the function itself instead of a stub only some callers go through
+
+use std::ffi::c_void;
+
+/// code behind `pointer`, import thunks and PLT stubs are followed to the real function
+///
+/// on Linux `.plt` and `.plt.sec` stubs are recognized when they jump through a GOT slot of
+/// a loaded module, a slot which is still bound lazily is resolved with `dlvsym` by the
+/// symbol and version its relocation names
+pub fn code_from_pointer(pointer: *const c_void) -> *const c_void {
+    if pointer.is_null() {
+        return pointer;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        plt::follow(pointer as usize) as *const c_void
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        crate::platform::detour_skip_jmp(crate::inst::decoder(pointer).decode()) as *const c_void
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod plt {
+    use crate::backend::ProcessMemory;
+    use crate::{got, inst, mem};
+    use iced_x86::{Code, Instruction, Register};
+
+    // stubs may chain through another module's PLT, stop at loops
+    const MAX_DEPTH: usize = 8;
+
+    /// first instruction after an optional `endbr` landing pad
+    fn decode_past_endbr(addr: usize) -> Instruction {
+        let mut decoder = inst::decoder(addr as *const u8);
+        let inst = decoder.decode();
+        match inst.code() {
+            Code::Endbr64 | Code::Endbr32 => decoder.decode(),
+            _ => inst,
+        }
+    }
+
+    /// GOT slot read by the `jmp [mem]` of a stub at `code`
+    fn stub_slot(code: usize) -> Option<usize> {
+        let inst = decode_past_endbr(code);
+        match inst.code() {
+            // x86_64 `jmp [rip+GOT]`, `bnd` prefix included
+            Code::Jmp_rm64 if inst.is_ip_rel_memory_operand() => {
+                Some(inst.ip_rel_memory_address() as usize)
+            }
+            Code::Jmp_rm32 if inst.memory_index() == Register::None => {
+                match inst.memory_base() {
+                    // non PIC i686 `jmp [GOT]`
+                    Register::None => Some(inst.memory_displacement32() as usize),
+                    // PIC i686 `jmp [ebx+disp]`, ebx holds the GOT of the module
+                    Register::EBX => got::module_at(code)?
+                        .got()
+                        .map(|x| x.wrapping_add(inst.memory_displacement32() as i32 as usize)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// lazy slots point back to `push index; jmp PLT0` in the PLT
+    fn is_lazy(module: &got::Module, target: usize) -> bool {
+        module.contains(target)
+            && matches!(
+                decode_past_endbr(target).code(),
+                Code::Pushq_imm32 | Code::Pushd_imm32
+            )
+    }
+
+    /// where the slot read by a stub leads, only slots relocated in a module's GOT count
+    fn slot_target(slot: usize) -> Option<usize> {
+        let module = got::module_at(slot)?;
+        let found = module.slots().into_iter().find(|x| x.addr == slot)?;
+        if !mem::is_committed(&ProcessMemory, slot, size_of::<usize>()) {
+            return None;
+        }
+        let target = unsafe { (slot as *const usize).read_unaligned() };
+        match is_lazy(&module, target) {
+            true => found.resolve(),
+            false => Some(target),
+        }
+    }
+
+    pub fn follow(mut code: usize) -> usize {
+        for _ in 0..MAX_DEPTH {
+            let Some(target) = stub_slot(code).and_then(slot_target) else {
+                break;
+            };
+            if target == 0 || target == code {
+                break;
+            }
+            code = target;
+        }
+        code
+    }
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::got::tests::malloc_slot;
+    use crate::platform::{MEM_TYPE_COMMIT, MEM_TYPE_RESERVE, valloc, vfree};
+
+    /// RWX page in rel32 reach of `addr`
+    fn page_near(addr: usize) -> usize {
+        (1..64)
+            .flat_map(|i| [addr - i * 0x100_0000, addr + i * 0x100_0000])
+            .find_map(|x| {
+                valloc(
+                    (x & !0xfff) as *const _,
+                    0x1000,
+                    MEM_TYPE_COMMIT | MEM_TYPE_RESERVE,
+                )
+            })
+            .unwrap() as usize
+    }
+
+    /// `jmp [rip+disp]` at `stub` reading `slot`
+    fn write_stub(stub: usize, slot: usize) {
+        let disp = (slot as i64 - (stub as i64 + 6)) as i32;
+        let code = [&[0xff, 0x25][..], &disp.to_le_bytes()].concat();
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), stub as *mut u8, code.len()) };
+    }
+
+    #[test]
+    fn stub_through_got_slot_is_followed() {
+        let (_, slot) = malloc_slot();
+        let malloc = slot.resolve().unwrap();
+        let stub = page_near(slot.addr);
+        write_stub(stub, slot.addr);
+        assert_eq!(code_from_pointer(stub as *const c_void) as usize, malloc);
+        vfree(stub as *mut c_void).unwrap();
+    }
+
+    #[test]
+    fn slot_outside_a_got_is_not_read() {
+        let page = page_near(malloc_slot().1.addr);
+        let literal = page + 0x100;
+        unsafe { *(literal as *mut usize) = code_from_pointer as *const () as usize };
+        write_stub(page, literal);
+        assert_eq!(code_from_pointer(page as *const c_void) as usize, page);
+        vfree(page as *mut c_void).unwrap();
+    }
+}
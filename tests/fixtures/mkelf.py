@@ -0,0 +1,224 @@
+#!/usr/bin/env python3
+"""Write the minimal ELF32 and ELF64 executables the patcher tests read.
+
+    python3 tests/fixtures/mkelf.py
+
+Both images have the same layout:
+
+* a read execute segment from the start of the file, `.text` at offset 0x1000
+* a read write segment at offset 0x2000, `.dynamic`, `.dynstr` and the version needs
+* `.symtab`, `.strtab`, `.shstrtab` and the section headers behind it, not loaded
+
+Functions in `.text`: `plain` (0x00) and `endbr` (0x20), the same prologue with and
+without a leading endbr. The 64 bit `riprel` (0x40) loads a qword at 0x80 rip relative,
+the 32 bit `thunk` (0x40) calls `__x86.get_pc_thunk.bx` (0x60). The program needs
+libc.so.6 with one version and libm.so.6.
+"""
+
+import os
+import struct
+
+PAGE = 0x1000
+TEXT = 0x1000
+DATA = 0x2000
+
+DT_NEEDED = 1
+DT_STRTAB = 5
+DT_STRSZ = 10
+DT_VERNEED = 0x6FFFFFFE
+DT_VERNEEDNUM = 0x6FFFFFFF
+
+
+class Blob:
+    def __init__(self, offset):
+        self.offset = offset
+        self.data = bytearray()
+
+    def here(self):
+        return self.offset + len(self.data)
+
+    def put(self, raw, align=1):
+        while len(self.data) % align:
+            self.data.append(0)
+        at = self.here()
+        self.data += raw
+        return at
+
+
+class Strings:
+    def __init__(self):
+        self.data = bytearray(b"\0")
+
+    def add(self, name):
+        at = len(self.data)
+        self.data += name.encode() + b"\0"
+        return at
+
+
+def text_64():
+    code = bytearray(b"\xcc" * 0x100)
+    prologue = b"\x55\x48\x89\xe5\x48\x83\xec\x20\xc9\xc3"
+    code[0x00:0x0A] = prologue
+    code[0x20:0x2E] = b"\xf3\x0f\x1e\xfa" + prologue
+    # mov rax, [rip + 0x39]; ret
+    code[0x40:0x48] = b"\x48\x8b\x05" + struct.pack("<i", 0x80 - 0x47) + b"\xc3"
+    code[0x80:0x88] = struct.pack("<Q", 0x1122334455667788)
+    return code, [("plain", 0x00), ("endbr", 0x20), ("riprel", 0x40)]
+
+
+def text_32():
+    code = bytearray(b"\xcc" * 0x100)
+    prologue = b"\x55\x89\xe5\x83\xec\x20\xc9\xc3"
+    code[0x00:0x08] = prologue
+    code[0x20:0x2C] = b"\xf3\x0f\x1e\xfb" + prologue
+    # call __x86.get_pc_thunk.bx; add ebx, 0x1000; ret
+    code[0x40:0x4C] = b"\xe8" + struct.pack("<i", 0x60 - 0x45) + b"\x81\xc3\x00\x10\x00\x00\xc3"
+    # mov ebx, [esp]; ret
+    code[0x60:0x64] = b"\x8b\x1c\x24\xc3"
+    return code, [
+        ("plain", 0x00),
+        ("endbr", 0x20),
+        ("thunk", 0x40),
+        ("__x86.get_pc_thunk.bx", 0x60),
+    ]
+
+
+def build(is_64):
+    word = 8 if is_64 else 4
+    w = "Q" if is_64 else "I"
+    base = 0x400000 if is_64 else 0x8048000
+    code, functions = text_64() if is_64 else text_32()
+
+    # dynamic data, the string table first so the dynamic entries can point into it
+    dynstr = Strings()
+    libc = dynstr.add("libc.so.6")
+    libm = dynstr.add("libm.so.6")
+    version = "GLIBC_2.2.5" if is_64 else "GLIBC_2.0"
+    version_name = dynstr.add(version)
+
+    data = Blob(DATA)
+    dynamic_size = 7 * 2 * word
+    dynamic = data.put(b"\0" * dynamic_size, word)
+    strtab = data.put(bytes(dynstr.data))
+    hash_ = 0
+    for c in version.encode():
+        hash_ = ((hash_ << 4) + c) & 0xFFFFFFFF
+        high = hash_ & 0xF0000000
+        if high:
+            hash_ ^= high >> 24
+        hash_ &= ~high & 0xFFFFFFFF
+    verneed = data.put(
+        struct.pack("<HHIII", 1, 1, libc, 16, 0) + struct.pack("<IHHII", hash_, 0, 2, version_name, 0),
+        4,
+    )
+    entries = [
+        (DT_NEEDED, libc),
+        (DT_NEEDED, libm),
+        (DT_STRTAB, base + strtab),
+        (DT_STRSZ, len(dynstr.data)),
+        (DT_VERNEED, base + verneed),
+        (DT_VERNEEDNUM, 1),
+        (0, 0),
+    ]
+    raw = b"".join(struct.pack("<" + w * 2, *x) for x in entries)
+    data.data[dynamic - DATA : dynamic - DATA + len(raw)] = raw
+
+    # symbols and section names, not loaded
+    tail = Blob(DATA + len(data.data))
+    strings = Strings()
+    symbols = b"\0" * (24 if is_64 else 16)
+    for name, at in functions:
+        name = strings.add(name)
+        if is_64:
+            symbols += struct.pack("<IBBHQQ", name, 0x12, 0, 1, base + TEXT + at, 0x10)
+        else:
+            symbols += struct.pack("<IIIBBH", name, base + TEXT + at, 0x10, 0x12, 0, 1)
+    symtab = tail.put(symbols, word)
+    strtab_at = tail.put(bytes(strings.data))
+
+    names = Strings()
+    sections = [
+        # name, type, flags, addr, offset, size, link, entsize
+        ("", 0, 0, 0, 0, 0, 0, 0),
+        (".text", 1, 6, base + TEXT, TEXT, len(code), 0, 0),
+        (".dynstr", 3, 2, base + strtab, strtab, len(dynstr.data), 0, 0),
+        (".dynamic", 6, 3, base + dynamic, dynamic, dynamic_size, 2, 2 * word),
+        (".symtab", 2, 0, 0, symtab, len(symbols), 5, 24 if is_64 else 16),
+        (".strtab", 3, 0, 0, strtab_at, len(strings.data), 0, 0),
+        (".shstrtab", 3, 0, 0, 0, 0, 0, 0),
+    ]
+    section_names = [names.add(x[0]) if x[0] else 0 for x in sections]
+    shstrtab = tail.put(bytes(names.data))
+    sections[-1] = (".shstrtab", 3, 0, 0, shstrtab, len(names.data), 0, 0)
+    shoff = tail.put(b"", word)
+
+    section_table = b""
+    for name, (_, kind, flags, addr, offset, size, link, entsize) in zip(section_names, sections):
+        # every symbol past the null one is global
+        info = 1 if kind == 2 else 0
+        if is_64:
+            section_table += struct.pack(
+                "<IIQQQQIIQQ", name, kind, flags, addr, offset, size, link, info, 1, entsize
+            )
+        else:
+            section_table += struct.pack(
+                "<IIIIIIIIII", name, kind, flags, addr, offset, size, link, info, 1, entsize
+            )
+
+    ehsize = 64 if is_64 else 52
+    phentsize = 56 if is_64 else 32
+    phnum = 4
+    phoff = ehsize
+    text_end = TEXT + len(code)
+    data_end = DATA + len(data.data)
+    segments = [
+        # kind, flags, offset, vaddr, size, align
+        (6, 4, phoff, base + phoff, phnum * phentsize, word),
+        (1, 5, 0, base, text_end, PAGE),
+        (1, 6, DATA, base + DATA, data_end - DATA, PAGE),
+        (2, 6, dynamic, base + dynamic, dynamic_size, word),
+    ]
+    program_table = b""
+    for kind, flags, offset, vaddr, size, align in segments:
+        if is_64:
+            program_table += struct.pack(
+                "<IIQQQQQQ", kind, flags, offset, vaddr, vaddr, size, size, align
+            )
+        else:
+            program_table += struct.pack(
+                "<IIIIIIII", kind, offset, vaddr, vaddr, size, size, flags, align
+            )
+
+    ident = b"\x7fELF" + bytes([2 if is_64 else 1, 1, 1, 0]) + b"\0" * 8
+    header = ident + struct.pack(
+        "<HHI" + w * 3 + "IHHHHHH",
+        2,
+        62 if is_64 else 3,
+        1,
+        base + TEXT,
+        phoff,
+        shoff,
+        0,
+        ehsize,
+        phentsize,
+        phnum,
+        64 if is_64 else 40,
+        len(sections),
+        len(sections) - 1,
+    )
+    assert len(header) == ehsize
+
+    out = bytearray(header + program_table)
+    out += b"\0" * (TEXT - len(out))
+    out += code
+    out += b"\0" * (DATA - len(out))
+    out += data.data
+    out += tail.data
+    out += section_table
+    return bytes(out)
+
+
+here = os.path.dirname(os.path.abspath(__file__))
+for name, is_64 in [("elf32", False), ("elf64", True)]:
+    with open(os.path.join(here, name), "wb") as f:
+        f.write(build(is_64))
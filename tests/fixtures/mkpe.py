@@ -0,0 +1,201 @@
+#!/usr/bin/env python3
+"""Write the minimal PE32 and PE32+ DLLs the parser tests read.
+
+    python3 tests/fixtures/mkpe.py
+
+Both images have the same layout:
+
+* `.text`  at 0x1000, a few `ret` the exports and the TLS callback point at
+* `.rdata` at 0x2000, imports, exports and the TLS directory
+* `.reloc` at 0x3000, two base relocation blocks
+
+Imports: KERNEL32.dll GetProcAddress and LoadLibraryA by name, WS2_32.dll ordinal 115
+and connect by name. Exports of fixture.dll with ordinal base 1: alpha (1), beta (2),
+a hole (3), gamma (4) forwarded to KERNEL32.GetProcAddress and an unnamed export (5).
+"""
+
+import os
+import struct
+
+FILE_ALIGN = 0x200
+SECTION_ALIGN = 0x1000
+HEADERS = 0x200
+
+TEXT_RVA = 0x1000
+RDATA_RVA = 0x2000
+RELOC_RVA = 0x3000
+
+
+class Blob:
+    def __init__(self, rva):
+        self.rva = rva
+        self.data = bytearray()
+
+    def here(self):
+        return self.rva + len(self.data)
+
+    def put(self, raw, align=1):
+        while len(self.data) % align:
+            self.data.append(0)
+        at = self.here()
+        self.data += raw
+        return at
+
+    def patch(self, rva, raw):
+        at = rva - self.rva
+        self.data[at : at + len(raw)] = raw
+
+
+def raw_size(blob):
+    return -(-len(blob.data) // FILE_ALIGN) * FILE_ALIGN
+
+
+def build(is_64):
+    width = 8 if is_64 else 4
+    ptr = "<Q" if is_64 else "<I"
+    image_base = 0x180000000 if is_64 else 0x10000000
+    ordinal_flag = 1 << (width * 8 - 1)
+
+    text = Blob(TEXT_RVA)
+    text.put(b"\xc3" * 0x40)
+
+    rdata = Blob(RDATA_RVA)
+
+    # imports, descriptors first then tables and names
+    dlls = [
+        ("KERNEL32.dll", [("name", "GetProcAddress"), ("name", "LoadLibraryA")]),
+        ("WS2_32.dll", [("ordinal", 115), ("name", "connect")]),
+    ]
+    descriptors = rdata.put(b"\0" * 20 * (len(dlls) + 1), 4)
+    for i, (dll, functions) in enumerate(dlls):
+        entries = []
+        for kind, value in functions:
+            if kind == "ordinal":
+                entries.append(ordinal_flag | value)
+            else:
+                entries.append(rdata.put(struct.pack("<H", 0) + value.encode() + b"\0", 2))
+        table = b"".join(struct.pack(ptr, x) for x in entries + [0])
+        lookup = rdata.put(table, width)
+        iat = rdata.put(table, width)
+        name = rdata.put(dll.encode() + b"\0")
+        rdata.patch(descriptors + i * 20, struct.pack("<IIIII", lookup, 0, 0, name, iat))
+    imports = (descriptors, 20 * (len(dlls) + 1))
+
+    # exports, the directory size covers everything up to the last string
+    export_dir = rdata.put(b"\0" * 40, 4)
+    forward = rdata.put(b"KERNEL32.GetProcAddress\0")
+    functions = [TEXT_RVA, TEXT_RVA + 0x10, 0, forward, TEXT_RVA + 0x20]
+    eat = rdata.put(b"".join(struct.pack("<I", x) for x in functions), 4)
+    names = [(b"alpha", 0), (b"beta", 1), (b"gamma", 3)]
+    name_rvas = [rdata.put(x + b"\0") for x, _ in names]
+    name_table = rdata.put(b"".join(struct.pack("<I", x) for x in name_rvas), 4)
+    ordinals = rdata.put(b"".join(struct.pack("<H", x) for _, x in names), 2)
+    dll_name = rdata.put(b"fixture.dll\0")
+    rdata.patch(
+        export_dir,
+        struct.pack(
+            "<IIHHIIIIIII",
+            0, 0, 0, 0, dll_name, 1, len(functions), len(names), eat, name_table, ordinals,
+        ),
+    )
+    exports = (export_dir, rdata.here() - export_dir)
+
+    # TLS, callbacks hold virtual addresses
+    tls_index = rdata.put(b"\0" * 4, 4)
+    callbacks = rdata.put(struct.pack(ptr, image_base + TEXT_RVA + 0x30) + struct.pack(ptr, 0), width)
+    tls_dir = rdata.put(
+        struct.pack(
+            "<" + ptr[1] * 4,
+            image_base + TEXT_RVA + 0x38,
+            image_base + TEXT_RVA + 0x40,
+            image_base + tls_index,
+            image_base + callbacks,
+        )
+        + struct.pack("<II", 0x10, 0),
+        width,
+    )
+    tls = (tls_dir, width * 4 + 8)
+
+    # base relocations, one padding entry keeps the second block 4 byte aligned
+    kind = 10 if is_64 else 3
+    reloc = Blob(RELOC_RVA)
+    blocks = [(TEXT_RVA, [0x0, 0x8]), (RDATA_RVA, [callbacks - RDATA_RVA])]
+    for page, offsets in blocks:
+        entries = [kind << 12 | x for x in offsets]
+        if len(entries) % 2:
+            entries.append(0)
+        reloc.put(struct.pack("<II", page, 8 + 2 * len(entries)))
+        reloc.put(b"".join(struct.pack("<H", x) for x in entries))
+    relocs = (RELOC_RVA, len(reloc.data))
+
+    sections = []
+    raw = HEADERS
+    for name, blob, characteristics in [
+        (b".text", text, 0x60000020),
+        (b".rdata", rdata, 0x40000040),
+        (b".reloc", reloc, 0x42000040),
+    ]:
+        sections.append((name, blob, raw, characteristics))
+        raw += raw_size(blob)
+
+    directories = [(0, 0)] * 16
+    directories[0] = exports
+    directories[1] = imports
+    directories[5] = relocs
+    directories[9] = tls
+
+    optional_size = 240 if is_64 else 224
+    size_of_image = RELOC_RVA + SECTION_ALIGN
+
+    dos = bytearray(0x40)
+    dos[0:2] = b"MZ"
+    struct.pack_into("<I", dos, 0x3C, 0x40)
+    file_header = struct.pack(
+        "<HHIIIHH",
+        0x8664 if is_64 else 0x14C,
+        len(sections),
+        0,
+        0,
+        0,
+        optional_size,
+        0x2022 if is_64 else 0x2102,
+    )
+    if is_64:
+        optional = struct.pack(
+            "<HBBIIIIIQIIHHHHHHIIIIHHQQQQII",
+            0x20B, 14, 0, 0x200, 0x600, 0, 0, TEXT_RVA, image_base,
+            SECTION_ALIGN, FILE_ALIGN, 6, 0, 0, 0, 6, 0, 0,
+            size_of_image, HEADERS, 0, 2, 0x160,
+            0x100000, 0x1000, 0x100000, 0x1000, 0, 16,
+        )
+    else:
+        optional = struct.pack(
+            "<HBBIIIIIIIIIHHHHHHIIIIHHIIIIII",
+            0x10B, 14, 0, 0x200, 0x600, 0, 0, TEXT_RVA, RDATA_RVA, image_base,
+            SECTION_ALIGN, FILE_ALIGN, 6, 0, 0, 0, 6, 0, 0,
+            size_of_image, HEADERS, 0, 2, 0x140,
+            0x100000, 0x1000, 0x100000, 0x1000, 0, 16,
+        )
+    optional += b"".join(struct.pack("<II", *x) for x in directories)
+    assert len(optional) == optional_size
+
+    section_table = b""
+    for name, blob, raw, characteristics in sections:
+        section_table += struct.pack(
+            "<8sIIIIIIHHI",
+            name, len(blob.data), blob.rva, raw_size(blob), raw, 0, 0, 0, 0, characteristics,
+        )
+
+    out = bytearray(dos + b"PE\0\0" + file_header + optional + section_table)
+    assert len(out) <= HEADERS
+    out += b"\0" * (HEADERS - len(out))
+    for _, blob, raw, _ in sections:
+        assert len(out) == raw
+        out += blob.data + b"\0" * (raw_size(blob) - len(blob.data))
+    return bytes(out)
+
+
+here = os.path.dirname(os.path.abspath(__file__))
+for name, is_64 in [("pe32.dll", False), ("pe64.dll", True)]:
+    with open(os.path.join(here, name), "wb") as f:
+        f.write(build(is_64))
@@ -0,0 +1,92 @@
+#!/usr/bin/env python3
+"""Write the minimal x86_64 shared object the inject tests load.
+
+    python3 tests/fixtures/mkso.py
+
+* a read execute segment from the start of the file, the `DT_INIT` code at offset 0x100
+* a read write segment at offset 0x1000, `.dynamic`, `.dynstr`, `.dynsym` and `.hash`
+
+The library needs nothing, its `DT_INIT` renames the calling thread to `injected` with a
+raw `prctl(PR_SET_NAME)` so the loading process shows it in `/proc/<pid>/comm`.
+"""
+
+import os
+import struct
+
+PAGE = 0x1000
+INIT = 0x100
+DATA = 0x1000
+
+DT_NULL = 0
+DT_HASH = 4
+DT_STRTAB = 5
+DT_SYMTAB = 6
+DT_STRSZ = 10
+DT_SYMENT = 11
+DT_INIT = 12
+
+
+def init_code():
+    name = b"injected\0"
+    # mov eax, SYS_prctl; mov edi, PR_SET_NAME; lea rsi, [rip + name]; syscall; ret
+    code = b"\xb8" + struct.pack("<I", 157) + b"\xbf" + struct.pack("<I", 15)
+    code += b"\x48\x8d\x35" + struct.pack("<i", 3)
+    code += b"\x0f\x05\xc3"
+    return code + name
+
+
+def build():
+    code = init_code()
+
+    # null symbol only, one empty hash bucket
+    dynstr = b"\0"
+    dynsym = b"\0" * 24
+    hash_ = struct.pack("<IIII", 1, 1, 0, 0)
+    dynamic_size = 7 * 16
+    strtab = DATA + dynamic_size
+    symtab = (strtab + len(dynstr) + 7) & ~7
+    hashtab = symtab + len(dynsym)
+    entries = [
+        (DT_INIT, INIT),
+        (DT_HASH, hashtab),
+        (DT_STRTAB, strtab),
+        (DT_SYMTAB, symtab),
+        (DT_STRSZ, len(dynstr)),
+        (DT_SYMENT, 24),
+        (DT_NULL, 0),
+    ]
+    data = bytearray(b"".join(struct.pack("<QQ", *x) for x in entries))
+    data += dynstr
+    data += b"\0" * (symtab - DATA - len(data))
+    data += dynsym + hash_
+
+    ehsize = 64
+    phentsize = 56
+    segments = [
+        # kind, flags, offset, size, align
+        (1, 5, 0, INIT + len(code), PAGE),
+        (1, 6, DATA, len(data), PAGE),
+        (2, 6, DATA, dynamic_size, 8),
+    ]
+    program_table = b"".join(
+        struct.pack("<IIQQQQQQ", kind, flags, offset, offset, offset, size, size, align)
+        for kind, flags, offset, size, align in segments
+    )
+
+    ident = b"\x7fELF" + bytes([2, 1, 1, 0]) + b"\0" * 8
+    header = ident + struct.pack(
+        "<HHIQQQIHHHHHH", 3, 62, 1, 0, ehsize, 0, 0, ehsize, phentsize, len(segments), 64, 0, 0
+    )
+    assert len(header) == ehsize
+
+    out = bytearray(header + program_table)
+    out += b"\0" * (INIT - len(out))
+    out += code
+    out += b"\0" * (DATA - len(out))
+    out += data
+    return bytes(out)
+
+
+here = os.path.dirname(os.path.abspath(__file__))
+with open(os.path.join(here, "inject.so"), "wb") as f:
+    f.write(build())